@@ -1,8 +1,82 @@
+use regex::Regex;
 use serde_derive::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 pub const SOCKET_ENV: &str = "RUSKCOV_INJECT_SOCK";
 
+/// Policy for following exec'd processes (see `FollowExec`)
+pub const FOLLOW_EXEC_ENV: &str = "RUSKCOV_FOLLOW_EXEC";
+
+/// The tracer's pid. The process whose parent this is is the primary; its descendants have
+/// other parents, so they can tell they're not, though it's passed on to them.
+pub const PRIMARY_ENV: &str = "RUSKCOV_INJECT_PRIMARY";
+
+#[cfg(target_os = "macos")]
+pub const INJECT_LIBRARY_VAR: &str = "DYLD_INSERT_LIBRARIES"; // XXX may not be enough to interpose dlopen
+#[cfg(all(unix, not(target_os = "macos")))]
+pub const INJECT_LIBRARY_VAR: &str = "LD_PRELOAD";
+
+/// Which exec'd processes participate in coverage. The primary process always does.
+#[derive(Debug, Clone)]
+pub enum FollowExec {
+    /// Follow all exec'd processes
+    All,
+    /// Don't follow any exec'd processes
+    None,
+    /// Follow exec'd processes whose binary path matches this regex
+    Filter(Regex),
+}
+
+impl FollowExec {
+    /// Follow exec'd processes whose binary path matches `re`
+    pub fn filter(re: &str) -> Result<Self, regex::Error> {
+        Regex::new(re).map(FollowExec::Filter)
+    }
+
+    /// Encode for passing via `FOLLOW_EXEC_ENV`
+    pub fn to_env(&self) -> String {
+        match self {
+            FollowExec::All => "all".to_string(),
+            FollowExec::None => "none".to_string(),
+            FollowExec::Filter(re) => format!("filter:{}", re),
+        }
+    }
+
+    /// Decode from `FOLLOW_EXEC_ENV`. Missing or malformed values mean `All`, and a
+    /// filter which isn't a valid regex follows nothing.
+    pub fn from_env(val: Option<&str>) -> Self {
+        match val {
+            Some("none") => FollowExec::None,
+            Some(val) if val.starts_with("filter:") => {
+                FollowExec::filter(&val["filter:".len()..]).unwrap_or(FollowExec::None)
+            }
+            _ => FollowExec::All,
+        }
+    }
+
+    /// Return true if a process running `exe` should participate
+    pub fn follows(&self, exe: &Path, primary: bool) -> bool {
+        primary
+            || match self {
+                FollowExec::All => true,
+                FollowExec::None => false,
+                FollowExec::Filter(re) => re.is_match(&exe.to_string_lossy()),
+            }
+    }
+}
+
+impl PartialEq for FollowExec {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (FollowExec::All, FollowExec::All) | (FollowExec::None, FollowExec::None) => true,
+            (FollowExec::Filter(a), FollowExec::Filter(b)) => a.as_str() == b.as_str(),
+            _ => false,
+        }
+    }
+}
+
+impl Eq for FollowExec {}
+
 /// Description of an object file and its mappings into a process address space
 #[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub struct ObjectInfo {
@@ -40,3 +114,37 @@ pub struct SetBreakpointsReq {
 pub struct SetBreakpointsResp {
     pub set: Vec<(u64, BreakpointInst)>,
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn follow_exec_env() {
+        for follow in &[
+            FollowExec::All,
+            FollowExec::None,
+            FollowExec::filter("^/usr/bin/(cc|ld)$").unwrap(),
+            FollowExec::filter("").unwrap(),
+        ] {
+            assert_eq!(FollowExec::from_env(Some(&follow.to_env())), *follow);
+        }
+
+        assert_eq!(FollowExec::from_env(None), FollowExec::All);
+        assert_eq!(FollowExec::from_env(Some("bogus")), FollowExec::All);
+        assert_eq!(FollowExec::from_env(Some("filter:(")), FollowExec::None);
+    }
+
+    #[test]
+    fn follows() {
+        let exe = Path::new("/usr/bin/cc");
+        let filter = FollowExec::filter("/cc$").unwrap();
+
+        assert!(FollowExec::All.follows(exe, false));
+        assert!(!FollowExec::None.follows(exe, false));
+        assert!(FollowExec::None.follows(exe, true));
+        assert!(filter.follows(exe, false));
+        assert!(!filter.follows(Path::new("/usr/bin/ld"), false));
+        assert!(filter.follows(Path::new("/usr/bin/ld"), true));
+    }
+}
@@ -0,0 +1,52 @@
+//! Coalescing breakpoint addresses into page spans, as done when bulk setting breakpoints.
+//!
+//! The library is only built as a `cdylib` to be preloaded, so this includes the module
+//! directly rather than linking it.
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use itertools::Itertools;
+
+#[path = "../src/span.rs"]
+#[allow(dead_code, unused_imports)]
+mod span;
+
+use span::Span;
+
+const BREAKPOINTS: usize = 1_000_000;
+
+fn coalesce(addrs: Vec<usize>) -> Vec<Span> {
+    addrs
+        .into_iter()
+        .map(Span::new)
+        .coalesce(|prev, cur| prev.extend(cur))
+        .collect()
+}
+
+fn spans(c: &mut Criterion) {
+    let mut group = c.benchmark_group("span");
+    group.sample_size(10);
+    // Breakpoints every few bytes through a large object, so they make a few long spans
+    group.bench_function("dense", |b| {
+        b.iter_batched(
+            || (0..BREAKPOINTS).map(|bp| 0x40_0000 + bp * 5).collect(),
+            coalesce,
+            BatchSize::LargeInput,
+        )
+    });
+    // One breakpoint on each of many scattered pages, so few spans coalesce
+    group.bench_function("sparse", |b| {
+        b.iter_batched(
+            || {
+                (0..BREAKPOINTS)
+                    .map(|bp| 0x40_0000 + bp * 3 * 4096)
+                    .collect()
+            },
+            coalesce,
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, spans);
+criterion_main!(benches);
@@ -12,13 +12,15 @@
 
 use findshlibs::{Segment, SharedLibrary, TargetSharedLibrary};
 use inject_types::{
-    BreakpointInst, ObjectInfo, PHdr, SetBreakpointsReq, SetBreakpointsResp, BREAKPOINT, SOCKET_ENV,
+    BreakpointInst, FollowExec, ObjectInfo, PHdr, SetBreakpointsReq, SetBreakpointsResp,
+    BREAKPOINT, FOLLOW_EXEC_ENV, PRIMARY_ENV, SOCKET_ENV,
 };
 use itertools::Itertools;
 use libc::{c_char, c_int, c_void, dlsym, raise, size_t, RTLD_NEXT, SIGSTOP};
+use once_cell::sync::OnceCell;
 use std::{
     env,
-    ffi::{CStr, OsStr},
+    ffi::{CStr, OsStr, OsString},
     io::{BufReader, BufWriter, Write},
     mem,
     os::unix::{ffi::OsStrExt, net::UnixStream},
@@ -34,11 +36,42 @@ extern "C" {
     fn breakpoint();
 }
 
+/// Controller socket path, captured at startup. `None` if this process isn't participating.
+static SOCK_PATH: OnceCell<Option<OsString>> = OnceCell::new();
+
+/// Decide whether this process participates in coverage. The environment is only read, never
+/// changed, since this runs in a constructor where other threads may already be using it. Our
+/// variables are passed on as they are, so exec'd children make their own decision: a process
+/// the filter rejects may exec one it accepts. The tracer has the final say when we connect.
+fn init_sock_path() -> Option<OsString> {
+    let sock_path = env::var_os(SOCKET_ENV)?;
+    let tracer = env::var(PRIMARY_ENV).ok().and_then(|pid| pid.parse().ok());
+    let primary = tracer == Some(unsafe { libc::getppid() });
+    let follow = FollowExec::from_env(env::var(FOLLOW_EXEC_ENV).ok().as_ref().map(String::as_str));
+
+    let participate = match env::current_exe() {
+        Ok(exe) => follow.follows(&exe, primary),
+        Err(_) => primary,
+    };
+
+    if participate {
+        Some(sock_path)
+    } else {
+        None
+    }
+}
+
 fn gather_phdrs() -> Vec<ObjectInfo> {
     let mut data: Vec<ObjectInfo> = Vec::new();
 
     TargetSharedLibrary::each(|shlib| {
-        let path = PathBuf::from(shlib.name());
+        let mut path = PathBuf::from(shlib.name());
+        // The executable itself has no name
+        if path.as_os_str().is_empty() {
+            if let Ok(exe) = env::current_exe() {
+                path = exe;
+            }
+        }
         let addr = shlib.virtual_memory_bias().0 as u64;
         let phvec: Vec<PHdr> = shlib
             .segments()
@@ -73,11 +106,12 @@ fn set_breakpoints(mut breakpoints: Vec<usize>) -> SetBreakpointsResp {
     let mut res = Vec::new();
 
     for span in spans {
+        // Other threads may be running code in these pages meanwhile
         unsafe {
             libc::mprotect(
                 span.start as *mut c_void,
                 span.len as size_t,
-                libc::PROT_WRITE,
+                libc::PROT_READ | libc::PROT_WRITE | libc::PROT_EXEC,
             )
         };
 
@@ -109,9 +143,9 @@ fn set_breakpoints(mut breakpoints: Vec<usize>) -> SetBreakpointsResp {
 /// TODO: Better name
 fn send_phdrs() {
     // Address of a unix domain socket
-    let sock_path = match env::var(SOCKET_ENV) {
-        Ok(path) => path,
-        Err(_) => return,
+    let sock_path = match SOCK_PATH.get_or_init(init_sock_path) {
+        Some(path) => path,
+        None => return,
     };
 
     let sock_rd = match UnixStream::connect(sock_path) {
@@ -155,14 +189,14 @@ fn send_phdrs() {
 /// Intercept dlopen to capture added phdrs
 #[no_mangle]
 pub unsafe extern "C" fn dlopen(name: *mut c_char, flags: c_int) -> *mut c_void {
-    let real_dlopen = dlsym(RTLD_NEXT, b"dlopen".as_ptr() as *const c_char)
-        as *const extern "C" fn(*mut c_char, c_int) -> *mut c_void;
-
+    let real_dlopen = dlsym(RTLD_NEXT, b"dlopen\0".as_ptr() as *const c_char);
     if real_dlopen.is_null() {
         return ptr::null_mut();
     }
+    let real_dlopen: extern "C" fn(*mut c_char, c_int) -> *mut c_void =
+        mem::transmute(real_dlopen);
 
-    let ret = (*real_dlopen)(name, flags);
+    let ret = real_dlopen(name, flags);
 
     if !ret.is_null() {
         send_phdrs();
@@ -173,6 +207,10 @@ pub unsafe extern "C" fn dlopen(name: *mut c_char, flags: c_int) -> *mut c_void
 
 #[ctor::ctor]
 fn init_send_phdrs() {
+    // Don't bother the tracer if we're not participating
+    if SOCK_PATH.get_or_init(init_sock_path).is_none() {
+        return;
+    }
     // Stop so tracer can catch up
     //unsafe { raise(SIGSTOP) };
     unsafe { breakpoint() };
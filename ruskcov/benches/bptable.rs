@@ -0,0 +1,115 @@
+//! Breakpoint table insertion and lookup, compared with a `HashMap`, and handling
+//! breakpoint hits.
+
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+use inject_types::BreakpointInst;
+use ruskcov::{
+    bptable::BreakpointTable,
+    process::AddressSpace,
+    srcloc::{Location, SrcPath},
+};
+use std::{collections::HashMap, path::Path};
+
+const BREAKPOINTS: u64 = 1_000_000;
+const FILES: u64 = 1000;
+
+/// Breakpoints every few bytes through an object, for a few hundred lines of each file
+fn breakpoints() -> Vec<(u64, Location)> {
+    let files: Vec<SrcPath> = (0..FILES)
+        .map(|file| SrcPath::from_path(Path::new(&format!("/src/file{}.rs", file))))
+        .collect();
+
+    (0..BREAKPOINTS)
+        .map(|bp| {
+            let file = files[(bp * FILES / BREAKPOINTS) as usize];
+            (
+                0x40_0000 + bp * 5,
+                Location::new(file, (bp % 500) as u32 + 1),
+            )
+        })
+        .collect()
+}
+
+/// Pseudo-random breakpoint addresses, and some near misses
+fn probes(bps: &[(u64, Location)], count: usize) -> Vec<u64> {
+    let mut state = 0x2545_f491_4f6c_dd1d_u64;
+    (0..count)
+        .map(|probe| {
+            state = state
+                .wrapping_mul(6_364_136_223_846_793_005)
+                .wrapping_add(1);
+            let (addr, _) = bps[(state >> 33) as usize % bps.len()];
+            addr + (probe % 2) as u64
+        })
+        .collect()
+}
+
+fn insert(c: &mut Criterion) {
+    let bps = breakpoints();
+
+    let mut group = c.benchmark_group("insert");
+    group.sample_size(10);
+    group.bench_function("table", |b| {
+        b.iter(|| {
+            let mut table = BreakpointTable::new();
+            table.insert(bps.iter().copied());
+            table
+        })
+    });
+    group.bench_function("hashmap", |b| {
+        b.iter(|| bps.iter().copied().collect::<HashMap<u64, Location>>())
+    });
+    group.finish();
+}
+
+fn lookup(c: &mut Criterion) {
+    let bps = breakpoints();
+    let probes = probes(&bps, 10_000);
+    let mut table = BreakpointTable::new();
+    table.insert(bps.iter().copied());
+    let map: HashMap<u64, Location> = bps.iter().copied().collect();
+
+    let mut group = c.benchmark_group("lookup");
+    group.bench_function("table", |b| {
+        b.iter(|| {
+            probes
+                .iter()
+                .filter_map(|&addr| table.get(black_box(addr)))
+                .count()
+        })
+    });
+    group.bench_function("hashmap", |b| {
+        b.iter(|| {
+            probes
+                .iter()
+                .filter_map(|&addr| map.get(&black_box(addr)))
+                .count()
+        })
+    });
+    group.finish();
+}
+
+/// Looking up and removing breakpoints as they're hit, as the tracer does for each trap
+fn hit(c: &mut Criterion) {
+    let bps = breakpoints();
+    let probes = probes(&bps, 10_000);
+    let mut addrspace = AddressSpace::default();
+    addrspace.add_breakpoints(bps.iter().copied());
+    addrspace.set_replaced(bps.iter().map(|&(addr, _)| (addr, BreakpointInst([0x55]))));
+
+    c.bench_function("hit", |b| {
+        b.iter_batched_ref(
+            || addrspace.clone(),
+            |addrspace| {
+                probes
+                    .iter()
+                    .filter_map(|&addr| addrspace.hit(black_box(addr), 0))
+                    .count()
+            },
+            BatchSize::LargeInput,
+        )
+    });
+}
+
+criterion_group!(benches, insert, lookup, hit);
+criterion_main!(benches);
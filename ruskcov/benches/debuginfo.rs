@@ -0,0 +1,171 @@
+//! Loading debug info and computing breakpoints for a large generated program.
+//!
+//! The program is C source with `RUSKCOV_BENCH_FILES` files (default 200) of 100 functions
+//! each, compiled with `cc -g -O1` so there's some inlining. The benchmarks are skipped if
+//! there's no `cc`.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use inject_types::ObjectInfo;
+use ruskcov::{
+    breakpoints::{get_breakpoints, load_debug, BreakpointConfig, Inline},
+    debuginfo::DebugSearch,
+    exclusion::Exclusions,
+    filter::{Filter, FilterArgs},
+    placement::Placement,
+};
+use std::{
+    env,
+    fmt::Write as _,
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+use tempfile::TempDir;
+
+const FUNCTIONS: usize = 100;
+
+/// Source for one file of the program, with a mix of branches, loops, calls and inlined
+/// helpers
+fn source(file: usize) -> String {
+    let mut src = String::from("#include <stdlib.h>\n\n");
+    let _ = writeln!(
+        src,
+        "static inline int clamp{0}(int x) {{\n\
+         \tif (x < 0)\n\t\treturn 0;\n\
+         \treturn x > 100 ? 100 : x;\n}}\n",
+        file
+    );
+    for func in 0..FUNCTIONS {
+        let _ = writeln!(
+            src,
+            "int f{0}_{1}(int a, int b) {{\n\
+             \tint total = 0;\n\
+             \tfor (int i = 0; i < a; i++) {{\n\
+             \t\tif (i % 3 == {2})\n\t\t\ttotal += clamp{0}(b - i);\n\
+             \t\telse\n\t\t\ttotal -= b;\n\
+             \t}}\n\
+             \tswitch (total & 3) {{\n\
+             \tcase 0: return total;\n\
+             \tcase 1: return total * 2;\n\
+             \tdefault: return abs(total) + {1};\n\
+             \t}}\n}}\n",
+            file,
+            func,
+            func % 3
+        );
+    }
+    src
+}
+
+/// Generate and build the program in a temporary directory, or `None` if it can't be built
+fn build() -> Option<(TempDir, PathBuf)> {
+    let files = env::var("RUSKCOV_BENCH_FILES")
+        .ok()
+        .and_then(|files| files.parse().ok())
+        .unwrap_or(200);
+    let dir = tempfile::tempdir().expect("tempdir");
+
+    let mut main = String::new();
+    for file in 0..files {
+        let _ = writeln!(main, "int f{}_0(int, int);", file);
+    }
+    main.push_str("\nint main(int argc, char **argv) {\n\tint total = 0;\n");
+    for file in 0..files {
+        let _ = writeln!(main, "\ttotal += f{}_0(argc, 3);", file);
+    }
+    main.push_str("\treturn total == 0;\n}\n");
+
+    let mut srcs = vec![dir.path().join("main.c")];
+    fs::write(&srcs[0], main).expect("write main");
+    for file in 0..files {
+        let path = dir.path().join(format!("file{}.c", file));
+        fs::write(&path, source(file)).expect("write source");
+        srcs.push(path);
+    }
+
+    let exe = dir.path().join("big");
+    let status = Command::new("cc")
+        .args(&["-g", "-O1", "-o"])
+        .arg(&exe)
+        .args(&srcs)
+        .status();
+    match status {
+        Ok(status) if status.success() => Some((dir, exe)),
+        Ok(status) => {
+            eprintln!("Skipping: cc failed: {}", status);
+            None
+        }
+        Err(err) => {
+            eprintln!("Skipping: can't run cc: {}", err);
+            None
+        }
+    }
+}
+
+fn object(path: &Path) -> ObjectInfo {
+    ObjectInfo {
+        pid: 0,
+        path: path.to_path_buf(),
+        addr: 0,
+        phdrs: vec![],
+    }
+}
+
+fn debuginfo(c: &mut Criterion) {
+    let (_dir, exe) = match build() {
+        Some(built) => built,
+        None => return,
+    };
+    let search = DebugSearch::new(&[], None);
+
+    let mut group = c.benchmark_group("debuginfo");
+    group.sample_size(10);
+    group.bench_function("load_debug", |b| {
+        b.iter(|| load_debug(&exe, &search, false).unwrap())
+    });
+    group.bench_function("parse_lines", |b| {
+        b.iter(|| {
+            let ctxt = load_debug(&exe, &search, false).unwrap();
+            ctxt.parse_lines().unwrap()
+        })
+    });
+    group.bench_function("parse_functions", |b| {
+        b.iter(|| {
+            let ctxt = load_debug(&exe, &search, false).unwrap();
+            ctxt.parse_functions().unwrap()
+        })
+    });
+    group.finish();
+}
+
+fn breakpoints(c: &mut Criterion) {
+    let (_dir, exe) = match build() {
+        Some(built) => built,
+        None => return,
+    };
+    let obj = object(&exe);
+
+    let mut group = c.benchmark_group("get_breakpoints");
+    group.sample_size(10);
+    for &(name, placement, branches) in &[
+        ("line", Placement::Line, false),
+        ("block", Placement::Block, false),
+        ("branches", Placement::Line, true),
+    ] {
+        let config = BreakpointConfig {
+            filter: Filter::new(&FilterArgs::default()).unwrap(),
+            exclusions: Exclusions::new::<&str>(&[]),
+            search: DebugSearch::new(&[], None),
+            cache: None,
+            placement,
+            inline: Inline::All,
+            branches,
+            debug: false,
+        };
+        group.bench_function(name, |b| b.iter(|| get_breakpoints(&obj, &config).unwrap()));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, debuginfo, breakpoints);
+criterion_main!(benches);
@@ -0,0 +1,490 @@
+//! Compact storage for the breakpoints in an address space.
+//!
+//! There can be tens of millions of breakpoints, so rather than a map from address to
+//! `Location` they're kept in blocks of parallel vectors sorted by address, one per object,
+//! and found by binary search. Each breakpoint takes 14 bytes: a 32 bit offset from the
+//! block's base address, file index and line, and a byte each for its state and the
+//! original instruction.
+//!
+//! Call sites credited by breakpoints in inlined code are kept the same way, with each
+//! breakpoint's offset indexing a range of one flat vector of call sites.
+
+use inject_types::BreakpointInst;
+use std::collections::{BTreeMap, HashMap};
+
+use crate::srcloc::{Location, SrcPath};
+
+/// State of a breakpoint
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[repr(u8)]
+pub enum Status {
+    /// Yet to be set
+    Unset,
+    /// Not to be set until asked for
+    Deferred,
+    /// Set, and the original instruction is known
+    Set,
+    /// Hit and removed
+    Removed,
+}
+
+/// A breakpoint's location and state
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Entry {
+    pub loc: Location,
+    pub status: Status,
+    /// Original instruction, if `Set` or `Removed`
+    pub inst: Option<BreakpointInst>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct BreakpointTable {
+    /// Blocks by base address. They don't overlap.
+    blocks: BTreeMap<u64, Block>,
+}
+
+/// Breakpoints within 4G of a base address
+#[derive(Debug, Clone, Default)]
+struct Block {
+    /// Offset of each breakpoint from the base address, sorted
+    offsets: Vec<u32>,
+    /// Index of each breakpoint's file in `srcpaths`
+    files: Vec<u32>,
+    lines: Vec<u32>,
+    status: Vec<Status>,
+    /// Original instructions, valid once `Set`
+    insts: Vec<BreakpointInst>,
+    srcpaths: Vec<SrcPath>,
+}
+
+/// Call sites credited by each breakpoint
+#[derive(Debug, Clone, Default)]
+pub struct CallTable {
+    /// Blocks by base address. They don't overlap.
+    blocks: BTreeMap<u64, CallBlock>,
+}
+
+/// Call sites of breakpoints within 4G of a base address
+#[derive(Debug, Clone, Default)]
+struct CallBlock {
+    /// Offset of each breakpoint from the base address, sorted
+    offsets: Vec<u32>,
+    /// Range of each breakpoint's call sites in `calls`, emptied once they're taken
+    ranges: Vec<(u32, u32)>,
+    calls: Vec<Location>,
+}
+
+impl BreakpointTable {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Number of breakpoints
+    pub fn len(&self) -> usize {
+        self.blocks.values().map(|block| block.offsets.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    /// Add unset breakpoints, typically all those in an object. Where there are several at
+    /// the same address the first is used, and existing breakpoints are left alone.
+    pub fn insert(&mut self, bps: impl IntoIterator<Item = (u64, Location)>) {
+        let mut new: Vec<_> = bps
+            .into_iter()
+            .filter(|&(addr, _)| self.get(addr).is_none())
+            .map(|(addr, loc)| {
+                (
+                    addr,
+                    Entry {
+                        loc,
+                        status: Status::Unset,
+                        inst: None,
+                    },
+                )
+            })
+            .collect();
+        // Stable, so the first of each address is kept
+        new.sort_by_key(|&(addr, _)| addr);
+        new.dedup_by_key(|&mut (addr, _)| addr);
+        let (start, end) = match (new.first(), new.last()) {
+            (Some(&(start, _)), Some(&(end, _))) => (start, end),
+            _ => return,
+        };
+
+        // Rebuild any blocks the new ones would overlap, so lookups only ever need to
+        // search the last block starting before an address
+        let first = self
+            .blocks
+            .range(..=start)
+            .next_back()
+            .map_or(start, |(&base, _)| base);
+        let overlapping: Vec<u64> = self
+            .blocks
+            .range(first..=end)
+            .filter(|(&base, block)| block.end(base) >= start)
+            .map(|(&base, _)| base)
+            .collect();
+        if !overlapping.is_empty() {
+            for base in overlapping {
+                let block = self.blocks.remove(&base).unwrap();
+                new.extend(block.entries(base));
+            }
+            new.sort_by_key(|&(addr, _)| addr);
+        }
+
+        let mut rest = &new[..];
+        while let Some(&(base, _)) = rest.first() {
+            let len = rest
+                .iter()
+                .position(|&(addr, _)| addr - base > u64::from(u32::MAX))
+                .unwrap_or(rest.len());
+            let _ = self.blocks.insert(base, Block::new(base, &rest[..len]));
+            rest = &rest[len..];
+        }
+    }
+
+    /// Get the breakpoint at `addr`
+    pub fn get(&self, addr: u64) -> Option<Entry> {
+        let (block, idx) = self.find(addr)?;
+        Some(block.entry(idx))
+    }
+
+    /// Set the state of the breakpoint at `addr`, and its original instruction if given.
+    /// Returns its previous state.
+    pub fn set_status(
+        &mut self,
+        addr: u64,
+        status: Status,
+        inst: Option<BreakpointInst>,
+    ) -> Option<Status> {
+        let (block, idx) = self.find_mut(addr)?;
+        let old = block.status[idx];
+        block.status[idx] = status;
+        if let Some(inst) = inst {
+            block.insts[idx] = inst;
+        }
+        Some(old)
+    }
+
+    /// Addresses of all breakpoints with `status`, in order
+    pub fn with_status(&self, status: Status) -> Vec<u64> {
+        self.blocks
+            .iter()
+            .flat_map(|(&base, block)| {
+                block
+                    .offsets
+                    .iter()
+                    .zip(&block.status)
+                    .filter(move |&(_, &s)| s == status)
+                    .map(move |(&offset, _)| base + u64::from(offset))
+            })
+            .collect()
+    }
+
+    fn find(&self, addr: u64) -> Option<(&Block, usize)> {
+        let (&base, block) = self.blocks.range(..=addr).next_back()?;
+        let idx = block.index(addr - base)?;
+        Some((block, idx))
+    }
+
+    fn find_mut(&mut self, addr: u64) -> Option<(&mut Block, usize)> {
+        let (&base, block) = self.blocks.range_mut(..=addr).next_back()?;
+        let idx = block.index(addr - base)?;
+        Some((block, idx))
+    }
+}
+
+impl CallTable {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Add call sites, typically all those in an object. Those at the same address are kept
+    /// in order, after any already there.
+    pub fn insert(&mut self, calls: impl IntoIterator<Item = (u64, Location)>) {
+        let mut new: Vec<_> = calls.into_iter().collect();
+        // Stable, so call sites at the same address stay in order
+        new.sort_by_key(|&(addr, _)| addr);
+        let (start, end) = match (new.first(), new.last()) {
+            (Some(&(start, _)), Some(&(end, _))) => (start, end),
+            _ => return,
+        };
+
+        // Rebuild any blocks the new ones would overlap, as for breakpoints
+        let first = self
+            .blocks
+            .range(..=start)
+            .next_back()
+            .map_or(start, |(&base, _)| base);
+        let overlapping: Vec<u64> = self
+            .blocks
+            .range(first..=end)
+            .filter(|(&base, block)| block.end(base) >= start)
+            .map(|(&base, _)| base)
+            .collect();
+        if !overlapping.is_empty() {
+            let mut old = Vec::new();
+            for base in overlapping {
+                let block = self.blocks.remove(&base).unwrap();
+                old.extend(block.entries(base));
+            }
+            old.append(&mut new);
+            new = old;
+            new.sort_by_key(|&(addr, _)| addr);
+        }
+
+        let mut rest = &new[..];
+        while let Some(&(base, _)) = rest.first() {
+            let len = rest
+                .iter()
+                .position(|&(addr, _)| addr - base > u64::from(u32::MAX))
+                .unwrap_or(rest.len());
+            let _ = self.blocks.insert(base, CallBlock::new(base, &rest[..len]));
+            rest = &rest[len..];
+        }
+    }
+
+    /// Take the call sites credited by the breakpoint at `addr`, so they're only
+    /// credited once
+    pub fn take(&mut self, addr: u64) -> &[Location] {
+        let (&base, block) = match self.blocks.range_mut(..=addr).next_back() {
+            Some(found) => found,
+            None => return &[],
+        };
+        let idx = match block.index(addr - base) {
+            Some(idx) => idx,
+            None => return &[],
+        };
+        let (start, end) = block.ranges[idx];
+        block.ranges[idx] = (start, start);
+        &block.calls[start as usize..end as usize]
+    }
+}
+
+impl CallBlock {
+    /// Make a block of call sites sorted by the address of their breakpoint, all within 4G
+    /// of `base`
+    fn new(base: u64, calls: &[(u64, Location)]) -> Self {
+        let mut block = CallBlock {
+            calls: Vec::with_capacity(calls.len()),
+            ..Default::default()
+        };
+
+        for &(addr, loc) in calls {
+            let offset = (addr - base) as u32;
+            let idx = block.calls.len() as u32;
+            if block.offsets.last() == Some(&offset) {
+                block.ranges.last_mut().unwrap().1 = idx + 1;
+            } else {
+                block.offsets.push(offset);
+                block.ranges.push((idx, idx + 1));
+            }
+            block.calls.push(loc);
+        }
+
+        block
+    }
+
+    /// Address of the last breakpoint
+    fn end(&self, base: u64) -> u64 {
+        base + self.offsets.last().map_or(0, |&offset| u64::from(offset))
+    }
+
+    fn index(&self, offset: u64) -> Option<usize> {
+        if offset > u64::from(u32::MAX) {
+            return None;
+        }
+        self.offsets.binary_search(&(offset as u32)).ok()
+    }
+
+    /// Call sites which haven't been taken, with their breakpoint's address
+    fn entries(&self, base: u64) -> impl Iterator<Item = (u64, Location)> + '_ {
+        self.offsets
+            .iter()
+            .zip(&self.ranges)
+            .flat_map(move |(&offset, &(start, end))| {
+                self.calls[start as usize..end as usize]
+                    .iter()
+                    .map(move |&loc| (base + u64::from(offset), loc))
+            })
+    }
+}
+
+impl Block {
+    /// Make a block of sorted, distinct breakpoints within 4G of `base`
+    fn new(base: u64, entries: &[(u64, Entry)]) -> Self {
+        let mut block = Block {
+            offsets: Vec::with_capacity(entries.len()),
+            files: Vec::with_capacity(entries.len()),
+            lines: Vec::with_capacity(entries.len()),
+            status: Vec::with_capacity(entries.len()),
+            insts: Vec::with_capacity(entries.len()),
+            srcpaths: Vec::new(),
+        };
+        let mut files = HashMap::new();
+
+        for (addr, entry) in entries {
+            let srcpaths = &mut block.srcpaths;
+            let file = *files.entry(entry.loc.file()).or_insert_with(|| {
+                srcpaths.push(entry.loc.file());
+                srcpaths.len() as u32 - 1
+            });
+
+            block.offsets.push((addr - base) as u32);
+            block.files.push(file);
+            block.lines.push(entry.loc.line());
+            block.status.push(entry.status);
+            block.insts.push(entry.inst.unwrap_or(BreakpointInst([0])));
+        }
+
+        block
+    }
+
+    /// Address of the last breakpoint
+    fn end(&self, base: u64) -> u64 {
+        base + self.offsets.last().map_or(0, |&offset| u64::from(offset))
+    }
+
+    fn index(&self, offset: u64) -> Option<usize> {
+        if offset > u64::from(u32::MAX) {
+            return None;
+        }
+        self.offsets.binary_search(&(offset as u32)).ok()
+    }
+
+    fn entry(&self, idx: usize) -> Entry {
+        let status = self.status[idx];
+        Entry {
+            loc: Location::new(self.srcpaths[self.files[idx] as usize], self.lines[idx]),
+            status,
+            inst: match status {
+                Status::Set | Status::Removed => Some(self.insts[idx]),
+                Status::Unset | Status::Deferred => None,
+            },
+        }
+    }
+
+    fn entries(&self, base: u64) -> impl Iterator<Item = (u64, Entry)> + '_ {
+        self.offsets
+            .iter()
+            .enumerate()
+            .map(move |(idx, &offset)| (base + u64::from(offset), self.entry(idx)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::path::Path;
+
+    fn loc(file: &str, line: u32) -> Location {
+        Location::new(SrcPath::from_path(Path::new(file)), line)
+    }
+
+    #[test]
+    fn insert_and_get() {
+        let mut table = BreakpointTable::new();
+        table.insert(vec![
+            (0x1020, loc("/src/a.rs", 2)),
+            (0x1010, loc("/src/a.rs", 1)),
+            (0x1020, loc("/src/b.rs", 3)),
+        ]);
+
+        assert_eq!(table.len(), 2);
+        assert_eq!(table.get(0x1010).unwrap().loc, loc("/src/a.rs", 1));
+        // The first at an address is used
+        assert_eq!(table.get(0x1020).unwrap().loc, loc("/src/a.rs", 2));
+        assert_eq!(table.get(0x1018), None);
+        assert_eq!(table.get(0x1000), None);
+        assert_eq!(table.get(0x2000), None);
+
+        // Existing breakpoints are left alone
+        assert_eq!(
+            table.set_status(0x1010, Status::Set, Some(BreakpointInst([0x55]))),
+            Some(Status::Unset)
+        );
+        table.insert(vec![(0x1010, loc("/src/c.rs", 9))]);
+        let entry = table.get(0x1010).unwrap();
+        assert_eq!(entry.loc, loc("/src/a.rs", 1));
+        assert_eq!(entry.status, Status::Set);
+        assert_eq!(entry.inst, Some(BreakpointInst([0x55])));
+
+        assert_eq!(table.with_status(Status::Unset), vec![0x1020]);
+        assert_eq!(table.set_status(0x1018, Status::Set, None), None);
+    }
+
+    #[test]
+    fn blocks() {
+        let mut table = BreakpointTable::new();
+        // An object with its breakpoints more than 4G apart
+        let far = 0x1000 + (1 << 33);
+        table.insert(vec![(0x1000, loc("/a.rs", 1)), (far, loc("/a.rs", 2))]);
+        let _ = table.set_status(0x1000, Status::Removed, Some(BreakpointInst([0x90])));
+        // Another object between them
+        table.insert(vec![(0x2000, loc("/b.rs", 1)), (0x4000, loc("/b.rs", 2))]);
+        let _ = table.set_status(0x4000, Status::Deferred, None);
+        // One interleaved with it, and others before and after
+        table.insert(vec![(0x3000, loc("/c.rs", 1)), (0x4800, loc("/c.rs", 2))]);
+        table.insert(vec![(0x10, loc("/d.rs", 1))]);
+        table.insert(vec![(far + 0x10, loc("/e.rs", 1))]);
+
+        assert_eq!(table.len(), 8);
+        assert_eq!(
+            table.with_status(Status::Unset),
+            vec![0x10, 0x2000, 0x3000, 0x4800, far, far + 0x10]
+        );
+        assert_eq!(table.with_status(Status::Deferred), vec![0x4000]);
+        for &(addr, file, line) in &[
+            (0x10, "/d.rs", 1),
+            (0x1000, "/a.rs", 1),
+            (0x2000, "/b.rs", 1),
+            (0x3000, "/c.rs", 1),
+            (0x4000, "/b.rs", 2),
+            (0x4800, "/c.rs", 2),
+            (far, "/a.rs", 2),
+            (far + 0x10, "/e.rs", 1),
+        ] {
+            assert_eq!(
+                table.get(addr).map(|entry| entry.loc),
+                Some(loc(file, line))
+            );
+        }
+        assert_eq!(
+            table.get(0x1000).unwrap().inst,
+            Some(BreakpointInst([0x90]))
+        );
+        assert_eq!(table.get(far + 0x20), None);
+    }
+
+    #[test]
+    fn calls() {
+        let mut table = CallTable::new();
+        let far = 0x1000 + (1 << 33);
+        table.insert(vec![
+            (0x1010, loc("/a.rs", 2)),
+            (0x1000, loc("/a.rs", 1)),
+            (0x1010, loc("/a.rs", 3)),
+            (far, loc("/a.rs", 4)),
+        ]);
+        // Overlapping the first block
+        table.insert(vec![(0x1008, loc("/b.rs", 1)), (0x1010, loc("/b.rs", 2))]);
+
+        assert_eq!(table.take(0x1000), &[loc("/a.rs", 1)]);
+        assert_eq!(table.take(0x1008), &[loc("/b.rs", 1)]);
+        assert_eq!(
+            table.take(0x1010),
+            &[loc("/a.rs", 2), loc("/a.rs", 3), loc("/b.rs", 2)]
+        );
+        assert_eq!(table.take(far), &[loc("/a.rs", 4)]);
+        assert!(table.take(0x1004).is_empty());
+        assert!(table.take(0x10).is_empty());
+
+        // Taken call sites are only credited once, even if their block is rebuilt
+        assert!(table.take(0x1010).is_empty());
+        table.insert(vec![(0x1004, loc("/c.rs", 1))]);
+        assert!(table.take(0x1000).is_empty());
+        assert_eq!(table.take(0x1004), &[loc("/c.rs", 1)]);
+    }
+}
@@ -0,0 +1,969 @@
+//! Finding the breakpoints to set in an object from its debug info
+
+use anyhow::{Context, Error};
+use inject_types::ObjectInfo;
+use rayon::prelude::*;
+use smallvec::SmallVec;
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    ops::Range,
+    path::Path,
+    str::FromStr,
+    time::Instant,
+};
+
+use crate::{
+    cache::BreakpointCache,
+    debuginfo::DebugSearch,
+    disasm,
+    error::ObjectError,
+    exclusion::Exclusions,
+    filter::Filter,
+    mapped_slice::MappedSlice,
+    placement::Placement,
+    process::CondBp,
+    split_dwarf,
+    srcloc::{BranchArm, Function, Location, SrcPath},
+    symtab,
+};
+
+/// Attribution of inlined code. The line table gives the location of the inlined code
+/// itself, and each level of inlining adds a call site.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Inline {
+    /// Only the inlined code's own line
+    Callee,
+    /// The inlined code's line, and each call site it was inlined through
+    All,
+    /// Only the outermost call site, ie the line in the function the code was compiled into,
+    /// so nothing if that function is filtered out
+    Caller,
+}
+
+impl Inline {
+    pub const VARIANTS: &'static [&'static str] = &["callee", "all", "caller"];
+}
+
+impl FromStr for Inline {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "callee" => Ok(Inline::Callee),
+            "all" => Ok(Inline::All),
+            "caller" => Ok(Inline::Caller),
+            _ => Err(format!("Unknown inline attribution {}", s)),
+        }
+    }
+}
+
+/// Settings for finding breakpoints, which are the same for every object in a run
+#[derive(Debug)]
+pub struct BreakpointConfig {
+    pub filter: Filter,
+    pub exclusions: Exclusions,
+    /// Where to look for separate debug info
+    pub search: DebugSearch,
+    /// Cache of each object's breakpoints, if it's being used
+    pub cache: Option<BreakpointCache>,
+    pub placement: Placement,
+    pub inline: Inline,
+    /// Whether to get branch breakpoints
+    pub branches: bool,
+    pub debug: bool,
+}
+
+/// Breakpoints to set in an object
+#[derive(Debug, Default)]
+pub struct Breakpoints {
+    /// Line breakpoints
+    pub lines: Vec<(u64, Location)>,
+    /// Call sites of inlined code, credited along with the line breakpoint at the same
+    /// address
+    pub calls: Vec<(u64, Location)>,
+    /// Branch arm breakpoints, with the location of the branch itself
+    pub branches: Vec<(u64, Location, BranchArm)>,
+    /// Conditional branches evaluated at the branch instruction
+    pub conds: Vec<(u64, Location, CondBp)>,
+    /// Function entry breakpoints
+    pub functions: Vec<(u64, Location, Function)>,
+    /// Code ranges of the functions with entry breakpoints, and their entries, by address
+    pub ranges: Vec<(Range<u64>, u64)>,
+}
+
+impl Breakpoints {
+    /// Remove breakpoints in any of the sorted, non-overlapping address `ranges`, which are
+    /// offset by `bias`.
+    fn exclude(&mut self, ranges: &[Range<u64>], bias: u64) {
+        let excluded = |addr: u64| {
+            let addr = addr - bias;
+            match ranges.binary_search_by_key(&addr, |range| range.start) {
+                Ok(_) => true,
+                Err(0) => false,
+                Err(idx) => ranges[idx - 1].contains(&addr),
+            }
+        };
+
+        self.lines.retain(|&(addr, _)| !excluded(addr));
+        self.calls.retain(|&(addr, _)| !excluded(addr));
+        self.branches.retain(|&(addr, _, _)| !excluded(addr));
+        self.conds.retain(|&(addr, _, _)| !excluded(addr));
+        self.functions.retain(|&(addr, _, _)| !excluded(addr));
+    }
+
+    /// Remove breakpoints on source lines excluded by markers. Functions are removed if
+    /// their declaration is excluded, and call sites if the line they're credited with is.
+    fn exclude_marked(&mut self, exclusions: &Exclusions) {
+        let excluded = |loc: &Location| exclusions.excluded(loc.file(), loc.line());
+
+        self.lines.retain(|(_, loc)| !excluded(loc));
+        let lines: HashSet<u64> = self.lines.iter().map(|&(addr, _)| addr).collect();
+        self.calls
+            .retain(|(addr, loc)| lines.contains(addr) && !excluded(loc));
+        self.branches.retain(|(_, loc, _)| !excluded(loc));
+        self.conds.retain(|(_, loc, _)| !excluded(loc));
+        self.functions
+            .retain(|(_, _, func)| !exclusions.excluded(func.file(), func.line()));
+    }
+
+    /// Group the other breakpoints within each function by its entry breakpoint. Those
+    /// which aren't in a function with one aren't included.
+    pub fn bodies(&self) -> Vec<(u64, Vec<u64>)> {
+        let entries: HashSet<u64> = self.functions.iter().map(|&(addr, _, _)| addr).collect();
+        let mut addrs: Vec<u64> = self
+            .lines
+            .iter()
+            .map(|&(addr, _)| addr)
+            .chain(self.branches.iter().map(|&(addr, _, _)| addr))
+            .chain(self.conds.iter().map(|&(addr, _, _)| addr))
+            .filter(|addr| !entries.contains(addr))
+            .collect();
+        addrs.sort();
+        addrs.dedup();
+
+        let index = |addr| match addrs.binary_search(&addr) {
+            Ok(idx) | Err(idx) => idx,
+        };
+        self.ranges
+            .iter()
+            .filter(|(_, entry)| entries.contains(entry))
+            .map(|(range, entry)| (*entry, addrs[index(range.start)..index(range.end)].to_vec()))
+            .filter(|(_, body)| !body.is_empty())
+            .collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty()
+            && self.calls.is_empty()
+            && self.branches.is_empty()
+            && self.conds.is_empty()
+            && self.functions.is_empty()
+    }
+}
+
+pub fn load_debug(
+    path: &Path,
+    search: &DebugSearch,
+    debug: bool,
+) -> Result<symtab::Context<gimli::EndianReader<gimli::RunTimeEndian, MappedSlice>>, Error> {
+    let map = {
+        let file = File::open(path).context("Failed to open object")?;
+
+        MappedSlice::new(file)?
+    };
+    let objfile = object::File::parse(&*map)
+        .map_err(ObjectError)
+        .context("object file parse failed")?;
+
+    let linkobj;
+    let linkmap;
+
+    let (objfile, mapping) = match search.find(path, &objfile, debug) {
+        Some(debugmap) => {
+            linkmap = debugmap;
+            // Already parsed successfully when it was checked
+            linkobj = object::File::parse(&*linkmap)
+                .map_err(ObjectError)
+                .context("debug file parse failed")?;
+
+            drop(objfile);
+            drop(map);
+            (&linkobj, &linkmap)
+        }
+        // No separate debug info, just use the object
+        None => (&objfile, &map),
+    };
+
+    let mut ctxt = symtab::Context::new_from_mapping(mapping, objfile)?;
+    if ctxt.skipped_units() > 0 {
+        println!(
+            "Warning: skipped {} units in {} with unsupported DWARF 5 unit types, such as \
+             -gsplit-dwarf skeletons; build with -gdwarf-4 to get coverage for them",
+            ctxt.skipped_units(),
+            path.display()
+        );
+    }
+    split_dwarf::load_split_units(&mut ctxt, path, debug);
+
+    Ok(ctxt)
+}
+
+pub fn get_breakpoints(obj: &ObjectInfo, config: &BreakpointConfig) -> Result<Breakpoints, Error> {
+    let &BreakpointConfig {
+        ref filter,
+        ref exclusions,
+        ref search,
+        ref cache,
+        placement,
+        inline,
+        branches,
+        debug,
+    } = config;
+
+    if debug {
+        println!("Object {:x?}", obj);
+    }
+
+    let start = Instant::now();
+    // Marker exclusions are applied afterwards, since they depend on the source files
+    // rather than the object
+    if let Some(mut bps) = cache.as_ref().and_then(|cache| cache.load(obj, debug)) {
+        if debug {
+            println!(
+                "{}: loaded cached breakpoints in {:.1?}",
+                obj.path.display(),
+                start.elapsed()
+            );
+        }
+        bps.exclude_marked(exclusions);
+        return Ok(bps);
+    }
+
+    let ctxt = load_debug(&obj.path, search, debug)?;
+    let loaded = start.elapsed();
+    let code = if placement == Placement::Block || branches {
+        Some(disasm::Code::new(&obj.path)?)
+    } else {
+        None
+    };
+
+    let (lines, calls) = match &code {
+        Some(code) if placement == Placement::Block => {
+            block_breakpoints(obj, &ctxt, code, filter, inline, debug)?
+        }
+        _ => line_breakpoints(obj, &ctxt, filter, placement, inline, debug)?,
+    };
+    let mut bps = match &code {
+        Some(code) if branches => branch_breakpoints(obj, &ctxt, code, filter, debug)?,
+        _ => Breakpoints::default(),
+    };
+    bps.lines = lines;
+    bps.calls = calls;
+    let (functions, ranges) = function_breakpoints(obj, &ctxt, filter, debug)?;
+    bps.functions = functions;
+    bps.ranges = ranges;
+
+    if filter.filters_functions() {
+        let excluded = excluded_functions(&ctxt, filter, debug)?;
+        bps.exclude(&excluded, obj.addr);
+    }
+    if let Some(cache) = cache {
+        cache.store(obj, &bps, debug);
+    }
+    bps.exclude_marked(exclusions);
+
+    if debug {
+        println!(
+            "{}: loaded debug info in {:.1?}, found breakpoints in {:.1?}",
+            obj.path.display(),
+            loaded,
+            start.elapsed() - loaded
+        );
+    }
+
+    Ok(bps)
+}
+
+/// Get breakpoints from the line table, along with the call sites they credit
+fn line_breakpoints<R>(
+    obj: &ObjectInfo,
+    ctxt: &symtab::Context<R>,
+    filter: &Filter,
+    placement: Placement,
+    inline: Inline,
+    debug: bool,
+) -> Result<(Vec<(u64, Location)>, Vec<(u64, Location)>), Error>
+where
+    R: gimli::Reader + Send + Sync,
+    R::Offset: Send + Sync,
+{
+    //println!("units for {}: {:#?}", obj.path.display(), ctxt.units());
+    let units = ctxt
+        .units()
+        .par_iter()
+        .map(|unit| unit_line_breakpoints(obj, ctxt, unit, filter, inline, debug))
+        .collect::<Result<Vec<_>, Error>>()?;
+
+    // Number line sequences across all units
+    let mut locations = Vec::new();
+    let mut sequence = 0;
+    for (unit_locations, sequences) in units {
+        locations.extend(
+            unit_locations
+                .into_iter()
+                .map(|(addr, seq, attributed)| (addr, sequence + seq, attributed)),
+        );
+        sequence += sequences;
+    }
+
+    let total = locations.len();
+    let locations = placement.select(locations);
+
+    if debug {
+        println!(
+            "{}: {:?} placement reduced {} breakpoints to {}",
+            obj.path.display(),
+            placement,
+            total,
+            locations.len()
+        );
+    }
+
+    Ok(split_calls(locations))
+}
+
+/// Get the line table breakpoint candidates of one unit, with their line sequence numbers
+/// within the unit, and the number of sequences.
+fn unit_line_breakpoints<R: gimli::Reader>(
+    obj: &ObjectInfo,
+    ctxt: &symtab::Context<R>,
+    unit: &gimli::Unit<R>,
+    filter: &Filter,
+    inline: Inline,
+    debug: bool,
+) -> Result<
+    (
+        Vec<(u64, usize, (Location, SmallVec<[Location; 2]>))>,
+        usize,
+    ),
+    Error,
+> {
+    let mut locations = Vec::new();
+    // Line sequence number within the unit
+    let mut sequence = 0;
+
+    let comp_dir = unit
+        .comp_dir
+        .as_ref()
+        .map(|dir| dir.to_string_lossy().map(|dir| dir.into_owned()))
+        .transpose()?;
+    let comp_dir = Path::new(comp_dir.as_ref().map(String::as_str).unwrap_or("."));
+
+    if debug {
+        println!(
+            "==== NEW UNIT ==== {} {}",
+            comp_dir.display(),
+            unit.name
+                .as_ref()
+                .map(|name| name.to_string_lossy().unwrap().into_owned())
+                .unwrap_or("???".to_string()),
+        );
+    }
+
+    let ilnp = match &unit.line_program {
+        Some(ilnp) => ilnp,
+        None => return Ok((locations, 0)),
+    };
+    // File-level filter, indexed by the per-unit file index number
+    let allowed_files: Vec<Option<(SrcPath, bool)>> = symtab::line_files(&ctxt.sections, unit)?
+        .into_iter()
+        .map(|file| {
+            file.map(|file| {
+                let srcpath = filter.srcpath(Path::new(&file));
+                (srcpath, filter.allows(&srcpath))
+            })
+        })
+        .collect();
+
+    if debug {
+        println!("allowed_files {:?}", allowed_files);
+    }
+    // Code is inlined into functions in the same unit, so if none of its
+    // files are interesting, none of the call sites can be either.
+    if !allowed_files.iter().flatten().any(|&(_, allow)| allow) {
+        return Ok((locations, 0));
+    }
+
+    let mut rows = ilnp.clone().rows();
+    while let Some((_, row)) = rows.next_row()? {
+        if row.end_sequence() {
+            sequence += 1;
+            continue;
+        }
+        if !row.is_stmt() {
+            continue;
+        }
+        let (srcpath, allow) = match allowed_files.get(row.file_index() as usize) {
+            Some(Some(file)) => *file,
+            _ => continue,
+        };
+        if !allow && inline == Inline::Callee {
+            continue;
+        }
+        let loc = if allow {
+            Some(Location::new(srcpath, row.line().unwrap_or(0) as u32))
+        } else {
+            None
+        };
+
+        let (loc, callers) = match attribute(ctxt, row.address(), loc, filter, inline)? {
+            Some(attributed) => attributed,
+            None => continue,
+        };
+        let addr = row.address() + obj.addr as u64;
+
+        if debug {
+            println!(
+                "Location: {}:{} {:x}",
+                loc.srcpath().display(),
+                loc.line(),
+                addr
+            );
+            for call in &callers {
+                println!("  Inlined at: {}:{}", call.srcpath().display(), call.line());
+            }
+        }
+
+        locations.push((addr, sequence, (loc, callers)));
+    }
+
+    Ok((locations, sequence))
+}
+
+/// Split breakpoints into line breakpoints and the call sites they credit
+fn split_calls(
+    locations: Vec<(u64, (Location, SmallVec<[Location; 2]>))>,
+) -> (Vec<(u64, Location)>, Vec<(u64, Location)>) {
+    let mut lines = Vec::with_capacity(locations.len());
+    let mut calls = Vec::new();
+
+    for (addr, (loc, callers)) in locations {
+        lines.push((addr, loc));
+        calls.extend(callers.into_iter().map(|call| (addr, call)));
+    }
+
+    (lines, calls)
+}
+
+/// Work out which locations a breakpoint at `addr` credits, given its interesting line table
+/// location `loc` if any. Returns the location for the breakpoint itself, and any call sites
+/// it also credits, or None if there's nothing interesting.
+fn attribute<R: gimli::Reader>(
+    ctxt: &symtab::Context<R>,
+    addr: u64,
+    loc: Option<Location>,
+    filter: &Filter,
+    inline: Inline,
+) -> Result<Option<(Location, SmallVec<[Location; 2]>)>, Error> {
+    if inline == Inline::Callee {
+        return Ok(loc.map(|loc| (loc, SmallVec::new())));
+    }
+
+    // The first frame's location is the line table's; the rest are call sites, from the
+    // innermost to the outermost.
+    let mut callers = SmallVec::<[Option<Location>; 2]>::new();
+    let mut frames = ctxt.find_frames(addr)?;
+    let _ = frames.next()?;
+    while let Some(frame) = frames.next()? {
+        callers.push(
+            frame
+                .location
+                .as_ref()
+                .and_then(|loc| to_location(loc, filter)),
+        );
+    }
+
+    Ok(credit(loc, &callers, inline))
+}
+
+/// Choose the locations a breakpoint credits, given its interesting line table location if
+/// any, and the call sites it was inlined through from the innermost to the outermost, each
+/// if it's interesting. Each location is credited at most once. Only the outermost call site
+/// is ever credited for `Inline::Caller`, so nothing is if it's uninteresting.
+fn credit(
+    loc: Option<Location>,
+    callers: &[Option<Location>],
+    inline: Inline,
+) -> Option<(Location, SmallVec<[Location; 2]>)> {
+    match inline {
+        Inline::Callee => loc.map(|loc| (loc, SmallVec::new())),
+        Inline::All => {
+            let mut locs = loc.iter().chain(callers.iter().flatten());
+            let first = *locs.next()?;
+            let mut calls = SmallVec::new();
+            for &call in locs {
+                if call != first && !calls.contains(&call) {
+                    calls.push(call);
+                }
+            }
+            Some((first, calls))
+        }
+        Inline::Caller => match callers.last() {
+            Some(&outermost) => outermost,
+            None => loc,
+        }
+        .map(|loc| (loc, SmallVec::new())),
+    }
+}
+
+/// Find the source location of an address using the line table, if it's in an interesting file
+fn find_location<R: gimli::Reader>(
+    ctxt: &symtab::Context<R>,
+    addr: u64,
+    filter: &Filter,
+) -> Result<Option<Location>, Error> {
+    Ok(ctxt
+        .find_location(addr)?
+        .and_then(|loc| to_location(&loc, filter)))
+}
+
+/// Convert a location from the debug info, if it's in an interesting file
+fn to_location(loc: &symtab::Location, filter: &Filter) -> Option<Location> {
+    let (file, line) = match loc {
+        symtab::Location {
+            file: Some(file),
+            line: Some(line),
+            ..
+        } => (Path::new(file), line),
+        _ => return None,
+    };
+    let srcpath = filter.srcpath(file);
+    if !filter.allows(&srcpath) {
+        return None;
+    }
+
+    Some(Location::new(srcpath, *line as u32))
+}
+
+/// Name of a function for reports and filtering. This is the demangled linkage name, since
+/// it has generic parameters and so on, or the qualified name if it isn't mangled.
+fn function_name<R: gimli::Reader>(func: &symtab::Function<R>) -> Result<String, Error> {
+    let (raw, name) = match &func.name {
+        Some(name) => (Some(name.raw_name()?), Some(name.demangle()?)),
+        None => (None, None),
+    };
+
+    Ok(match (&raw, name, &func.qualified_name) {
+        (Some(raw), Some(name), _) if name != *raw => name.into_owned(),
+        (_, _, Some(qualified)) => qualified.clone(),
+        (_, Some(name), None) => name.into_owned(),
+        (_, None, None) => format!("{:#x}", func.entry),
+    })
+}
+
+/// Return the sorted and merged code ranges of functions excluded by the filter
+fn excluded_functions<R: gimli::Reader>(
+    ctxt: &symtab::Context<R>,
+    filter: &Filter,
+    debug: bool,
+) -> Result<Vec<Range<u64>>, Error> {
+    let mut ranges = Vec::new();
+
+    for func in ctxt.functions()? {
+        let name = function_name(&func)?;
+        if !filter.allow_function(&name) {
+            if debug {
+                println!("Excluding function {}", name);
+            }
+            ranges.extend(func.ranges.iter().cloned());
+        }
+    }
+
+    ranges.sort_by_key(|range| range.start);
+    let mut merged: Vec<Range<u64>> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+
+    Ok(merged)
+}
+
+/// Get a breakpoint on the entry of each function declared in an interesting file, along
+/// with the functions' code ranges.
+fn function_breakpoints<R>(
+    obj: &ObjectInfo,
+    ctxt: &symtab::Context<R>,
+    filter: &Filter,
+    debug: bool,
+) -> Result<(Vec<(u64, Location, Function)>, Vec<(Range<u64>, u64)>), Error>
+where
+    R: gimli::Reader + Send + Sync,
+    R::Offset: Send + Sync,
+{
+    let units = (0..ctxt.units().len())
+        .into_par_iter()
+        .map(|unit_id| unit_function_breakpoints(obj, ctxt, unit_id, filter, debug))
+        .collect::<Result<Vec<_>, Error>>()?;
+
+    let mut functions = Vec::new();
+    let mut ranges = Vec::new();
+    for (unit_functions, unit_ranges) in units {
+        functions.extend(unit_functions);
+        ranges.extend(unit_ranges);
+    }
+    functions.sort_by_key(|&(addr, _, _)| addr);
+    ranges.sort_by_key(|(range, _)| range.start);
+
+    Ok((functions, ranges))
+}
+
+/// Get the function breakpoints and code ranges of one unit
+fn unit_function_breakpoints<R: gimli::Reader>(
+    obj: &ObjectInfo,
+    ctxt: &symtab::Context<R>,
+    unit_id: usize,
+    filter: &Filter,
+    debug: bool,
+) -> Result<(Vec<(u64, Location, Function)>, Vec<(Range<u64>, u64)>), Error> {
+    let objname = obj
+        .path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let mut functions = Vec::new();
+    let mut ranges = Vec::new();
+
+    for func in ctxt.unit_functions(unit_id)? {
+        let (file, line) = match func.decl {
+            symtab::Location {
+                file: Some(file),
+                line: Some(line),
+                ..
+            } => (Path::new(file), line),
+            _ => continue,
+        };
+        let srcpath = filter.srcpath(file);
+        if !filter.allows(&srcpath) {
+            continue;
+        }
+        let loc = match find_location(ctxt, func.entry, filter)? {
+            Some(loc) => loc,
+            None => continue,
+        };
+
+        let name = function_name(&func)?;
+        let krate = match func.scope.split("::").next() {
+            Some(krate) if !krate.is_empty() => krate,
+            _ => &objname,
+        };
+
+        if debug {
+            println!(
+                "Function: {} ({}) {}:{} {:x}",
+                name,
+                func.name
+                    .as_ref()
+                    .map(|name| name.raw_name())
+                    .transpose()?
+                    .unwrap_or_default(),
+                file.display(),
+                line,
+                func.entry + obj.addr
+            );
+        }
+
+        functions.push((
+            func.entry + obj.addr,
+            loc,
+            Function::new(srcpath, line as u32, &name, krate),
+        ));
+        ranges.extend(func.ranges.iter().map(|range| {
+            (
+                range.start + obj.addr..range.end + obj.addr,
+                func.entry + obj.addr,
+            )
+        }));
+    }
+
+    Ok((functions, ranges))
+}
+
+/// Get one breakpoint per basic block of each function, using the line table to map each
+/// block back to its source line.
+fn block_breakpoints<R>(
+    obj: &ObjectInfo,
+    ctxt: &symtab::Context<R>,
+    code: &disasm::Code,
+    filter: &Filter,
+    inline: Inline,
+    debug: bool,
+) -> Result<(Vec<(u64, Location)>, Vec<(u64, Location)>), Error>
+where
+    R: gimli::Reader + Send + Sync,
+    R::Offset: Send + Sync,
+{
+    let units = (0..ctxt.units().len())
+        .into_par_iter()
+        .map(|unit_id| unit_block_breakpoints(obj, ctxt, unit_id, code, filter, inline, debug))
+        .collect::<Result<Vec<_>, Error>>()?;
+
+    let mut locations = Vec::new();
+    let mut functions = 0;
+    for (unit_locations, unit_functions) in units {
+        locations.extend(unit_locations);
+        functions += unit_functions;
+    }
+    // Stable, so where functions share code the first unit's is kept
+    locations.sort_by_key(|(addr, _)| *addr);
+    locations.dedup_by_key(|(addr, _)| *addr);
+
+    if debug {
+        println!(
+            "{}: {} block breakpoints in {} functions",
+            obj.path.display(),
+            locations.len(),
+            functions
+        );
+    }
+
+    Ok(split_calls(locations))
+}
+
+/// Get the block breakpoints of one unit's functions, and the number of functions with code
+fn unit_block_breakpoints<R: gimli::Reader>(
+    obj: &ObjectInfo,
+    ctxt: &symtab::Context<R>,
+    unit_id: usize,
+    code: &disasm::Code,
+    filter: &Filter,
+    inline: Inline,
+    debug: bool,
+) -> Result<(Vec<(u64, (Location, SmallVec<[Location; 2]>))>, usize), Error> {
+    let mut locations = Vec::new();
+    let mut functions = 0;
+
+    for range in ctxt.unit_function_ranges(unit_id)? {
+        let leaders = match code.block_leaders(&range) {
+            Some(leaders) => leaders,
+            None => {
+                if debug {
+                    println!("No code for function at {:x?}", range);
+                }
+                continue;
+            }
+        };
+        functions += 1;
+
+        for addr in leaders {
+            let loc = find_location(ctxt, addr, filter)?;
+            if let Some(attributed) = attribute(ctxt, addr, loc, filter, inline)? {
+                locations.push((addr + obj.addr, attributed));
+            }
+        }
+    }
+
+    Ok((locations, functions))
+}
+
+/// Get breakpoints for both arms of each conditional branch. Where an arm's successor can only
+/// be reached through the branch, the breakpoint goes there, and also records the branch's
+/// own line since it must have executed. Otherwise the branch itself is evaluated when it's
+/// hit.
+fn branch_breakpoints<R>(
+    obj: &ObjectInfo,
+    ctxt: &symtab::Context<R>,
+    code: &disasm::Code,
+    filter: &Filter,
+    debug: bool,
+) -> Result<Breakpoints, Error>
+where
+    R: gimli::Reader + Send + Sync,
+    R::Offset: Send + Sync,
+{
+    let units = (0..ctxt.units().len())
+        .into_par_iter()
+        .map(|unit_id| unit_cond_branches(ctxt, unit_id, code, filter))
+        .collect::<Result<Vec<_>, Error>>()?;
+    // Branches are numbered in address order within each line, across all units
+    let mut condbranches: Vec<_> = units.into_iter().flatten().collect();
+    condbranches.sort_by_key(|(br, _)| br.addr);
+
+    let mut bps = Breakpoints::default();
+    // Next branch number for each line
+    let mut numbers: HashMap<(SrcPath, u32), u32> = HashMap::new();
+
+    for (br, loc) in condbranches {
+        let number = numbers.entry((loc.file(), loc.line())).or_insert(0);
+        let arms = [
+            BranchArm::new(&obj.path, loc.file(), loc.line(), *number, 0),
+            BranchArm::new(&obj.path, loc.file(), loc.line(), *number, 1),
+        ];
+        *number += 1;
+
+        let targets = [br.taken + obj.addr, br.not_taken + obj.addr];
+        let mut pending = [false; 2];
+        for arm in 0..2 {
+            if br.exclusive[arm] {
+                bps.branches.push((targets[arm], loc, arms[arm]));
+            } else {
+                pending[arm] = true;
+            }
+        }
+
+        if pending.iter().any(|&p| p) {
+            bps.conds.push((
+                br.addr + obj.addr,
+                loc,
+                CondBp {
+                    cond: br.cond,
+                    targets,
+                    arms,
+                    pending,
+                },
+            ));
+        }
+    }
+
+    if debug {
+        println!(
+            "{}: {} branch arm breakpoints, {} evaluated branches",
+            obj.path.display(),
+            bps.branches.len(),
+            bps.conds.len(),
+        );
+    }
+
+    Ok(bps)
+}
+
+/// Get the conditional branches in one unit's functions whose location is interesting
+fn unit_cond_branches<R: gimli::Reader>(
+    ctxt: &symtab::Context<R>,
+    unit_id: usize,
+    code: &disasm::Code,
+    filter: &Filter,
+) -> Result<Vec<(disasm::CondBranch, Location)>, Error> {
+    let mut found = Vec::new();
+
+    for range in ctxt.unit_function_ranges(unit_id)? {
+        let condbranches = match code.cond_branches(&range) {
+            Some(condbranches) => condbranches,
+            None => continue,
+        };
+
+        for br in condbranches {
+            if let Some(loc) = find_location(ctxt, br.addr, filter)? {
+                found.push((br, loc));
+            }
+        }
+    }
+
+    Ok(found)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn exclude_marked() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("lib.c");
+        fs::write(
+            &path,
+            "int f(void) {\n\treturn g(); // LCOV_EXCL_LINE\n}\nint g(void) {\n\treturn 1;\n}\n",
+        )
+        .unwrap();
+        let loc = |line| Location::new(SrcPath::from_path(&path), line);
+        let arm = BranchArm::new(Path::new("/lib.so"), loc(2).file(), 2, 0, 0);
+
+        let mut bps = Breakpoints {
+            lines: vec![(0x10, loc(2)), (0x20, loc(5))],
+            // Call sites credited along with each line
+            calls: vec![(0x10, loc(5)), (0x20, loc(5))],
+            branches: vec![(0x20, loc(2), arm)],
+            ..Default::default()
+        };
+        bps.exclude_marked(&Exclusions::new::<&str>(&[]));
+
+        assert_eq!(bps.lines, vec![(0x20, loc(5))]);
+        assert_eq!(bps.calls, vec![(0x20, loc(5))]);
+        assert!(bps.branches.is_empty());
+    }
+
+    #[test]
+    fn nested_inlining() {
+        let loc = |line| Location::new(SrcPath::from_path(Path::new("/src/lib.rs")), line);
+        let credited = |loc, callers: &[Option<Location>], inline| {
+            credit(loc, callers, inline).map(|(loc, calls)| (loc, calls.into_vec()))
+        };
+
+        // Line 3 inlined through call sites at lines 13, 23 and 13 again, as happens with
+        // recursion, and finally at line 33 in the function it was compiled into
+        let callers = [Some(loc(13)), Some(loc(23)), Some(loc(13)), Some(loc(33))];
+        assert_eq!(
+            credited(Some(loc(3)), &callers, Inline::Callee),
+            Some((loc(3), vec![]))
+        );
+        assert_eq!(
+            credited(Some(loc(3)), &callers, Inline::All),
+            Some((loc(3), vec![loc(13), loc(23), loc(33)]))
+        );
+        assert_eq!(
+            credited(Some(loc(3)), &callers, Inline::Caller),
+            Some((loc(33), vec![]))
+        );
+
+        // An uninteresting inlined function still credits its interesting call sites
+        assert_eq!(credited(None, &callers, Inline::Callee), None);
+        assert_eq!(
+            credited(None, &callers, Inline::All),
+            Some((loc(13), vec![loc(23), loc(33)]))
+        );
+        assert_eq!(
+            credited(None, &callers[..3], Inline::Caller),
+            Some((loc(13), vec![]))
+        );
+
+        // Code which wasn't inlined
+        assert_eq!(
+            credited(Some(loc(3)), &[], Inline::Caller),
+            Some((loc(3), vec![]))
+        );
+        assert_eq!(credited(None, &[], Inline::All), None);
+    }
+
+    #[test]
+    fn uninteresting_caller() {
+        let loc = |line| Location::new(SrcPath::from_path(Path::new("/src/lib.rs")), line);
+        let credited = |loc, callers: &[Option<Location>], inline| {
+            credit(loc, callers, inline).map(|(loc, calls)| (loc, calls.into_vec()))
+        };
+
+        // Line 3 inlined through an interesting call site at line 13 into a function which
+        // the filter rejects, so the outermost call site is uninteresting
+        let callers = [Some(loc(13)), None];
+        assert_eq!(
+            credited(Some(loc(3)), &callers, Inline::All),
+            Some((loc(3), vec![loc(13)]))
+        );
+        // The inner call site isn't credited in its place
+        assert_eq!(credited(Some(loc(3)), &callers, Inline::Caller), None);
+
+        // An uninteresting call site in the middle is just skipped
+        let callers = [None, Some(loc(33))];
+        assert_eq!(
+            credited(Some(loc(3)), &callers, Inline::All),
+            Some((loc(3), vec![loc(33)]))
+        );
+        assert_eq!(
+            credited(Some(loc(3)), &callers, Inline::Caller),
+            Some((loc(33), vec![]))
+        );
+    }
+}
@@ -0,0 +1,639 @@
+//! On-disk cache of each object's breakpoints, so repeated runs needn't parse its debug info
+//! again.
+//!
+//! Entries are keyed by the object's build-id, or its path, modification time and size if it
+//! doesn't have one. Addresses are stored relative to the object's load address, and source
+//! paths and names in a string table. Each entry has a hash of the settings which affect
+//! which breakpoints are found, and is ignored if they've changed.
+//!
+//! An entry is a flat file which is mapped and read in place. It starts with a header of the
+//! magic number, the settings hash, and the offset and number of records of each section,
+//! followed by the sections themselves, each 8-byte aligned. Records are fixed size, and all
+//! values are little-endian `u32`s or `u64`s, except for the condition and pending flags of a
+//! conditional branch which are bytes. A location is a file's string index and a line, and a
+//! branch arm is a location, branch number and arm number. The sections are:
+//!
+//! - strings: the offset and length of each string in the string data
+//! - string data: UTF-8 bytes
+//! - lines and calls: address and location
+//! - branches: address, location and arm
+//! - conds: address, location, condition, pending flags, padding, targets and arms
+//! - functions: address, location, declaration location, and name and crate string indices
+//! - ranges: start, end and entry
+
+use anyhow::{anyhow, bail, Context, Error};
+use inject_types::ObjectInfo;
+use once_cell::unsync::OnceCell;
+use std::{
+    collections::HashMap,
+    convert::TryInto,
+    env,
+    fs::{self, File},
+    io::{self, Write},
+    os::unix::{ffi::OsStrExt, fs::MetadataExt},
+    path::{Path, PathBuf},
+    str,
+};
+
+use crate::{
+    breakpoints::Breakpoints,
+    debuginfo,
+    disasm::Cond,
+    error::ObjectError,
+    mapped_slice::MappedSlice,
+    process::CondBp,
+    srcloc::{BranchArm, Function, Location, SrcPath},
+};
+
+/// Identifies the file format, and changes whenever it does
+const MAGIC: [u8; 8] = *b"rkcovbp3";
+
+/// Size of the header: magic, settings, and an offset and count for each section
+const HEADER_SIZE: usize = 16 + 16 * SECTIONS.len();
+
+/// Size of an encoded location and branch arm
+const LOC_SIZE: usize = 8;
+const ARM_SIZE: usize = LOC_SIZE + 8;
+
+/// Conditions by their encoding
+const CONDS: [Cond; 16] = [
+    Cond::O,
+    Cond::NO,
+    Cond::B,
+    Cond::AE,
+    Cond::E,
+    Cond::NE,
+    Cond::BE,
+    Cond::A,
+    Cond::S,
+    Cond::NS,
+    Cond::P,
+    Cond::NP,
+    Cond::L,
+    Cond::GE,
+    Cond::LE,
+    Cond::G,
+];
+
+/// Sections of an entry, in the order they're stored
+#[derive(Debug, Clone, Copy)]
+enum Section {
+    Strings,
+    StringData,
+    Lines,
+    Calls,
+    Branches,
+    Conds,
+    Functions,
+    Ranges,
+}
+
+const SECTIONS: [Section; 8] = [
+    Section::Strings,
+    Section::StringData,
+    Section::Lines,
+    Section::Calls,
+    Section::Branches,
+    Section::Conds,
+    Section::Functions,
+    Section::Ranges,
+];
+
+impl Section {
+    /// Size of each of the section's records
+    fn record_size(self) -> usize {
+        match self {
+            Section::Strings => 8,
+            Section::StringData => 1,
+            Section::Lines | Section::Calls => 8 + LOC_SIZE,
+            Section::Branches => 8 + LOC_SIZE + ARM_SIZE,
+            Section::Conds => 8 + LOC_SIZE + 8 + 16 + 2 * ARM_SIZE,
+            Section::Functions => 8 + 2 * LOC_SIZE + 8,
+            Section::Ranges => 24,
+        }
+    }
+}
+
+/// Return the user's cache directory, `$XDG_CACHE_HOME` or `~/.cache`
+pub fn cache_home() -> Option<PathBuf> {
+    env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))
+}
+
+#[derive(Debug, Clone)]
+pub struct BreakpointCache {
+    dir: PathBuf,
+    /// Hash of the settings entries must have been made with
+    settings: u64,
+}
+
+impl BreakpointCache {
+    /// Make a cache in `dir`, for breakpoints found with `settings`, which should describe
+    /// everything affecting which breakpoints are found.
+    pub fn new(dir: PathBuf, settings: &str) -> Self {
+        BreakpointCache {
+            dir,
+            settings: crc::crc64::checksum_ecma(settings.as_bytes()),
+        }
+    }
+
+    /// Make a cache in `$XDG_CACHE_HOME/ruskcov`
+    pub fn from_env(settings: &str) -> Option<Self> {
+        Some(BreakpointCache::new(
+            cache_home()?.join("ruskcov"),
+            settings,
+        ))
+    }
+
+    /// Get an object's breakpoints, if they're cached with the current settings
+    pub fn load(&self, obj: &ObjectInfo, debug: bool) -> Option<Breakpoints> {
+        let path = self.path(&obj.path)?;
+
+        match self.read(&path, obj) {
+            Ok(bps) => Some(bps),
+            Err(err) => {
+                if debug {
+                    println!("cache {}: {:#}", path.display(), err);
+                }
+                None
+            }
+        }
+    }
+
+    /// Save an object's breakpoints. Objects without any aren't saved, since that may be
+    /// because their debug info is missing, which can change without the object changing.
+    pub fn store(&self, obj: &ObjectInfo, bps: &Breakpoints, debug: bool) {
+        if bps.is_empty() {
+            return;
+        }
+
+        let result = self
+            .path(&obj.path)
+            .ok_or_else(|| anyhow!("can't identify {}", obj.path.display()))
+            .and_then(|path| {
+                self.write(&path, &encode(bps, obj.addr, self.settings))
+                    .with_context(|| format!("saving {}", path.display()))
+            });
+        if let Err(err) = result {
+            if debug {
+                println!("cache: {:#}", err);
+            }
+        }
+    }
+
+    /// Path of an object's entry
+    fn path(&self, objpath: &Path) -> Option<PathBuf> {
+        let map = MappedSlice::new(File::open(objpath).ok()?).ok()?;
+        let obj = object::File::parse(&*map).map_err(ObjectError).ok()?;
+
+        let key = match debuginfo::build_id(&obj) {
+            Some(id) => debuginfo::hex(&id),
+            None => {
+                let meta = fs::metadata(objpath).ok()?;
+                let mut key = objpath.as_os_str().as_bytes().to_vec();
+                key.extend_from_slice(&meta.mtime().to_le_bytes());
+                key.extend_from_slice(&meta.mtime_nsec().to_le_bytes());
+                key.extend_from_slice(&meta.size().to_le_bytes());
+                format!("path-{:016x}", crc::crc64::checksum_ecma(&key))
+            }
+        };
+
+        Some(self.dir.join(key))
+    }
+
+    /// Read the breakpoints in the entry at `path` for an object
+    fn read(&self, path: &Path, obj: &ObjectInfo) -> Result<Breakpoints, Error> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => bail!("missing"),
+            Err(err) => return Err(err.into()),
+        };
+        let map = MappedSlice::new(file)?;
+
+        Entry::new(&map, self.settings)?.breakpoints(obj)
+    }
+
+    /// Write an entry via a temporary file, so a partial one is never seen
+    fn write(&self, path: &Path, entry: &[u8]) -> Result<(), Error> {
+        fs::create_dir_all(&self.dir)?;
+        let mut tmp = tempfile::NamedTempFile::new_in(&self.dir)?;
+        tmp.as_file_mut().write_all(entry)?;
+        let _ = tmp.persist(path).map_err(|err| err.error)?;
+
+        Ok(())
+    }
+}
+
+/// Encode an object's breakpoints as an entry, with addresses relative to its load address
+/// `base`
+fn encode(bps: &Breakpoints, base: u64, settings: u64) -> Vec<u8> {
+    let mut enc = Encoder::default();
+
+    for &(addr, loc) in &bps.lines {
+        enc.u64(Section::Lines, addr - base);
+        enc.loc(Section::Lines, loc.file(), loc.line());
+    }
+    for &(addr, loc) in &bps.calls {
+        enc.u64(Section::Calls, addr - base);
+        enc.loc(Section::Calls, loc.file(), loc.line());
+    }
+    for &(addr, loc, arm) in &bps.branches {
+        enc.u64(Section::Branches, addr - base);
+        enc.loc(Section::Branches, loc.file(), loc.line());
+        enc.arm(Section::Branches, arm);
+    }
+    for &(addr, loc, cond) in &bps.conds {
+        let index = CONDS.iter().position(|&c| c == cond.cond).unwrap_or(0);
+        enc.u64(Section::Conds, addr - base);
+        enc.loc(Section::Conds, loc.file(), loc.line());
+        enc.bytes(
+            Section::Conds,
+            &[
+                index as u8,
+                cond.pending[0] as u8,
+                cond.pending[1] as u8,
+                0,
+                0,
+                0,
+                0,
+                0,
+            ],
+        );
+        enc.u64(Section::Conds, cond.targets[0] - base);
+        enc.u64(Section::Conds, cond.targets[1] - base);
+        enc.arm(Section::Conds, cond.arms[0]);
+        enc.arm(Section::Conds, cond.arms[1]);
+    }
+    for &(addr, loc, func) in &bps.functions {
+        enc.u64(Section::Functions, addr - base);
+        enc.loc(Section::Functions, loc.file(), loc.line());
+        enc.loc(Section::Functions, func.file(), func.line());
+        let name = enc.string(func.name());
+        let krate = enc.string(func.krate());
+        enc.u32(Section::Functions, name);
+        enc.u32(Section::Functions, krate);
+    }
+    for (range, entry) in &bps.ranges {
+        enc.u64(Section::Ranges, range.start - base);
+        enc.u64(Section::Ranges, range.end - base);
+        enc.u64(Section::Ranges, entry - base);
+    }
+
+    enc.finish(settings)
+}
+
+/// Encoder for an entry's sections
+#[derive(Default)]
+struct Encoder {
+    sections: [Vec<u8>; SECTIONS.len()],
+    /// Index of each distinct string
+    strings: HashMap<String, u32>,
+}
+
+impl Encoder {
+    fn bytes(&mut self, section: Section, bytes: &[u8]) {
+        self.sections[section as usize].extend_from_slice(bytes)
+    }
+
+    fn u32(&mut self, section: Section, val: u32) {
+        self.bytes(section, &val.to_le_bytes())
+    }
+
+    fn u64(&mut self, section: Section, val: u64) {
+        self.bytes(section, &val.to_le_bytes())
+    }
+
+    /// Return the index of a string, adding it if it's new
+    fn string(&mut self, s: &str) -> u32 {
+        if let Some(&idx) = self.strings.get(s) {
+            return idx;
+        }
+        let idx = self.strings.len() as u32;
+        let offset = self.sections[Section::StringData as usize].len() as u32;
+        self.u32(Section::Strings, offset);
+        self.u32(Section::Strings, s.len() as u32);
+        self.bytes(Section::StringData, s.as_bytes());
+        let _ = self.strings.insert(s.to_string(), idx);
+        idx
+    }
+
+    fn loc(&mut self, section: Section, srcpath: SrcPath, line: u32) {
+        let file = self.string(&srcpath.to_pathbuf().to_string_lossy());
+        self.u32(section, file);
+        self.u32(section, line);
+    }
+
+    fn arm(&mut self, section: Section, arm: BranchArm) {
+        self.loc(section, arm.file(), arm.line());
+        self.u32(section, arm.branch());
+        self.u32(section, arm.arm());
+    }
+
+    /// Lay out the header and sections
+    fn finish(self, settings: u64) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(&MAGIC);
+        out.extend_from_slice(&settings.to_le_bytes());
+
+        let mut offset = HEADER_SIZE;
+        for (section, data) in SECTIONS.iter().zip(&self.sections) {
+            out.extend_from_slice(&(offset as u64).to_le_bytes());
+            out.extend_from_slice(&((data.len() / section.record_size()) as u64).to_le_bytes());
+            offset += align(data.len());
+        }
+        for data in &self.sections {
+            out.extend_from_slice(data);
+            out.resize(align(out.len()), 0);
+        }
+        out
+    }
+}
+
+/// Round up to a multiple of 8
+fn align(len: usize) -> usize {
+    (len + 7) & !7
+}
+
+/// A mapped entry, read in place
+struct Entry<'a> {
+    data: &'a [u8],
+    /// Offset and number of records of each section
+    sections: [(usize, usize); SECTIONS.len()],
+}
+
+impl<'a> Entry<'a> {
+    /// Check an entry's header, and that it was made with `settings`
+    fn new(data: &'a [u8], settings: u64) -> Result<Self, Error> {
+        if data.len() < HEADER_SIZE {
+            bail!("bad header");
+        }
+        if data[..8] != MAGIC {
+            bail!("unknown format");
+        }
+        let mut header = Fields(&data[8..HEADER_SIZE]);
+        if header.u64() != settings {
+            bail!("stale, settings changed");
+        }
+
+        let mut sections = [(0, 0); SECTIONS.len()];
+        for (section, bounds) in SECTIONS.iter().zip(&mut sections) {
+            let offset = header.u64() as usize;
+            let count = header.u64() as usize;
+            match count
+                .checked_mul(section.record_size())
+                .and_then(|len| len.checked_add(offset))
+            {
+                Some(end) if end <= data.len() => {}
+                _ => bail!("bad {:?} section", section),
+            }
+            *bounds = (offset, count);
+        }
+
+        Ok(Entry { data, sections })
+    }
+
+    /// A section's records
+    fn records(&self, section: Section) -> impl Iterator<Item = Fields<'a>> {
+        let (offset, count) = self.sections[section as usize];
+        let size = section.record_size();
+        self.data[offset..offset + count * size]
+            .chunks_exact(size)
+            .map(Fields)
+    }
+
+    /// Make the breakpoints for an object, at the address it's loaded at
+    fn breakpoints(&self, obj: &ObjectInfo) -> Result<Breakpoints, Error> {
+        let base = obj.addr;
+        let (offset, len) = self.sections[Section::StringData as usize];
+        let string_data = &self.data[offset..offset + len];
+        let strings = self
+            .records(Section::Strings)
+            .map(|mut string| {
+                let start = string.u32() as usize;
+                let end = start + string.u32() as usize;
+                string_data
+                    .get(start..end)
+                    .and_then(|bytes| str::from_utf8(bytes).ok())
+                    .ok_or_else(|| anyhow!("bad string"))
+            })
+            .collect::<Result<Vec<&str>, _>>()?;
+
+        let string = |idx: u32| strings.get(idx as usize).copied().unwrap_or("");
+        // Each file's path is made once, rather than for every record in it. Strings which
+        // are function names are never made into paths.
+        let srcpaths: Vec<OnceCell<SrcPath>> = strings.iter().map(|_| OnceCell::new()).collect();
+        let srcpath = |idx: u32| match srcpaths.get(idx as usize) {
+            Some(cell) => *cell.get_or_init(|| SrcPath::from_path(Path::new(string(idx)))),
+            None => SrcPath::from_path(Path::new("")),
+        };
+        let loc = |fields: &mut Fields| {
+            let file = srcpath(fields.u32());
+            Location::new(file, fields.u32())
+        };
+        let arm = |fields: &mut Fields| {
+            let file = srcpath(fields.u32());
+            let line = fields.u32();
+            BranchArm::new(&obj.path, file, line, fields.u32(), fields.u32())
+        };
+
+        Ok(Breakpoints {
+            lines: self
+                .records(Section::Lines)
+                .map(|mut rec| (rec.u64() + base, loc(&mut rec)))
+                .collect(),
+            calls: self
+                .records(Section::Calls)
+                .map(|mut rec| (rec.u64() + base, loc(&mut rec)))
+                .collect(),
+            branches: self
+                .records(Section::Branches)
+                .map(|mut rec| (rec.u64() + base, loc(&mut rec), arm(&mut rec)))
+                .collect(),
+            conds: self
+                .records(Section::Conds)
+                .map(|mut rec| {
+                    let addr = rec.u64() + base;
+                    let loc = loc(&mut rec);
+                    let flags = rec.bytes8();
+                    let cond = *CONDS
+                        .get(flags[0] as usize)
+                        .ok_or_else(|| anyhow!("bad condition"))?;
+                    let targets = [rec.u64() + base, rec.u64() + base];
+                    let arms = [arm(&mut rec), arm(&mut rec)];
+                    Ok((
+                        addr,
+                        loc,
+                        CondBp {
+                            cond,
+                            targets,
+                            arms,
+                            pending: [flags[1] != 0, flags[2] != 0],
+                        },
+                    ))
+                })
+                .collect::<Result<_, Error>>()?,
+            functions: self
+                .records(Section::Functions)
+                .map(|mut rec| {
+                    let addr = rec.u64() + base;
+                    let at = loc(&mut rec);
+                    let decl = loc(&mut rec);
+                    let name = string(rec.u32());
+                    let krate = string(rec.u32());
+                    (
+                        addr,
+                        at,
+                        Function::new(decl.file(), decl.line(), name, krate),
+                    )
+                })
+                .collect(),
+            ranges: self
+                .records(Section::Ranges)
+                .map(|mut rec| {
+                    let start = rec.u64() + base;
+                    let end = rec.u64() + base;
+                    (start..end, rec.u64() + base)
+                })
+                .collect(),
+        })
+    }
+}
+
+/// The fields of a record, read in order
+struct Fields<'a>(&'a [u8]);
+
+impl<'a> Fields<'a> {
+    fn take(&mut self, len: usize) -> &'a [u8] {
+        let (field, rest) = self.0.split_at(len);
+        self.0 = rest;
+        field
+    }
+
+    fn bytes8(&mut self) -> [u8; 8] {
+        self.take(8).try_into().unwrap()
+    }
+
+    fn u32(&mut self) -> u32 {
+        u32::from_le_bytes(self.take(4).try_into().unwrap())
+    }
+
+    fn u64(&mut self) -> u64 {
+        u64::from_le_bytes(self.bytes8())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn object(addr: u64) -> ObjectInfo {
+        ObjectInfo {
+            pid: 1,
+            path: Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/lines-dwarf4"),
+            addr,
+            phdrs: vec![],
+        }
+    }
+
+    fn breakpoints(base: u64) -> Breakpoints {
+        let file = SrcPath::from_path(Path::new("/src/lib.rs"));
+        let other = SrcPath::from_path(Path::new("/src/other.rs"));
+        let arm = |arm| BranchArm::new(&object(base).path, file, 10, 0, arm);
+
+        Breakpoints {
+            lines: vec![(base + 0x10, Location::new(file, 3))],
+            calls: vec![(base + 0x10, Location::new(other, 7))],
+            branches: vec![(base + 0x20, Location::new(file, 10), arm(1))],
+            conds: vec![(
+                base + 0x18,
+                Location::new(file, 10),
+                CondBp {
+                    cond: Cond::NE,
+                    targets: [base + 0x20, base + 0x1a],
+                    arms: [arm(0), arm(1)],
+                    pending: [true, false],
+                },
+            )],
+            functions: vec![(
+                base + 0x8,
+                Location::new(file, 2),
+                Function::new(file, 1, "lib::func", "lib"),
+            )],
+            ranges: vec![(base + 0x8..base + 0x30, base + 0x8)],
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = BreakpointCache::new(dir.path().to_path_buf(), "settings");
+
+        assert!(cache.load(&object(0x1000), false).is_none());
+        cache.store(&object(0x1000), &breakpoints(0x1000), false);
+
+        // The fixture has no build-id, so it's keyed by path
+        let entries: Vec<_> = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        assert_eq!(entries.len(), 1);
+        assert!(entries[0].starts_with("path-"), "{:?}", entries);
+
+        // Loaded somewhere else
+        let bps = cache.load(&object(0x4000), false).unwrap();
+        let expected = breakpoints(0x4000);
+        assert_eq!(bps.lines, expected.lines);
+        assert_eq!(bps.calls, expected.calls);
+        assert_eq!(bps.branches, expected.branches);
+        assert_eq!(bps.functions, expected.functions);
+        assert_eq!(bps.ranges, expected.ranges);
+        assert_eq!(bps.conds.len(), 1);
+        let ((addr, loc, cond), (_, _, expected_cond)) = (bps.conds[0], expected.conds[0]);
+        assert_eq!((addr, loc), (expected.conds[0].0, expected.conds[0].1));
+        assert_eq!(cond.cond, expected_cond.cond);
+        assert_eq!(cond.targets, expected_cond.targets);
+        assert_eq!(cond.arms, expected_cond.arms);
+        assert_eq!(cond.pending, expected_cond.pending);
+    }
+
+    #[test]
+    fn invalidated() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = BreakpointCache::new(dir.path().to_path_buf(), "settings");
+        cache.store(&object(0), &breakpoints(0), false);
+
+        // Different settings
+        let other = BreakpointCache::new(dir.path().to_path_buf(), "other settings");
+        assert!(other.load(&object(0), false).is_none());
+        assert!(cache.load(&object(0), false).is_some());
+
+        // Corrupt
+        let entry = fs::read_dir(dir.path()).unwrap().next().unwrap().unwrap();
+        let data = fs::read(entry.path()).unwrap();
+        fs::write(entry.path(), &data[..data.len() / 2]).unwrap();
+        assert!(cache.load(&object(0), false).is_none());
+    }
+
+    #[test]
+    fn aligned() {
+        let data = encode(&breakpoints(0x1000), 0x1000, 0);
+        let entry = Entry::new(&data, 0).unwrap();
+
+        assert_eq!(data.len() % 8, 0);
+        for &(offset, _) in &entry.sections {
+            assert_eq!(offset % 8, 0);
+        }
+        assert_eq!(entry.records(Section::Lines).count(), 1);
+        assert_eq!(entry.records(Section::Conds).count(), 1);
+    }
+
+    #[test]
+    fn empty() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = BreakpointCache::new(dir.path().to_path_buf(), "settings");
+        cache.store(&object(0), &Breakpoints::default(), false);
+
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+}
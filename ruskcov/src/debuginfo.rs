@@ -0,0 +1,341 @@
+//! Finding separate debug info files for objects
+
+use anyhow::{anyhow, Context, Error};
+use object::read::Object;
+use std::{
+    env,
+    ffi::OsStr,
+    fs::File,
+    io, iter,
+    os::unix::ffi::OsStrExt,
+    path::{Component, Path, PathBuf},
+};
+
+use crate::{debuginfod::Debuginfod, error::ObjectError, mapped_slice::MappedSlice};
+
+/// Directory of separate debug info files
+const DEBUG_DIR: &str = "/usr/lib/debug";
+
+/// Environment variable with a `:`-separated list of extra debug directories
+pub const DEBUG_PATH_ENV: &str = "RUSKCOV_DEBUG_PATH";
+
+/// Where to look for separate debug info files
+#[derive(Debug, Clone)]
+pub struct DebugSearch {
+    /// Debug directories, each with a `.build-id` tree and debuglink files under the path of
+    /// their object
+    dirs: Vec<PathBuf>,
+    /// Server to fetch files by build-id from, if there's no local debug info at all
+    debuginfod: Option<Debuginfod>,
+}
+
+impl DebugSearch {
+    /// Search `dirs`, then those in `RUSKCOV_DEBUG_PATH`, then the system debug directory.
+    pub fn new(dirs: &[PathBuf], debuginfod: Option<Debuginfod>) -> Self {
+        let env_dirs = env::var_os(DEBUG_PATH_ENV)
+            .map(|path| env::split_paths(&path).collect::<Vec<_>>())
+            .unwrap_or_default();
+        let dirs = dirs
+            .iter()
+            .cloned()
+            .chain(env_dirs)
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .chain(iter::once(PathBuf::from(DEBUG_DIR)))
+            .collect();
+
+        DebugSearch { dirs, debuginfod }
+    }
+
+    /// Describe where debug info is searched for, for keying cached breakpoints. This leaves
+    /// out how debuginfod is queried, which doesn't change what's found.
+    pub fn cache_key(&self) -> String {
+        format!(
+            "debug dirs {:?} debuginfod {}",
+            self.dirs,
+            self.debuginfod
+                .as_ref()
+                .map_or_else(|| "none".to_string(), Debuginfod::cache_key)
+        )
+    }
+
+    /// Find a separate debug info file for an object, first by its build-id and then by its
+    /// debuglink in the local directories. Only if neither finds one and the object has no
+    /// DWARF of its own is it fetched from debuginfod.
+    pub fn find(&self, path: &Path, objfile: &object::File, debug: bool) -> Option<MappedSlice> {
+        let id = build_id(objfile);
+        let check = |obj: &object::File, _: &[u8]| {
+            if id.is_some() && build_id(obj) == id {
+                Ok(())
+            } else {
+                Err(anyhow!("build-id mismatch"))
+            }
+        };
+
+        if let Some(id) = &id {
+            if debug {
+                println!("{} => build-id {}", path.display(), hex(id));
+            }
+
+            let candidates = self.dirs.iter().filter_map(|dir| build_id_path(dir, id));
+            let found = try_candidates("build-id", candidates, debug, &check);
+            if found.is_some() {
+                return found;
+            }
+        }
+
+        if let Some((name, crc)) = objfile.gnu_debuglink() {
+            let name = Path::new(OsStr::from_bytes(name));
+            if debug {
+                println!(
+                    "{} => debuglink {} {:x}",
+                    path.display(),
+                    name.display(),
+                    crc
+                );
+            }
+
+            let candidates = debuglink_paths(path, name, &self.dirs);
+            let found = try_candidates("debuglink", candidates, debug, |_, map| {
+                let linked_crc = crc::crc32::checksum_ieee(map);
+                if crc == linked_crc {
+                    Ok(())
+                } else {
+                    Err(anyhow!(
+                        "crc mismatch got {:08x} wanted {:08x}",
+                        linked_crc,
+                        crc
+                    ))
+                }
+            });
+            if found.is_some() {
+                return found;
+            }
+        }
+
+        match (&id, &self.debuginfod) {
+            (Some(id), Some(debuginfod)) if !has_dwarf(objfile) => {
+                let candidates = debuginfod.fetch(&hex(id), debug);
+                try_candidates("debuginfod", candidates, debug, &check)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Whether an object has DWARF of its own, compressed or not
+fn has_dwarf(obj: &object::File) -> bool {
+    // This also finds `.zdebug_info`
+    obj.section_by_name(".debug_info").is_some()
+}
+
+/// Return the first candidate debug file which passes `check`, reporting why each of the
+/// others was rejected if `debug` is set.
+fn try_candidates<I, F>(kind: &str, candidates: I, debug: bool, check: F) -> Option<MappedSlice>
+where
+    I: IntoIterator<Item = PathBuf>,
+    F: Fn(&object::File, &[u8]) -> Result<(), Error>,
+{
+    for candidate in candidates {
+        match open_debug_file(&candidate, &check) {
+            Ok(map) => {
+                if debug {
+                    println!("Using {} {}", kind, candidate.display());
+                }
+                return Some(map);
+            }
+            Err(err) => {
+                if debug {
+                    println!("{} {}: {:#}", kind, candidate.display(), err);
+                }
+            }
+        }
+    }
+
+    None
+}
+
+/// Candidate paths for the debuglink `name` of the object at `path`: next to the object, in
+/// a `.debug` directory next to it, and under each of `dirs` at the object's directory.
+fn debuglink_paths(path: &Path, name: &Path, dirs: &[PathBuf]) -> Vec<PathBuf> {
+    let objdir = path.parent().unwrap_or(Path::new("."));
+    let relobjdir = objdir
+        .components()
+        .filter(|c| match c {
+            Component::Prefix { .. } | Component::RootDir => false,
+            _ => true,
+        })
+        .collect::<PathBuf>();
+
+    vec![objdir.join(name), objdir.join(".debug").join(name)]
+        .into_iter()
+        .chain(dirs.iter().map(|dir| dir.join(&relobjdir).join(name)))
+        .collect()
+}
+
+/// Map and parse a debug info file, and check it belongs to the object with `check`
+fn open_debug_file<F>(path: &Path, check: F) -> Result<MappedSlice, Error>
+where
+    F: FnOnce(&object::File, &[u8]) -> Result<(), Error>,
+{
+    let file = match File::open(path) {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Err(anyhow!("missing")),
+        file => file.context("open failed")?,
+    };
+    let map = MappedSlice::new(file).context("map failed")?;
+    let obj = object::File::parse(&*map)
+        .map_err(ObjectError)
+        .context("parse error")?;
+    check(&obj, &map)?;
+
+    Ok(map)
+}
+
+/// Get an object's build-id from its `NT_GNU_BUILD_ID` note
+pub fn build_id(obj: &object::File) -> Option<Vec<u8>> {
+    let data = obj.section_data_by_name(".note.gnu.build-id")?;
+    parse_build_id(&data, obj.is_little_endian()).map(Vec::from)
+}
+
+/// Find the `NT_GNU_BUILD_ID` note in a note section's data, returning its descriptor
+fn parse_build_id(mut data: &[u8], little_endian: bool) -> Option<&[u8]> {
+    const NT_GNU_BUILD_ID: u32 = 3;
+    let align = |len: u32| (len as usize + 3) & !3;
+    let word = |bytes: &[u8]| {
+        let bytes = [bytes[0], bytes[1], bytes[2], bytes[3]];
+        if little_endian {
+            u32::from_le_bytes(bytes)
+        } else {
+            u32::from_be_bytes(bytes)
+        }
+    };
+
+    // Each note is a header of name size, descriptor size and type, followed by the name
+    // and descriptor each padded to 4 bytes
+    while data.len() >= 12 {
+        let (namesz, descsz, ntype) = (word(&data[0..]), word(&data[4..]), word(&data[8..]));
+        let name_end = 12 + align(namesz);
+        let desc_end = name_end + align(descsz);
+        if desc_end > data.len() {
+            return None;
+        }
+
+        if ntype == NT_GNU_BUILD_ID && &data[12..12 + namesz as usize] == b"GNU\0" {
+            return Some(&data[name_end..name_end + descsz as usize]);
+        }
+        data = &data[desc_end..];
+    }
+
+    None
+}
+
+/// Path of the debug info file for a build-id within a debug directory, which is
+/// `.build-id/xx/yyyy.debug` where `xx` is the first byte of the id in hex.
+fn build_id_path(dir: &Path, id: &[u8]) -> Option<PathBuf> {
+    if id.len() < 2 {
+        return None;
+    }
+
+    let (first, rest) = id.split_at(1);
+    Some(
+        dir.join(".build-id")
+            .join(hex(first))
+            .join(format!("{}.debug", hex(rest))),
+    )
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn build_id_paths() {
+        assert_eq!(
+            build_id_path(Path::new("/usr/lib/debug"), &[0xab, 0xcd, 0x01, 0x23]),
+            Some(PathBuf::from("/usr/lib/debug/.build-id/ab/cd0123.debug"))
+        );
+        assert_eq!(build_id_path(Path::new("/usr/lib/debug"), &[0xab]), None);
+    }
+
+    #[test]
+    fn debuglinks() {
+        assert_eq!(
+            debuglink_paths(
+                Path::new("/usr/lib/libfoo.so"),
+                Path::new("libfoo.debug"),
+                &[PathBuf::from("/opt/debug"), PathBuf::from(DEBUG_DIR)]
+            ),
+            vec![
+                PathBuf::from("/usr/lib/libfoo.debug"),
+                PathBuf::from("/usr/lib/.debug/libfoo.debug"),
+                PathBuf::from("/opt/debug/usr/lib/libfoo.debug"),
+                PathBuf::from("/usr/lib/debug/usr/lib/libfoo.debug"),
+            ]
+        );
+    }
+
+    #[test]
+    fn build_id_note() {
+        let note = |ntype: u8, name: &[u8], desc: &[u8]| {
+            let mut note = vec![name.len() as u8, 0, 0, 0, desc.len() as u8, 0, 0, 0];
+            note.extend_from_slice(&[ntype, 0, 0, 0]);
+            note.extend_from_slice(name);
+            note.resize((note.len() + 3) & !3, 0);
+            note.extend_from_slice(desc);
+            note.resize((note.len() + 3) & !3, 0);
+            note
+        };
+
+        let mut data = note(1, b"GNU\0", &[0; 4]);
+        data.extend(note(3, b"Go\0", &[1, 2]));
+        data.extend(note(3, b"GNU\0", &[0xab, 0xcd, 0xef]));
+
+        assert_eq!(parse_build_id(&data, true), Some(&[0xab, 0xcd, 0xef][..]));
+        assert_eq!(parse_build_id(&data[..data.len() - 4], true), None);
+        assert_eq!(parse_build_id(&data[..16], true), None);
+    }
+
+    #[test]
+    fn cache_key() {
+        let search = |dirs: &[&str], timeout, offline| {
+            let dirs: Vec<_> = dirs.iter().map(PathBuf::from).collect();
+            let debuginfod = Debuginfod::new(
+                vec!["http://debuginfod.example.com".into()],
+                PathBuf::from("/tmp/debuginfod"),
+                Duration::from_secs(timeout),
+                offline,
+            );
+            DebugSearch::new(&dirs, Some(debuginfod)).cache_key()
+        };
+
+        assert_eq!(
+            search(&["/opt/debug"], 5, false),
+            search(&["/opt/debug"], 90, true)
+        );
+        assert_ne!(search(&["/opt/debug"], 5, false), search(&[], 5, false));
+        assert_ne!(
+            search(&[], 5, false),
+            DebugSearch::new(&[], None).cache_key()
+        );
+    }
+
+    #[test]
+    fn own_dwarf() {
+        let has_dwarf = |name| {
+            let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("testdata")
+                .join(name);
+            let map = MappedSlice::new(File::open(path).unwrap()).unwrap();
+            has_dwarf(&object::File::parse(&*map).unwrap())
+        };
+
+        assert!(has_dwarf("lines-dwarf4"));
+        assert!(has_dwarf("lines-dwarf4-zlib"));
+        // Stripped, so its debug info has to come from debuginfod
+        assert!(!has_dwarf("build-id"));
+    }
+}
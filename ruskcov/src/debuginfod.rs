@@ -0,0 +1,525 @@
+//! Client for debuginfod servers, which serve debug info files by build-id over HTTP.
+//!
+//! This follows the elfutils client's conventions: servers are listed in `DEBUGINFOD_URLS`,
+//! and downloaded files are cached as `<cache>/<build-id>/debuginfo`, so the cache can be
+//! shared with gdb and other tools. A build-id which no server has is recorded with an empty
+//! file there, which is trusted for `<cache>/cache_miss_s` seconds. Only plain `http://`
+//! servers are supported.
+
+use anyhow::{anyhow, bail, Context, Error};
+use std::{
+    env, fmt, fs,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime},
+};
+
+use crate::{
+    cache::cache_home,
+    debuginfo::{build_id, hex},
+    error::ObjectError,
+    mapped_slice::MappedSlice,
+};
+
+/// Space-separated list of server URLs
+pub const URLS_ENV: &str = "DEBUGINFOD_URLS";
+/// Cache directory
+pub const CACHE_ENV: &str = "DEBUGINFOD_CACHE_PATH";
+/// Timeout in seconds
+pub const TIMEOUT_ENV: &str = "DEBUGINFOD_TIMEOUT";
+
+/// Default time limit for each request
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(90);
+
+/// File in the cache directory with how long to trust a missing build-id, in seconds
+const CACHE_MISS_FILE: &str = "cache_miss_s";
+/// How long to trust a missing build-id if the cache doesn't say
+const DEFAULT_CACHE_MISS: Duration = Duration::from_secs(600);
+
+#[derive(Debug, Clone)]
+pub struct Debuginfod {
+    /// Server base URLs
+    urls: Vec<String>,
+    /// Directory of downloaded files
+    cache: PathBuf,
+    /// Time limit for each request, from connecting until the body is read
+    timeout: Duration,
+    /// Only use files already in the cache
+    offline: bool,
+    /// How long to trust a record that no server has a build-id
+    cache_miss: Duration,
+}
+
+impl Debuginfod {
+    /// Make a client configured from the environment, with an optional timeout overriding
+    /// `DEBUGINFOD_TIMEOUT`. Returns `None` if there are no servers, unless `offline` in which
+    /// case only the cache is used. `https://` servers are skipped with a warning.
+    pub fn from_env(timeout: Option<Duration>, offline: bool) -> Option<Self> {
+        let urls: Vec<String> = env::var(URLS_ENV)
+            .unwrap_or_default()
+            .split_whitespace()
+            .filter(|url| {
+                let https = url.starts_with("https://");
+                if https {
+                    eprintln!(
+                        "Warning: skipping debuginfod server {}, since only http:// is supported",
+                        url
+                    );
+                }
+                !https
+            })
+            .map(String::from)
+            .collect();
+        if urls.is_empty() && !offline {
+            return None;
+        }
+
+        let cache = env::var_os(CACHE_ENV)
+            .map(PathBuf::from)
+            .or_else(|| cache_home().map(|cache| cache.join("debuginfod_client")))?;
+        let timeout = timeout
+            .or_else(|| {
+                env::var(TIMEOUT_ENV)
+                    .ok()
+                    .and_then(|secs| secs.parse().ok())
+                    .map(Duration::from_secs)
+            })
+            .unwrap_or(DEFAULT_TIMEOUT);
+
+        Some(Debuginfod::new(urls, cache, timeout, offline))
+    }
+
+    pub fn new(urls: Vec<String>, cache: PathBuf, timeout: Duration, offline: bool) -> Self {
+        let cache_miss = fs::read_to_string(cache.join(CACHE_MISS_FILE))
+            .ok()
+            .and_then(|secs| secs.trim().parse().ok())
+            .map_or(DEFAULT_CACHE_MISS, Duration::from_secs);
+
+        Debuginfod {
+            urls,
+            cache,
+            timeout,
+            offline,
+            cache_miss,
+        }
+    }
+
+    /// Describe the servers and cache, for keying cached breakpoints
+    pub fn cache_key(&self) -> String {
+        format!("{:?} {}", self.urls, self.cache.display())
+    }
+
+    /// Return the path of the debug info file for a build-id, downloading it into the cache
+    /// from the first server which has it if it isn't already there. If none of them have it,
+    /// that's remembered for a while so they're not asked again.
+    pub fn fetch(&self, build_id: &str, debug: bool) -> Option<PathBuf> {
+        let path = self.cache.join(build_id).join("debuginfo");
+        if let Ok(meta) = fs::metadata(&path) {
+            if meta.len() > 0 {
+                return Some(path);
+            }
+            let age = meta
+                .modified()
+                .ok()
+                .and_then(|time| SystemTime::now().duration_since(time).ok());
+            if age.map_or(false, |age| age < self.cache_miss) {
+                if debug {
+                    println!("debuginfod {}: recently not found", build_id);
+                }
+                return None;
+            }
+            let _ = fs::remove_file(&path);
+        }
+        if self.offline {
+            if debug {
+                println!("debuginfod {}: not cached, offline", build_id);
+            }
+            return None;
+        }
+
+        let mut not_found = true;
+        for url in &self.urls {
+            let url = format!(
+                "{}/buildid/{}/debuginfo",
+                url.trim_end_matches('/'),
+                build_id
+            );
+            match self.download(&url, build_id, &path) {
+                Ok(()) => return Some(path),
+                Err(err) => {
+                    if debug {
+                        println!("debuginfod {}: {:#}", url, err);
+                    }
+                    not_found &= err
+                        .downcast_ref::<HttpStatus>()
+                        .map_or(false, |status| status.0 == "404");
+                }
+            }
+        }
+
+        // Only remember a definite answer, not that servers were unreachable
+        if not_found && !self.urls.is_empty() {
+            let _ =
+                fs::create_dir_all(path.parent().unwrap()).and_then(|()| fs::File::create(&path));
+        }
+
+        None
+    }
+
+    /// Download `url` to `path`, via a temporary file so a partial download is never seen.
+    /// It's discarded unless it's an object with `build_id`.
+    fn download(&self, url: &str, build_id: &str, path: &Path) -> Result<(), Error> {
+        let dir = path.parent().unwrap_or(Path::new("."));
+        fs::create_dir_all(dir).with_context(|| format!("creating {}", dir.display()))?;
+        let result = tempfile::NamedTempFile::new_in(dir)
+            .context("creating temp file")
+            .and_then(|mut tmp| {
+                http_get(url, self.timeout, tmp.as_file_mut())?;
+                check_build_id(tmp.as_file(), build_id)?;
+                tmp.persist(path)
+                    .map_err(|err| err.error)
+                    .with_context(|| format!("saving {}", path.display()))
+            });
+        if result.is_err() {
+            // Don't leave an empty directory for every build-id the servers don't have
+            let _ = fs::remove_dir(dir);
+        }
+
+        result.map(drop)
+    }
+}
+
+/// Check a downloaded file is an object with the build-id `wanted`
+fn check_build_id(file: &fs::File, wanted: &str) -> Result<(), Error> {
+    let map = MappedSlice::new(file.try_clone()?).context("map failed")?;
+    let obj = object::File::parse(&*map)
+        .map_err(ObjectError)
+        .context("parse error")?;
+    match build_id(&obj) {
+        Some(id) if hex(&id) == wanted => Ok(()),
+        Some(id) => bail!("build-id mismatch, got {}", hex(&id)),
+        None => bail!("no build-id"),
+    }
+}
+
+/// An HTTP status other than success
+#[derive(Debug)]
+struct HttpStatus(String);
+
+impl fmt::Display for HttpStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "HTTP status {}", self.0)
+    }
+}
+
+impl std::error::Error for HttpStatus {}
+
+/// A connection which fails reads and writes once `deadline` has passed, so that a slow
+/// server can't drag a request out one read at a time.
+struct DeadlineStream {
+    stream: TcpStream,
+    deadline: Instant,
+}
+
+impl DeadlineStream {
+    /// The time left before the deadline, for the next read or write's timeout
+    fn remaining(&self) -> io::Result<Duration> {
+        let now = Instant::now();
+        if now >= self.deadline {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "deadline passed"));
+        }
+        Ok(self.deadline - now)
+    }
+}
+
+impl Read for DeadlineStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.set_read_timeout(Some(self.remaining()?))?;
+        self.stream.read(buf)
+    }
+}
+
+impl Write for DeadlineStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.set_write_timeout(Some(self.remaining()?))?;
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+/// Fetch an `http://` URL, writing the body to `out`. The whole request, from connecting to
+/// reading the end of the body, must finish within `timeout`.
+fn http_get<W: Write>(url: &str, timeout: Duration, out: &mut W) -> Result<(), Error> {
+    let deadline = Instant::now() + timeout;
+    const SCHEME: &str = "http://";
+    if !url.starts_with(SCHEME) {
+        bail!("unsupported URL scheme");
+    }
+    let rest = &url[SCHEME.len()..];
+    let (host, path) = match rest.find('/') {
+        Some(idx) => rest.split_at(idx),
+        None => (rest, "/"),
+    };
+    // Add the default port unless there's one after any IPv6 address
+    let addr = match (host.rfind(':'), host.rfind(']')) {
+        (Some(colon), Some(bracket)) if colon > bracket => host.to_string(),
+        (Some(_), None) => host.to_string(),
+        _ => format!("{}:80", host),
+    };
+
+    let mut result = Err(anyhow!("no addresses for {}", host));
+    for addr in addr.to_socket_addrs().context("resolving host")? {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining == Duration::from_secs(0) {
+            break;
+        }
+        result = TcpStream::connect_timeout(&addr, remaining).map_err(Error::from);
+        if result.is_ok() {
+            break;
+        }
+    }
+    let mut stream = DeadlineStream {
+        stream: result.context("connect failed")?,
+        deadline,
+    };
+
+    // HTTP/1.0 so the response isn't chunked, and the body ends when the connection closes
+    let request = format!(
+        "GET {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: ruskcov\r\n\r\n",
+        path, host
+    );
+    stream
+        .write_all(request.as_bytes())
+        .context("sending request")?;
+
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    let _ = reader.read_line(&mut line).context("reading status")?;
+    match line.split_whitespace().nth(1) {
+        Some("200") => {}
+        Some(status) => return Err(HttpStatus(status.to_string()).into()),
+        None => bail!("bad HTTP status line {:?}", line.trim_end()),
+    }
+
+    let mut length = None;
+    loop {
+        line.clear();
+        if reader.read_line(&mut line).context("reading headers")? == 0 {
+            bail!("truncated headers");
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(idx) = header.find(':') {
+            let (name, value) = header.split_at(idx);
+            if name.eq_ignore_ascii_case("content-length") {
+                length = value[1..].trim().parse::<u64>().ok();
+            }
+        }
+    }
+
+    let copied = io::copy(&mut reader, out).context("reading body")?;
+    match length {
+        Some(length) if length != copied => {
+            bail!("truncated body, got {} of {} bytes", copied, length)
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::{net::TcpListener, thread};
+
+    /// Serve each of `responses` to one connection in turn, returning the base URL and a
+    /// handle which yields the requests.
+    fn serve(responses: Vec<Vec<u8>>) -> (String, thread::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+
+        let handle = thread::spawn(move || {
+            let mut requests = Vec::new();
+            for response in responses {
+                let (mut conn, _) = listener.accept().unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let len = conn.read(&mut buf).unwrap();
+                    assert_ne!(len, 0, "truncated request");
+                    request.extend_from_slice(&buf[..len]);
+                }
+                requests.push(String::from_utf8(request).unwrap());
+                conn.write_all(&response).unwrap();
+            }
+            requests
+        });
+
+        (url, handle)
+    }
+
+    /// Build-id of `object()`
+    const ID: &str = "0123456789abcdef";
+
+    /// An object to serve, built by `testdata/build.sh`
+    fn object() -> Vec<u8> {
+        fs::read(Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/build-id")).unwrap()
+    }
+
+    fn client(urls: Vec<String>, cache: &Path, offline: bool) -> Debuginfod {
+        Debuginfod::new(urls, cache.to_path_buf(), Duration::from_secs(5), offline)
+    }
+
+    fn ok(body: &[u8]) -> Vec<u8> {
+        let mut response =
+            format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", body.len()).into_bytes();
+        response.extend_from_slice(body);
+        response
+    }
+
+    #[test]
+    fn fetch_and_cache() {
+        let cache = tempfile::tempdir().unwrap();
+        let (url, server) = serve(vec![
+            b"HTTP/1.1 404 Not Found\r\n\r\n".to_vec(),
+            ok(&object()),
+        ]);
+
+        // The first server doesn't have it, so it's fetched from the second
+        let path = client(vec![url.clone(), format!("{}/", url)], cache.path(), false)
+            .fetch(ID, false)
+            .unwrap();
+        assert_eq!(path, cache.path().join(ID).join("debuginfo"));
+        assert_eq!(fs::read(&path).unwrap(), object());
+
+        let requests = server.join().unwrap();
+        assert!(requests[1].starts_with(&format!("GET /buildid/{}/debuginfo HTTP/1.0\r\n", ID)));
+
+        // Now it's cached, so no server is needed
+        assert_eq!(
+            client(vec![], cache.path(), true).fetch(ID, false),
+            Some(path)
+        );
+        assert_eq!(
+            client(vec![], cache.path(), true).fetch("ef01", false),
+            None
+        );
+    }
+
+    #[test]
+    fn not_found() {
+        let cache = tempfile::tempdir().unwrap();
+        let (url, server) = serve(vec![
+            b"HTTP/1.1 404 Not Found\r\n\r\n".to_vec(),
+            ok(&object()),
+        ]);
+
+        // The miss is remembered, so the server isn't asked again for a while
+        assert_eq!(
+            client(vec![url.clone()], cache.path(), false).fetch(ID, false),
+            None
+        );
+        let path = cache.path().join(ID).join("debuginfo");
+        assert_eq!(fs::metadata(&path).unwrap().len(), 0);
+        assert_eq!(
+            client(vec![url.clone()], cache.path(), false).fetch(ID, false),
+            None
+        );
+        assert_eq!(client(vec![], cache.path(), true).fetch(ID, false), None);
+
+        // Once it's stale, the server is asked again
+        fs::write(cache.path().join(CACHE_MISS_FILE), "0\n").unwrap();
+        assert_eq!(
+            client(vec![url], cache.path(), false).fetch(ID, false),
+            Some(path)
+        );
+        assert_eq!(server.join().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn mismatch() {
+        let cache = tempfile::tempdir().unwrap();
+        let (url, server) = serve(vec![ok(&object())]);
+
+        assert_eq!(
+            client(vec![url], cache.path(), false).fetch("abcd", false),
+            None
+        );
+        // Not kept, nor remembered as missing
+        assert!(!cache.path().join("abcd").exists());
+        let _ = server.join().unwrap();
+    }
+
+    #[test]
+    fn truncated() {
+        let cache = tempfile::tempdir().unwrap();
+        let (url, server) = serve(vec![
+            b"HTTP/1.0 200 OK\r\nContent-Length: 100\r\n\r\nshort".to_vec()
+        ]);
+        assert_eq!(
+            client(vec![url], cache.path(), false).fetch("abcd", false),
+            None
+        );
+        assert!(!cache.path().join("abcd").exists());
+        let _ = server.join().unwrap();
+    }
+
+    #[test]
+    fn timeout() {
+        // Accept the connection, but never respond
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let mut body = Vec::new();
+
+        let err = http_get(
+            &format!("{}/buildid/abcd/debuginfo", url),
+            Duration::from_millis(100),
+            &mut body,
+        )
+        .unwrap_err();
+        assert!(format!("{:#}", err).contains("reading status"));
+        drop(listener);
+    }
+
+    #[test]
+    fn deadline() {
+        // Respond, but trickle out the body more slowly than the time limit allows in all,
+        // though each read is quick
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let server = thread::spawn(move || {
+            let (mut conn, _) = listener.accept().unwrap();
+            let _ = conn.write_all(b"HTTP/1.0 200 OK\r\n\r\n");
+            for _ in 0..100 {
+                if conn.write_all(b"x").is_err() {
+                    break;
+                }
+                thread::sleep(Duration::from_millis(50));
+            }
+        });
+        let mut body = Vec::new();
+
+        let start = Instant::now();
+        let err = http_get(
+            &format!("{}/buildid/abcd/debuginfo", url),
+            Duration::from_millis(300),
+            &mut body,
+        )
+        .unwrap_err();
+        assert!(format!("{:#}", err).contains("reading body"));
+        assert!(start.elapsed() < Duration::from_secs(2));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn unsupported() {
+        let mut body = Vec::new();
+
+        assert!(http_get("https://example.com/", Duration::from_secs(1), &mut body).is_err());
+    }
+}
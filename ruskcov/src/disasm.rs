@@ -0,0 +1,420 @@
+//! Instruction decoding, for finding basic blocks within functions
+
+use anyhow::{Context, Error};
+use iced_x86::{ConditionCode, Decoder, DecoderOptions, FlowControl, Instruction};
+use object::{
+    read::{Object, ObjectSection},
+    SectionKind,
+};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    ops::Range,
+    path::Path,
+};
+
+use crate::{error::ObjectError, mapped_slice::MappedSlice};
+
+/// Executable code from an object file, indexed by link-time virtual address.
+pub struct Code {
+    bitness: u32,
+    /// Address range of each code section, and its contents
+    sections: Vec<(Range<u64>, MappedSlice)>,
+}
+
+impl Code {
+    pub fn new(path: &Path) -> Result<Self, Error> {
+        let map = {
+            let file = File::open(path).context("Failed to open object")?;
+
+            MappedSlice::new(file)?
+        };
+        let objfile = object::File::parse(&*map)
+            .map_err(ObjectError)
+            .context("object file parse failed")?;
+
+        let sections = objfile
+            .sections()
+            .filter(|section| section.kind() == SectionKind::Text)
+            .filter_map(|section| {
+                let (offset, size) = section.file_range()?;
+                let offset = offset as usize;
+                Some((
+                    section.address()..section.address() + size,
+                    map.subslice(offset..offset + size as usize),
+                ))
+            })
+            .collect();
+
+        Ok(Code {
+            bitness: if objfile.is_64() { 64 } else { 32 },
+            sections,
+        })
+    }
+
+    /// Return the code for an address range, if it's entirely within one section.
+    pub fn bytes(&self, range: &Range<u64>) -> Option<&[u8]> {
+        self.sections
+            .iter()
+            .find(|(secrange, _)| secrange.start <= range.start && range.end <= secrange.end)
+            .map(|(secrange, data)| {
+                let start = (range.start - secrange.start) as usize;
+                let end = (range.end - secrange.start) as usize;
+                &data[start..end]
+            })
+    }
+
+    /// Return the basic block leaders for a function's code range.
+    pub fn block_leaders(&self, range: &Range<u64>) -> Option<Vec<u64>> {
+        self.bytes(range)
+            .map(|code| block_leaders(code, range.start, self.bitness))
+    }
+
+    /// Return the conditional branches in a function's code range.
+    pub fn cond_branches(&self, range: &Range<u64>) -> Option<Vec<CondBranch>> {
+        self.bytes(range)
+            .map(|code| cond_branches(code, range.start, self.bitness))
+    }
+}
+
+/// A conditional branch instruction which depends only on the flags, and its two possible
+/// successors
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct CondBranch {
+    /// Address of the branch instruction
+    pub addr: u64,
+    /// Target if the branch is taken
+    pub taken: u64,
+    /// Next instruction if it isn't
+    pub not_taken: u64,
+    pub cond: Cond,
+    /// Whether each arm's successor (taken, not taken) can only be reached by this branch,
+    /// so that a breakpoint there records the arm unambiguously.
+    pub exclusive: [bool; 2],
+}
+
+/// x86 condition codes for `Jcc`, evaluated against the flags register
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+pub enum Cond {
+    O,
+    NO,
+    B,
+    AE,
+    E,
+    NE,
+    BE,
+    A,
+    S,
+    NS,
+    P,
+    NP,
+    L,
+    GE,
+    LE,
+    G,
+}
+
+impl Cond {
+    fn from_iced(cc: ConditionCode) -> Option<Self> {
+        use Cond::*;
+        Some(match cc {
+            ConditionCode::None => return None,
+            ConditionCode::o => O,
+            ConditionCode::no => NO,
+            ConditionCode::b => B,
+            ConditionCode::ae => AE,
+            ConditionCode::e => E,
+            ConditionCode::ne => NE,
+            ConditionCode::be => BE,
+            ConditionCode::a => A,
+            ConditionCode::s => S,
+            ConditionCode::ns => NS,
+            ConditionCode::p => P,
+            ConditionCode::np => NP,
+            ConditionCode::l => L,
+            ConditionCode::ge => GE,
+            ConditionCode::le => LE,
+            ConditionCode::g => G,
+        })
+    }
+
+    /// Return true if a branch with this condition is taken with the given flags register.
+    pub fn eval(self, flags: u64) -> bool {
+        use Cond::*;
+        let flag = |bit: u32| flags & (1 << bit) != 0;
+        let (cf, pf, zf, sf, of) = (flag(0), flag(2), flag(6), flag(7), flag(11));
+
+        match self {
+            O => of,
+            NO => !of,
+            B => cf,
+            AE => !cf,
+            E => zf,
+            NE => !zf,
+            BE => cf || zf,
+            A => !cf && !zf,
+            S => sf,
+            NS => !sf,
+            P => pf,
+            NP => !pf,
+            L => sf != of,
+            GE => sf == of,
+            LE => zf || sf != of,
+            G => !zf && sf == of,
+        }
+    }
+}
+
+/// Find the addresses of the first instruction of each basic block in a function, whose
+/// code starts at `base`. A call also ends a block, since the callee may not return (ie,
+/// panic). Returns the leaders in address order.
+pub fn block_leaders(code: &[u8], base: u64, bitness: u32) -> Vec<u64> {
+    let end = base + code.len() as u64;
+    let mut leaders = vec![base];
+
+    let mut decoder = Decoder::new(bitness, code, DecoderOptions::NONE);
+    decoder.set_ip(base);
+    let mut instr = Instruction::default();
+
+    while decoder.can_decode() {
+        decoder.decode_out(&mut instr);
+
+        match instr.flow_control() {
+            FlowControl::Next => continue,
+            FlowControl::UnconditionalBranch | FlowControl::ConditionalBranch => {
+                let target = instr.near_branch_target();
+                if base <= target && target < end {
+                    leaders.push(target);
+                }
+            }
+            _ => {}
+        }
+
+        if instr.next_ip() < end {
+            leaders.push(instr.next_ip());
+        }
+    }
+
+    leaders.sort();
+    leaders.dedup();
+    leaders
+}
+
+/// Find the conditional branches in a function, whose code starts at `base`. Branches
+/// whose target is the next instruction are ignored, since there's no way to distinguish
+/// the arms. Returns branches in address order.
+///
+/// An arm's successor is exclusive if it's in the function, nothing else in the function
+/// jumps to it, and (for the taken arm) the preceding instruction doesn't fall through into
+/// it. Otherwise it's a join point, or another function's code as for a conditional tail
+/// call, and reaching it says nothing about which way the branch went. Indirect jumps
+/// aren't accounted for.
+pub fn cond_branches(code: &[u8], base: u64, bitness: u32) -> Vec<CondBranch> {
+    let end = base + code.len() as u64;
+    let inside = |addr: u64| addr >= base && addr < end;
+    let mut decoder = Decoder::new(bitness, code, DecoderOptions::NONE);
+    decoder.set_ip(base);
+
+    let mut instrs = Vec::new();
+    // Number of direct branches to each address
+    let mut targets: HashMap<u64, usize> = HashMap::new();
+    // Addresses reached by falling through from the previous instruction
+    let mut fallthrough = HashSet::new();
+
+    while decoder.can_decode() {
+        let instr = decoder.decode();
+
+        match instr.flow_control() {
+            FlowControl::UnconditionalBranch | FlowControl::ConditionalBranch => {
+                *targets.entry(instr.near_branch_target()).or_insert(0) += 1
+            }
+            _ => {}
+        }
+        match instr.flow_control() {
+            FlowControl::UnconditionalBranch
+            | FlowControl::IndirectBranch
+            | FlowControl::Return
+            | FlowControl::Exception => {}
+            _ => {
+                let _ = fallthrough.insert(instr.next_ip());
+            }
+        }
+
+        instrs.push(instr);
+    }
+
+    // Branches on other conditions (jrcxz, loop) are skipped, since their arms can only be
+    // told apart by their successors, which needn't be exclusive to them
+    instrs
+        .iter()
+        .filter(|instr| {
+            instr.flow_control() == FlowControl::ConditionalBranch
+                && instr.near_branch_target() != instr.next_ip()
+        })
+        .filter_map(|instr| {
+            let taken = instr.near_branch_target();
+            let not_taken = instr.next_ip();
+
+            Some(CondBranch {
+                addr: instr.ip(),
+                taken,
+                not_taken,
+                cond: Cond::from_iced(instr.condition_code())?,
+                exclusive: [
+                    inside(taken)
+                        && targets.get(&taken) == Some(&1)
+                        && !fallthrough.contains(&taken),
+                    inside(not_taken) && !targets.contains_key(&not_taken),
+                ],
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn straight_line() {
+        // mov eax, 1; add eax, 2; nop
+        let code = [0xb8, 0x01, 0x00, 0x00, 0x00, 0x83, 0xc0, 0x02, 0x90];
+
+        assert_eq!(block_leaders(&code, 0x1000, 64), vec![0x1000]);
+    }
+
+    #[test]
+    fn conditional() {
+        // 0x1000: test edi, edi
+        // 0x1002: je 0x1009
+        // 0x1004: mov eax, 1
+        // 0x1009: ret
+        let code = [0x85, 0xff, 0x74, 0x05, 0xb8, 0x01, 0x00, 0x00, 0x00, 0xc3];
+
+        assert_eq!(
+            block_leaders(&code, 0x1000, 64),
+            vec![0x1000, 0x1004, 0x1009]
+        );
+    }
+
+    #[test]
+    fn branches() {
+        // 0x1000: test edi, edi
+        // 0x1002: je 0x1009
+        // 0x1004: mov eax, 1
+        // 0x1009: jne 0x100b
+        // 0x100b: ret
+        let code = [
+            0x85, 0xff, 0x74, 0x05, 0xb8, 0x01, 0x00, 0x00, 0x00, 0x75, 0x00, 0xc3,
+        ];
+
+        assert_eq!(
+            cond_branches(&code, 0x1000, 64),
+            vec![CondBranch {
+                addr: 0x1002,
+                taken: 0x1009,
+                not_taken: 0x1004,
+                cond: Cond::E,
+                exclusive: [false, true],
+            }]
+        );
+    }
+
+    #[test]
+    fn flagless_branches() {
+        // 0x1000: jrcxz 0x1006
+        // 0x1002: loop 0x1000
+        // 0x1004: xor eax, eax
+        // 0x1006: ret
+        let code = [0xe3, 0x04, 0xe2, 0xfc, 0x31, 0xc0, 0xc3];
+
+        assert_eq!(cond_branches(&code, 0x1000, 64), vec![]);
+    }
+
+    #[test]
+    fn exclusive_arms() {
+        // 0x1000: test edi, edi
+        // 0x1002: je 0x100a
+        // 0x1004: mov eax, 1
+        // 0x1009: ret
+        // 0x100a: xor eax, eax
+        // 0x100c: ret
+        let code = [
+            0x85, 0xff, 0x74, 0x06, 0xb8, 0x01, 0x00, 0x00, 0x00, 0xc3, 0x31, 0xc0, 0xc3,
+        ];
+
+        assert_eq!(
+            cond_branches(&code, 0x1000, 64),
+            vec![CondBranch {
+                addr: 0x1002,
+                taken: 0x100a,
+                not_taken: 0x1004,
+                cond: Cond::E,
+                exclusive: [true, true],
+            }]
+        );
+    }
+
+    #[test]
+    fn outside_targets() {
+        // A conditional tail call
+        // 0x1000: test edi, edi
+        // 0x1002: je 0x2000
+        // 0x1008: ret
+        let code = [0x85, 0xff, 0x0f, 0x84, 0xf8, 0x0f, 0x00, 0x00, 0xc3];
+
+        assert_eq!(
+            cond_branches(&code, 0x1000, 64),
+            vec![CondBranch {
+                addr: 0x1002,
+                taken: 0x2000,
+                not_taken: 0x1008,
+                cond: Cond::E,
+                exclusive: [false, true],
+            }]
+        );
+
+        // Falling off the end of the function
+        // 0x1000: test edi, edi
+        // 0x1002: je 0x1000
+        let code = [0x85, 0xff, 0x74, 0xfc];
+
+        assert_eq!(
+            cond_branches(&code, 0x1000, 64),
+            vec![CondBranch {
+                addr: 0x1002,
+                taken: 0x1000,
+                not_taken: 0x1004,
+                cond: Cond::E,
+                exclusive: [true, false],
+            }]
+        );
+    }
+
+    #[test]
+    fn eval_cond() {
+        const CF: u64 = 1 << 0;
+        const ZF: u64 = 1 << 6;
+        const SF: u64 = 1 << 7;
+        const OF: u64 = 1 << 11;
+
+        assert!(Cond::E.eval(ZF));
+        assert!(!Cond::E.eval(0));
+        assert!(Cond::BE.eval(CF));
+        assert!(!Cond::A.eval(CF));
+        assert!(Cond::L.eval(SF));
+        assert!(!Cond::L.eval(SF | OF));
+        assert!(Cond::G.eval(SF | OF));
+        assert!(!Cond::G.eval(ZF));
+    }
+
+    #[test]
+    fn call_ends_block() {
+        // 0x1000: call 0x2000
+        // 0x1005: ret
+        let code = [0xe8, 0xfb, 0x0f, 0x00, 0x00, 0xc3];
+
+        assert_eq!(block_leaders(&code, 0x1000, 64), vec![0x1000, 0x1005]);
+    }
+}
@@ -0,0 +1,148 @@
+//! Exclusion of source lines marked with `LCOV_EXCL_LINE`, or a region between
+//! `LCOV_EXCL_START` and `LCOV_EXCL_STOP`.
+
+use once_cell::sync::OnceCell;
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    sync::{Arc, Mutex},
+};
+
+use crate::srcloc::SrcPath;
+
+/// Marker recognized in addition to any custom ones
+const LCOV_MARKER: &str = "LCOV_EXCL";
+
+/// Excluded lines of each source file, read on first use.
+#[derive(Debug)]
+pub struct Exclusions {
+    /// Marker prefixes, each of which has `_LINE`, `_START` and `_STOP` variants
+    markers: Vec<String>,
+    /// Each file's lines, in a cell of its own so the lock is only held to find it
+    files: Mutex<HashMap<SrcPath, Arc<OnceCell<Arc<HashSet<u32>>>>>>,
+}
+
+impl Exclusions {
+    pub fn new<S: AsRef<str>>(custom: &[S]) -> Self {
+        let markers = std::iter::once(LCOV_MARKER)
+            .chain(custom.iter().map(AsRef::as_ref))
+            .map(String::from)
+            .collect();
+
+        Exclusions {
+            markers,
+            files: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Return the excluded lines of a source file. Files which can't be read have none. Each
+    /// file is read once, without holding the lock, so threads only wait for each other
+    /// when they want the same file.
+    pub fn lines(&self, srcpath: SrcPath) -> Arc<HashSet<u32>> {
+        let cell = Arc::clone(self.files.lock().unwrap().entry(srcpath).or_default());
+
+        Arc::clone(cell.get_or_init(|| {
+            let lines = fs::read(srcpath.to_pathbuf())
+                .map(|text| excluded_lines(&String::from_utf8_lossy(&text), &self.markers))
+                .unwrap_or_default();
+            Arc::new(lines)
+        }))
+    }
+
+    /// Return true if a source line is excluded
+    pub fn excluded(&self, srcpath: SrcPath, line: u32) -> bool {
+        self.lines(srcpath).contains(&line)
+    }
+}
+
+/// Find the lines of `text` (numbered from 1) which are excluded by any of `markers`. An
+/// unterminated region runs to the end of the file.
+fn excluded_lines(text: &str, markers: &[String]) -> HashSet<u32> {
+    let suffixed = |suffix: &str| -> Vec<String> {
+        markers
+            .iter()
+            .map(|marker| format!("{}_{}", marker, suffix))
+            .collect()
+    };
+    let (line_markers, starts, stops) = (suffixed("LINE"), suffixed("START"), suffixed("STOP"));
+    let contains = |line: &str, markers: &[String]| markers.iter().any(|m| line.contains(m));
+
+    let mut excluded = HashSet::new();
+    let mut in_region = false;
+
+    for (lineno, line) in (1..).zip(text.lines()) {
+        if contains(line, &starts) {
+            in_region = true;
+        }
+        if in_region || contains(line, &line_markers) {
+            let _ = excluded.insert(lineno);
+        }
+        if contains(line, &stops) {
+            let _ = excluded.insert(lineno);
+            in_region = false;
+        }
+    }
+
+    excluded
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn lines(text: &str, custom: &[&str]) -> Vec<u32> {
+        let markers: Vec<_> = std::iter::once(LCOV_MARKER)
+            .chain(custom.iter().cloned())
+            .map(String::from)
+            .collect();
+        let mut lines: Vec<_> = excluded_lines(text, &markers).into_iter().collect();
+        lines.sort();
+        lines
+    }
+
+    #[test]
+    fn markers() {
+        let text = "fn main() {\n\
+                    \x20   unreachable!(); // LCOV_EXCL_LINE\n\
+                    \x20   // LCOV_EXCL_START\n\
+                    \x20   defensive();\n\
+                    \x20   // LCOV_EXCL_STOP\n\
+                    \x20   real();\n\
+                    }\n";
+
+        assert_eq!(lines(text, &[]), vec![2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn custom_marker() {
+        let text = "a // NOCOV_LINE\nb\n// NOCOV_START\nc\n";
+
+        assert_eq!(lines(text, &[]), Vec::<u32>::new());
+        assert_eq!(lines(text, &["NOCOV"]), vec![1, 3, 4]);
+    }
+
+    #[test]
+    fn shared() {
+        use rayon::prelude::*;
+
+        let dir = tempfile::tempdir().unwrap();
+        let paths: Vec<_> = (0..4)
+            .map(|idx| {
+                let path = dir.path().join(format!("{}.c", idx));
+                fs::write(&path, format!("{}\nx(); // LCOV_EXCL_LINE\n", idx)).unwrap();
+                SrcPath::from_path(&path)
+            })
+            .collect();
+        let exclusions = Exclusions::new::<&str>(&[]);
+
+        // Every thread asking for a file gets the same lines
+        let found: Vec<_> = (0..64)
+            .into_par_iter()
+            .map(|idx| exclusions.lines(paths[idx % paths.len()]))
+            .collect();
+        for (idx, lines) in found.iter().enumerate() {
+            assert_eq!(**lines, [2].iter().copied().collect());
+            assert!(Arc::ptr_eq(lines, &found[idx % paths.len()]));
+        }
+    }
+}
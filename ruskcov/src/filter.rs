@@ -0,0 +1,482 @@
+//! Selection of interesting source files and functions, and where to find them
+
+use anyhow::{Context, Error};
+use glob::Pattern;
+use regex::RegexSet;
+use std::{
+    env,
+    path::{Path, PathBuf},
+};
+use structopt::StructOpt;
+
+use crate::srcloc::{PathMap, SrcPath};
+
+#[derive(StructOpt, Debug, Clone, Default)]
+#[structopt(rename_all = "kebab-case")]
+pub struct FilterArgs {
+    /// Include sources in directories matching this REGEX
+    #[structopt(long, number_of_values(1))]
+    include_dir: Vec<String>,
+    /// Exclude sources in directories matching this REGEX
+    #[structopt(long, number_of_values(1))]
+    exclude_dir: Vec<String>,
+    /// Include source files whose full path matches this REGEX
+    #[structopt(long, number_of_values(1))]
+    include_file: Vec<String>,
+    /// Exclude source files whose full path matches this REGEX
+    #[structopt(long, number_of_values(1))]
+    exclude_file: Vec<String>,
+    /// Include source files matching this GLOB. A GLOB without a `/` matches the file name.
+    #[structopt(long, number_of_values(1))]
+    include_glob: Vec<String>,
+    /// Exclude source files matching this GLOB. A GLOB without a `/` matches the file name.
+    #[structopt(long, number_of_values(1))]
+    exclude_glob: Vec<String>,
+    /// Include source files under these comma-separated PATHS
+    #[structopt(long, number_of_values(1))]
+    include_path: Vec<String>,
+    /// Exclude source files under these comma-separated PATHS
+    #[structopt(long, number_of_values(1))]
+    exclude_path: Vec<String>,
+    /// Include functions whose demangled name matches this REGEX
+    #[structopt(long, number_of_values(1))]
+    include_function: Vec<String>,
+    /// Exclude functions whose demangled name matches this REGEX
+    #[structopt(long, number_of_values(1))]
+    exclude_function: Vec<String>,
+    /// Rewrite source paths starting with FROM to start with TO instead, for sources built
+    /// somewhere other than where they are now. The last matching map applies.
+    #[structopt(long, alias = "remap-path-prefix", number_of_values(1))]
+    path_map: Vec<PathMap>,
+    /// Resolve symlinks in source paths, so files reached through different links are
+    /// reported once
+    #[structopt(long)]
+    canonicalize_paths: bool,
+}
+
+/// Filter for interesting source files and functions.
+///
+/// A source file's full path is checked against these rules in order, and the first which
+/// matches decides:
+///
+/// 1. file includes (`--include-file`, `--include-glob` and `--include-path`)
+/// 2. file excludes (`--exclude-file`, `--exclude-glob` and `--exclude-path`)
+/// 3. directory includes (`--include-dir`), matched against the file's directory
+/// 4. directory excludes (`--exclude-dir`)
+///
+/// That is, more specific rules take precedence over less specific ones, and includes take
+/// precedence over excludes. If none match, the file is interesting only if there are no
+/// includes at all: giving any include means "only these".
+///
+/// Functions are filtered by name in the same way, with include taking precedence over
+/// exclude, and any include meaning only those functions are interesting.
+///
+/// Source paths are remapped before any of these rules apply, so they're matched against
+/// where the files are on this host.
+#[derive(Clone, Debug)]
+pub struct Filter {
+    /// Include all directories matching this set
+    dir_include: RegexSet,
+    /// Exclude all directories matching this set
+    dir_exclude: RegexSet,
+    /// Include all files matching this set
+    file_include: PathSet,
+    /// Exclude all files matching this set
+    file_exclude: PathSet,
+    /// Include all functions whose name matches this set
+    function_include: RegexSet,
+    /// Exclude all functions whose name matches this set
+    function_exclude: RegexSet,
+    /// Source path rewrites
+    path_map: Vec<PathMap>,
+    /// Whether to resolve symlinks in source paths
+    canonicalize_paths: bool,
+}
+
+impl Filter {
+    pub fn new(args: &FilterArgs) -> Result<Self, Error> {
+        Ok(Filter {
+            dir_include: RegexSet::new(&args.include_dir)?,
+            dir_exclude: RegexSet::new(&args.exclude_dir)?,
+            file_include: PathSet::new(
+                &args.include_file,
+                &args.include_glob,
+                &args.include_path,
+                args.canonicalize_paths,
+            )?,
+            file_exclude: PathSet::new(
+                &args.exclude_file,
+                &args.exclude_glob,
+                &args.exclude_path,
+                args.canonicalize_paths,
+            )?,
+            function_include: RegexSet::new(&args.include_function)?,
+            function_exclude: RegexSet::new(&args.exclude_function)?,
+            path_map: args.path_map.clone(),
+            canonicalize_paths: args.canonicalize_paths,
+        })
+    }
+
+    /// Make the `SrcPath` for a file named in the debug info, with any path map applied and
+    /// symlinks resolved if asked
+    pub fn srcpath(&self, path: &Path) -> SrcPath {
+        let path = self
+            .path_map
+            .iter()
+            .rev()
+            .find_map(|map| map.apply(path))
+            .unwrap_or_else(|| path.to_path_buf());
+
+        let srcpath = SrcPath::from_path(&path);
+        if self.canonicalize_paths {
+            srcpath.canonical()
+        } else {
+            srcpath
+        }
+    }
+
+    /// Return true if the source file is interesting
+    pub fn allows(&self, srcpath: &SrcPath) -> bool {
+        let path = srcpath.to_pathbuf();
+        let dir = path.parent().unwrap_or(Path::new("."));
+        let dir = dir.to_string_lossy();
+
+        if self.file_include.is_match(&path) {
+            true
+        } else if self.file_exclude.is_match(&path) {
+            false
+        } else if self.dir_include.is_match(&dir) {
+            true
+        } else if self.dir_exclude.is_match(&dir) {
+            false
+        } else {
+            self.file_include.is_empty() && self.dir_include.is_empty()
+        }
+    }
+
+    /// Return true if code in the function with this demangled name is interesting
+    pub fn allow_function(&self, name: &str) -> bool {
+        if self.function_include.is_match(name) {
+            true
+        } else if self.function_exclude.is_match(name) {
+            false
+        } else {
+            self.function_include.is_empty()
+        }
+    }
+
+    /// Return true if there are any function filters
+    pub fn filters_functions(&self) -> bool {
+        !self.function_include.is_empty() || !self.function_exclude.is_empty()
+    }
+
+    /// Describe the filter for keying cached breakpoints. This is the filter as resolved,
+    /// with absolute path prefixes, so the same arguments given from different directories
+    /// don't share cache entries.
+    pub fn cache_key(&self) -> String {
+        format!(
+            "dirs {:?} {:?} files {} {} functions {:?} {:?} maps {:?} canonicalize {}",
+            self.dir_include.patterns(),
+            self.dir_exclude.patterns(),
+            self.file_include.cache_key(),
+            self.file_exclude.cache_key(),
+            self.function_include.patterns(),
+            self.function_exclude.patterns(),
+            self.path_map,
+            self.canonicalize_paths
+        )
+    }
+}
+
+/// Patterns matched against a source file's full path
+#[derive(Clone, Debug)]
+struct PathSet {
+    regex: RegexSet,
+    /// Globs, and whether they only match the file name
+    globs: Vec<(Pattern, bool)>,
+    /// Path prefixes, matched by component
+    prefixes: Vec<PathBuf>,
+}
+
+impl PathSet {
+    /// Make a set from the patterns given on the command line. Path prefixes are made absolute
+    /// and normalized like source paths, with symlinks only resolved if `canonicalize` is set.
+    fn new(
+        regex: &[String],
+        globs: &[String],
+        prefixes: &[String],
+        canonicalize: bool,
+    ) -> Result<Self, Error> {
+        let globs = globs
+            .iter()
+            .map(|glob| {
+                Pattern::new(glob)
+                    .with_context(|| format!("Bad glob {}", glob))
+                    .map(|pat| (pat, !glob.contains('/')))
+            })
+            .collect::<Result<_, _>>()?;
+        let cwd = env::current_dir().context("Getting current directory")?;
+        let prefixes = prefixes
+            .iter()
+            .flat_map(|paths| paths.split(','))
+            .filter(|path| !path.is_empty())
+            .map(|path| {
+                let path = cwd.join(path);
+                let srcpath = SrcPath::from_path(&path);
+                if canonicalize {
+                    srcpath.canonical().to_pathbuf()
+                } else {
+                    srcpath.to_pathbuf()
+                }
+            })
+            .collect();
+
+        Ok(PathSet {
+            regex: RegexSet::new(regex)?,
+            globs,
+            prefixes,
+        })
+    }
+
+    fn is_empty(&self) -> bool {
+        self.regex.is_empty() && self.globs.is_empty() && self.prefixes.is_empty()
+    }
+
+    fn cache_key(&self) -> String {
+        let globs: Vec<_> = self.globs.iter().map(|(glob, _)| glob.as_str()).collect();
+        format!(
+            "{:?} {:?} {:?}",
+            self.regex.patterns(),
+            globs,
+            self.prefixes
+        )
+    }
+
+    fn is_match(&self, path: &Path) -> bool {
+        self.regex.is_match(&path.to_string_lossy())
+            || self.globs.iter().any(|(glob, name_only)| {
+                if *name_only {
+                    path.file_name()
+                        .map_or(false, |name| glob.matches(&name.to_string_lossy()))
+                } else {
+                    glob.matches_path(path)
+                }
+            })
+            || self.prefixes.iter().any(|prefix| path.starts_with(prefix))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+
+    fn strs(v: &[&str]) -> Vec<String> {
+        v.iter().map(|s| s.to_string()).collect()
+    }
+
+    fn lib() -> SrcPath {
+        SrcPath::new("/src/foo", "lib.rs")
+    }
+
+    /// Whether `lib()` is allowed with the given directory and file filters
+    fn allows(
+        include_dir: &[&str],
+        exclude_dir: &[&str],
+        include: &[&str],
+        exclude: &[&str],
+    ) -> bool {
+        Filter::new(&FilterArgs {
+            include_dir: strs(include_dir),
+            exclude_dir: strs(exclude_dir),
+            include_file: strs(include),
+            exclude_file: strs(exclude),
+            ..Default::default()
+        })
+        .unwrap()
+        .allows(&lib())
+    }
+
+    #[test]
+    fn default_allows() {
+        assert!(allows(&[], &[], &[], &[]));
+    }
+
+    #[test]
+    fn dir_truth_table() {
+        // (include matches, exclude matches) for (given and matching, given and not, not given)
+        assert!(allows(&["foo"], &[], &[], &[]));
+        assert!(!allows(&["bar"], &[], &[], &[]));
+        assert!(!allows(&[], &["foo"], &[], &[]));
+        assert!(allows(&[], &["bar"], &[], &[]));
+        assert!(allows(&["foo"], &["foo"], &[], &[]));
+        assert!(allows(&["foo"], &["bar"], &[], &[]));
+        assert!(!allows(&["bar"], &["foo"], &[], &[]));
+        assert!(!allows(&["bar"], &["bar"], &[], &[]));
+    }
+
+    #[test]
+    fn file_truth_table() {
+        assert!(allows(&[], &[], &["lib"], &[]));
+        assert!(!allows(&[], &[], &["main"], &[]));
+        assert!(!allows(&[], &[], &[], &["lib"]));
+        assert!(allows(&[], &[], &[], &["main"]));
+        assert!(allows(&[], &[], &["lib"], &["lib"]));
+        assert!(!allows(&[], &[], &["main"], &["lib"]));
+    }
+
+    #[test]
+    fn file_over_dir() {
+        // File rules are more specific than directory rules
+        assert!(allows(&[], &["foo"], &["lib"], &[]));
+        assert!(!allows(&["foo"], &[], &[], &["lib"]));
+        // Any include is an allowlist, so a file include excludes other directories
+        assert!(!allows(&[], &[], &["main"], &[]));
+        assert!(allows(&["foo"], &[], &["main"], &[]));
+    }
+
+    #[test]
+    fn globs_and_prefixes() {
+        let filter = |args: FilterArgs| Filter::new(&args).unwrap().allows(&lib());
+
+        assert!(!filter(FilterArgs {
+            exclude_glob: strs(&["*.rs"]),
+            ..Default::default()
+        }));
+        assert!(!filter(FilterArgs {
+            exclude_glob: strs(&["*/foo/*.rs"]),
+            ..Default::default()
+        }));
+        assert!(filter(FilterArgs {
+            exclude_glob: strs(&["*/bar/*.rs"]),
+            ..Default::default()
+        }));
+        assert!(!filter(FilterArgs {
+            exclude_path: strs(&["/other,/src"]),
+            ..Default::default()
+        }));
+        assert!(filter(FilterArgs {
+            include_path: strs(&["/other,/src"]),
+            ..Default::default()
+        }));
+        assert!(filter(FilterArgs {
+            include_path: strs(&["/src"]),
+            exclude_glob: strs(&["*.rs"]),
+            ..Default::default()
+        }));
+        // Prefixes match whole components
+        assert!(filter(FilterArgs {
+            exclude_path: strs(&["/src/fo"]),
+            ..Default::default()
+        }));
+        assert!(!filter(FilterArgs {
+            include_path: strs(&["/src/fo"]),
+            ..Default::default()
+        }));
+    }
+
+    #[test]
+    fn prefix_symlinks() {
+        let dir = tempfile::tempdir().unwrap();
+        let real = dir.path().join("real");
+        let link = dir.path().join("link");
+        fs::create_dir(&real).unwrap();
+        fs::write(real.join("lib.rs"), "").unwrap();
+        std::os::unix::fs::symlink(&real, &link).unwrap();
+
+        // Prefixes are only resolved when source paths are
+        for &canonicalize_paths in &[false, true] {
+            let filter = Filter::new(&FilterArgs {
+                include_path: vec![link.display().to_string()],
+                canonicalize_paths,
+                ..Default::default()
+            })
+            .unwrap();
+            let srcpath = filter.srcpath(&link.join("lib.rs"));
+            assert!(filter.allows(&srcpath), "{}", canonicalize_paths);
+        }
+    }
+
+    #[test]
+    fn cache_key() {
+        let key = |args: FilterArgs| Filter::new(&args).unwrap().cache_key();
+
+        // Keyed on the resolved prefixes, not how they were written
+        assert_eq!(
+            key(FilterArgs {
+                include_path: strs(&["/src/./foo,"]),
+                ..Default::default()
+            }),
+            key(FilterArgs {
+                include_path: strs(&["/src/foo"]),
+                ..Default::default()
+            })
+        );
+        assert_ne!(
+            key(FilterArgs {
+                include_path: strs(&["foo"]),
+                ..Default::default()
+            }),
+            key(FilterArgs {
+                include_path: strs(&["/foo"]),
+                ..Default::default()
+            })
+        );
+        assert_ne!(
+            key(FilterArgs {
+                include_path: strs(&["/foo"]),
+                ..Default::default()
+            }),
+            key(FilterArgs {
+                exclude_path: strs(&["/foo"]),
+                ..Default::default()
+            })
+        );
+        assert_ne!(
+            key(Default::default()),
+            key(FilterArgs {
+                canonicalize_paths: true,
+                ..Default::default()
+            })
+        );
+    }
+
+    #[test]
+    fn functions() {
+        let filter = |include: &[&str], exclude: &[&str]| {
+            Filter::new(&FilterArgs {
+                include_function: strs(include),
+                exclude_function: strs(exclude),
+                ..Default::default()
+            })
+            .unwrap()
+        };
+
+        assert!(filter(&[], &[]).allow_function("core::fmt::write"));
+        assert!(!filter(&[], &["^core::fmt"]).allow_function("core::fmt::write"));
+        assert!(!filter(&["^mycrate::"], &[]).allow_function("core::fmt::write"));
+        assert!(filter(&["^mycrate::"], &["::tests::"]).allow_function("mycrate::tests::foo"));
+        assert!(!filter(&["^mycrate::"], &[]).allow_function("other::foo"));
+    }
+
+    #[test]
+    fn path_map() {
+        let filter = Filter::new(&FilterArgs {
+            path_map: vec![
+                "/build=/src".parse().unwrap(),
+                "/build/xyz=/src/foo".parse().unwrap(),
+            ],
+            ..Default::default()
+        })
+        .unwrap();
+
+        let srcpath = |path: &str| filter.srcpath(Path::new(path));
+
+        assert_eq!(srcpath("/build/xyz/lib.rs"), lib());
+        assert_eq!(
+            srcpath("/build/abc/lib.rs"),
+            SrcPath::new("/src/abc", "lib.rs")
+        );
+        assert_eq!(srcpath("/other/lib.rs"), SrcPath::new("/other", "lib.rs"));
+    }
+}
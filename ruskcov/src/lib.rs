@@ -0,0 +1,18 @@
+//! Everything but the tracer itself, so it can be benchmarked.
+
+pub mod bptable;
+pub mod breakpoints;
+pub mod cache;
+pub mod debuginfo;
+pub mod debuginfod;
+pub mod disasm;
+pub mod error;
+pub mod exclusion;
+pub mod filter;
+pub mod mapped_slice;
+pub mod placement;
+pub mod process;
+pub mod report;
+pub mod split_dwarf;
+pub mod srcloc;
+pub mod symtab;
@@ -1,49 +1,54 @@
-use anyhow::{Context, Error};
-use gimli::read::Reader;
-use inject_types::{BreakpointInst, ObjectInfo, SetBreakpointsReq, SetBreakpointsResp, SOCKET_ENV};
+use anyhow::{anyhow, Context, Error};
+use inject_types::{
+    BreakpointInst, FollowExec, ObjectInfo, SetBreakpointsReq, SetBreakpointsResp, BREAKPOINT,
+    FOLLOW_EXEC_ENV, INJECT_LIBRARY_VAR, PRIMARY_ENV, SOCKET_ENV,
+};
 use nix::{
     sys::{signal, wait},
     unistd::Pid,
 };
-use object::read::Object;
-use regex::RegexSet;
+use object::Object;
+use rayon::prelude::*;
 use std::{
-    borrow::Borrow,
-    collections::HashSet,
-    ffi::OsStr,
-    fs::File,
+    collections::{HashMap, HashSet},
+    convert::TryInto,
+    fs::{self, File},
     io::{self, BufReader, BufWriter, Write},
-    iter,
-    ops::{Deref, Index, Range},
-    os::unix::{ffi::OsStrExt, net::UnixListener, process::CommandExt},
-    path::{Component, Path, PathBuf},
+    os::unix::{
+        fs::FileExt,
+        net::{UnixListener, UnixStream},
+        process::CommandExt,
+    },
+    path::{Path, PathBuf},
     process::{Child, Command},
-    sync::{Arc, Mutex},
+    sync::{mpsc, Arc, Mutex},
     thread,
+    time::Duration,
 };
 use structopt::StructOpt;
 
-mod error;
-mod mapped_slice;
-mod process;
-mod srcloc;
-mod symtab;
-
-use srcloc::{Location, SrcPath};
-
 #[cfg_attr(
     any(target_arch = "x86", target_arch = "x86_64"),
     path = "ptrace_x86.rs"
 )]
 mod ptrace;
 
-use error::ObjectError;
-use mapped_slice::MappedSlice;
+use ruskcov::{
+    breakpoints::{get_breakpoints, BreakpointConfig, Breakpoints, Inline},
+    cache::BreakpointCache,
+    debuginfo::DebugSearch,
+    debuginfod::Debuginfod,
+    error::ObjectError,
+    exclusion::Exclusions,
+    filter::{Filter, FilterArgs},
+    mapped_slice::MappedSlice,
+    placement::Placement,
+    process::{Process, ProcessState},
+    report::{Coverage, Totals},
+};
 
-#[cfg(target_os = "macos")]
-const INJECT_LIBRARY_VAR: &str = "DYLD_INSERT_LIBRARIES"; // XXX may not be enough to interpose dlopen
-#[cfg(all(unix, not(target_os = "macos")))]
-const INJECT_LIBRARY_VAR: &str = "LD_PRELOAD";
+/// Maximum number of breakpoints to send to the inject library at once
+const BREAKPOINT_BATCH: usize = 4096;
 
 #[derive(StructOpt, Debug, Clone)]
 #[structopt(rename_all = "kebab-case")]
@@ -51,12 +56,57 @@ struct Args {
     /// Path to libruskcov_inject.so (TODO: build in)
     #[structopt(long, default_value = "libruskcov_inject.so", number_of_values(1))]
     inject: Vec<PathBuf>,
-    /// Include sources in directories matching this REGEX
+    #[structopt(flatten)]
+    filter: FilterArgs,
+    /// Also exclude lines marked with MARKER_LINE, or between MARKER_START and MARKER_STOP,
+    /// as well as the LCOV_EXCL ones
     #[structopt(long, number_of_values(1))]
-    include_dir: Vec<String>,
-    /// Include sources in directories matching this REGEX
+    exclusion_marker: Vec<String>,
+    /// Breakpoint placement strategy
+    #[structopt(long, default_value = "all", possible_values = Placement::VARIANTS)]
+    placement: Placement,
+    /// How to attribute code inlined from one function into another
+    #[structopt(long, default_value = "all", possible_values = Inline::VARIANTS)]
+    inline: Inline,
+    /// Don't trace programs exec'd by the traced program
+    #[structopt(long, conflicts_with = "follow-exec-filter")]
+    no_follow_exec: bool,
+    /// Only trace exec'd programs whose path matches this REGEX
+    #[structopt(long)]
+    follow_exec_filter: Option<String>,
+    /// Record branch coverage (x86 only)
+    #[structopt(long)]
+    branches: bool,
+    /// Only set breakpoints on function entries at first, and the rest of each function's
+    /// once it's entered. This writes to fewer pages of code, so starts faster when most of
+    /// a large program never runs.
+    #[structopt(long)]
+    lazy: bool,
+    /// List functions which were never called, by crate
+    #[structopt(long)]
+    functions: bool,
+    /// Write an LCOV tracefile to FILE
+    #[structopt(long)]
+    lcov: Option<PathBuf>,
+    /// Write a Cobertura XML report to FILE
+    #[structopt(long)]
+    cobertura: Option<PathBuf>,
+    /// Search DIR for separate debug info files, by build-id or debuglink. These are
+    /// searched before the directories in RUSKCOV_DEBUG_PATH and /usr/lib/debug.
     #[structopt(long, number_of_values(1))]
-    exclude_dir: Vec<String>,
+    debug_dir: Vec<PathBuf>,
+    /// Only use debug info already in the debuginfod cache, rather than fetching it from the
+    /// servers in DEBUGINFOD_URLS. Only http:// servers are supported, and https:// ones are
+    /// skipped.
+    #[structopt(long)]
+    debuginfod_offline: bool,
+    /// Time limit in seconds for each debuginfod request, overriding DEBUGINFOD_TIMEOUT
+    #[structopt(long)]
+    debuginfod_timeout: Option<u64>,
+    /// Don't use or update the cache of each object's breakpoints in
+    /// $XDG_CACHE_HOME/ruskcov
+    #[structopt(long)]
+    no_cache: bool,
     /// Print verbose debug gunk
     #[structopt(long)]
     debug: bool,
@@ -64,227 +114,579 @@ struct Args {
     args: Vec<String>,
 }
 
-/// Filter for interesting source files. By default, all files are
-/// considered interesting, and then the include and exclude filters are applied. Include
-/// takes precidence over exclude.
-#[derive(Clone, Debug)]
-struct Filter {
-    /// Include all directories matching this set
-    dir_include: RegexSet,
-    /// Exclude all directories matching this set (include takes precidence)
-    dir_exclude: RegexSet,
+/// Result of a tracee trapping on a breakpoint instruction
+#[derive(Debug, Clone, Copy)]
+enum Trap {
+    /// Hit one of our breakpoints, which had this original instruction
+    Breakpoint(BreakpointInst),
+    /// Hit a conditional branch breakpoint which is staying in place; continue at this
+    /// address
+    Jump(u64),
+    /// Hit one of our breakpoints which another thread has already removed
+    Removed,
+    /// Not one of ours
+    Unknown,
 }
 
 struct State {
     primary: Child,
-    tracees: HashSet<u32>,
+    /// All traced threads and processes by tid
+    tracees: HashMap<Pid, Process>,
+    /// New tracees which stopped before we heard about them from their parent
+    pending: HashSet<Pid>,
+    coverage: Coverage,
+    /// Exit status of the primary
+    status: Option<i32>,
+    /// Whether each process's current image is being followed, decided when it first
+    /// connects and forgotten when it execs
+    followed: HashMap<Pid, bool>,
 }
 
 impl State {
     fn new(primary: Child) -> Self {
-        let mut tracees = HashSet::new();
-        let _ = tracees.insert(primary.id());
+        let pid = Pid::from_raw(primary.id() as i32);
+        let mut tracees = HashMap::new();
+        let _ = tracees.insert(pid, Process::new(pid));
+        // The primary's first image is always followed, but not anything it execs later
+        let mut followed = HashMap::new();
+        let _ = followed.insert(pid, true);
+
+        State {
+            primary,
+            tracees,
+            pending: HashSet::new(),
+            coverage: Coverage::default(),
+            status: None,
+            followed,
+        }
+    }
+
+    /// Add a new tracee created by `parent`. Returns true if the child had already stopped
+    /// waiting for us, and needs to be continued.
+    fn add_child(&mut self, parent: Pid, child: Pid, shared: bool) -> bool {
+        let mut process = match self.tracees.get(&parent) {
+            Some(parent) if shared => parent.new_thread(child),
+            Some(parent) => parent.fork(child),
+            None => Process::new(child),
+        };
+
+        let stopped = self.pending.remove(&child);
+        if stopped {
+            process.set_state(ProcessState::Running);
+        }
+        let _ = self.tracees.insert(child, process);
+        // The child is running the same image, so it's followed if the parent is
+        if let Some(&followed) = self.followed.get(&parent) {
+            let _ = self.followed.insert(child, followed);
+        }
 
-        State { primary, tracees }
+        stopped
     }
 
-    fn add_child(&mut self, child: &Child) {
-        let _ = self.tracees.insert(child.id());
+    fn exec(&mut self, pid: Pid) {
+        let _ = self.followed.remove(&pid);
+        if let Some(process) = self.tracees.get_mut(&pid) {
+            process.exec()
+        }
     }
-}
 
-fn load_debug(
-    path: &Path,
-    debug: bool,
-) -> Result<symtab::Context<gimli::EndianReader<gimli::RunTimeEndian, MappedSlice>>, Error> {
-    let map = {
-        let file = File::open(path).context("Failed to open object")?;
+    fn exit(&mut self, pid: Pid) {
+        let _ = self.tracees.remove(&pid);
+        let _ = self.followed.remove(&pid);
+    }
 
-        MappedSlice::new(file)?
-    };
-    let objfile = object::File::parse(&*map)
-        .map_err(ObjectError)
-        .context("object file parse failed")?;
+    /// Return whether `exe` is followed, deciding on its first connection since its
+    /// process last exec'd.
+    fn follows(&mut self, exe: &ObjectInfo, follow_exec: &FollowExec) -> bool {
+        *self
+            .followed
+            .entry(Pid::from_raw(exe.pid as i32))
+            .or_insert_with(|| follow_exec.follows(&exe.path, false))
+    }
+
+    fn process(&mut self, pid: u32) -> &mut Process {
+        let pid = Pid::from_raw(pid as i32);
+        self.tracees.entry(pid).or_insert_with(|| Process::new(pid))
+    }
 
-    let linkobj;
-    let linkmap;
+    /// Add an object to its process. Returns false if it's already been seen.
+    fn add_object(&mut self, obj: &ObjectInfo) -> bool {
+        self.process(obj.pid).addrspace().add_object(obj)
+    }
 
-    let (objfile, mapping) = if let Some((name, crc)) = objfile.gnu_debuglink() {
-        let name = Path::new(OsStr::from_bytes(name));
-        if debug {
-            println!(
-                "{} => debuglink {} {:x}",
-                path.display(),
-                name.display(),
-                crc
-            );
+    /// Add breakpoints to a process, and return the addresses which need setting. If `lazy`,
+    /// those in functions with entry breakpoints are deferred until the entry is hit.
+    fn add_breakpoints(&mut self, pid: u32, bps: &Breakpoints, lazy: bool) -> Vec<u64> {
+        let coverage = &mut self.coverage;
+        let pid = Pid::from_raw(pid as i32);
+        let process = self.tracees.entry(pid).or_insert_with(|| Process::new(pid));
+        let mut addrspace = process.addrspace();
+
+        // The first location at each address is used for its breakpoint
+        addrspace.add_breakpoints(
+            bps.lines
+                .iter()
+                .copied()
+                .chain(bps.branches.iter().map(|&(addr, loc, _)| (addr, loc)))
+                .chain(bps.functions.iter().map(|&(addr, loc, _)| (addr, loc)))
+                .chain(bps.conds.iter().map(|&(addr, loc, _)| (addr, loc))),
+        );
+        for &(_, loc) in &bps.lines {
+            coverage.add_line(&loc);
+        }
+        for &(addr, loc, arm) in &bps.branches {
+            addrspace.add_branch(addr, arm);
+            coverage.add_line(&loc);
+            coverage.add_branch(&arm);
+        }
+        addrspace.add_calls(bps.calls.iter().copied());
+        for &(_, loc) in &bps.calls {
+            coverage.add_line(&loc);
+        }
+        for &(addr, loc, func) in &bps.functions {
+            addrspace.add_function(addr, func);
+            coverage.add_line(&loc);
+            coverage.add_function(&func);
+        }
+        for &(addr, loc, cond) in &bps.conds {
+            addrspace.add_cond(addr, cond);
+            coverage.add_line(&loc);
+            cond.arms.iter().for_each(|arm| coverage.add_branch(arm));
+        }
+        if lazy {
+            for (entry, body) in bps.bodies() {
+                addrspace.defer(entry, body);
+            }
         }
 
-        let objdir = path.parent().unwrap_or(Path::new("."));
-        let relobjdir = objdir
-            .components()
-            .filter(|c| match c {
-                Component::Prefix { .. } | Component::RootDir => false,
-                _ => true,
-            })
-            .collect::<PathBuf>();
-
-        let paths = vec![
-            objdir.join(name),
-            objdir.join(".debug").join(name),
-            Path::new("/usr/lib/debug").join(&relobjdir).join(name),
-            // TODO: option for other debug dirs
-        ];
+        addrspace.unset_breakpoints()
+    }
 
-        let mut file = None;
+    fn set_replaced(&mut self, pid: u32, set: Vec<(u64, BreakpointInst)>) {
+        self.process(pid).addrspace().set_replaced(set)
+    }
 
-        for path in paths {
-            if let Ok(f) = File::open(&path) {
-                if debug {
-                    println!("Using debuglink {}", path.display());
-                }
-                file = Some(f);
-                break;
-            }
+    /// Set the breakpoints deferred until the one at `entry` was hit, while `pid` is stopped
+    fn set_deferred(&mut self, pid: Pid, entry: u64) -> Result<(), nix::Error> {
+        match self.tracees.get(&pid) {
+            Some(process) => process
+                .addrspace()
+                .set_deferred(entry, |addr| ptrace::replace_inst(pid, addr, BREAKPOINT)),
+            None => Ok(()),
         }
+    }
 
-        if let Some(file) = file {
-            linkmap = MappedSlice::new(file)?;
-            let linked_crc = crc::crc32::checksum_ieee(&*linkmap);
-            if crc == linked_crc {
-                match object::File::parse(&*linkmap) {
-                    Ok(obj) => {
-                        linkobj = obj;
-
-                        drop(objfile);
-                        drop(map);
-                        (&linkobj, &linkmap)
-                    }
-                    Err(err) => {
-                        println!("Failed to parse debuglink {:?}", err);
-                        (&objfile, &map)
-                    }
+    /// Handle a tracee trapping on a breakpoint at `addr` with the given flags register,
+    /// recording coverage if it was one of ours.
+    fn trap(&mut self, pid: Pid, addr: u64, flags: u64) -> Trap {
+        let process = match self.tracees.get(&pid) {
+            Some(process) => process,
+            None => return Trap::Unknown,
+        };
+        let mut addrspace = process.addrspace();
+
+        match addrspace.hit(addr, flags) {
+            Some(hit) => {
+                self.coverage.hit_line(&hit.loc);
+                for loc in &hit.calls {
+                    self.coverage.hit_line(loc);
+                }
+                for arm in &hit.branches {
+                    self.coverage.hit_branch(arm);
+                }
+                if let Some(func) = &hit.function {
+                    self.coverage.hit_function(func);
                 }
-            } else {
-                // CRC mismatch
-                if debug {
-                    println!(
-                        "debuglink {} crc mismatch got {:08x} wanted {:08x}",
-                        path.display(),
-                        linked_crc,
-                        crc
-                    );
+                match hit.jump {
+                    Some(target) => Trap::Jump(target),
+                    None => Trap::Breakpoint(hit.inst),
                 }
-                (&objfile, &map)
             }
-        } else {
-            // Couldn't find debuglink, use the object
-            (&objfile, &map)
+            None if addrspace.was_removed(addr) => Trap::Removed,
+            None => Trap::Unknown,
         }
-    } else {
-        // No debuglink, just use the object
-        (&objfile, &map)
+    }
+}
+
+/// Return true if `path` is one of the inject libraries
+fn is_inject(path: &Path, inject: &[PathBuf]) -> bool {
+    let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+
+    inject.iter().any(|inject| {
+        inject.canonicalize().map_or(false, |inject| inject == path)
+            || (inject.parent() == Some(Path::new(""))
+                && path.file_name() == Some(inject.as_os_str()))
+    })
+}
+
+/// Read the instructions at `addrs`, which are sorted, from the memory of process `pid`.
+/// Any which can't be read are skipped.
+fn read_insts(pid: u32, addrs: &[u64]) -> io::Result<Vec<(u64, BreakpointInst)>> {
+    const PAGE_SIZE: u64 = 4096;
+
+    let mem = File::open(format!("/proc/{}/mem", pid))?;
+    let mut insts = Vec::with_capacity(addrs.len());
+    let mut page = vec![0; PAGE_SIZE as usize];
+    let mut rest = addrs;
+    while let Some(&first) = rest.first() {
+        let start = first & !(PAGE_SIZE - 1);
+        let len = rest
+            .iter()
+            .take_while(|&&addr| addr < start + PAGE_SIZE)
+            .count();
+        let (addrs, next) = rest.split_at(len);
+        if mem.read_exact_at(&mut page, start).is_ok() {
+            insts.extend(
+                addrs
+                    .iter()
+                    .map(|&addr| (addr, BreakpointInst([page[(addr - start) as usize]]))),
+            );
+        }
+        rest = next;
+    }
+    Ok(insts)
+}
+
+/// If `pid` has just exec'd a statically linked executable, return it. The inject library
+/// can't be preloaded into those, so the tracer has to set their breakpoints itself.
+fn static_exe(pid: Pid) -> Result<Option<ObjectInfo>, Error> {
+    let auxv = fs::read(format!("/proc/{}/auxv", pid)).context("reading auxv")?;
+    let mut base = None;
+    let mut entry = None;
+    for pair in auxv.chunks_exact(16) {
+        let key = u64::from_ne_bytes(pair[..8].try_into().unwrap());
+        let value = u64::from_ne_bytes(pair[8..].try_into().unwrap());
+        match key {
+            libc::AT_BASE => base = Some(value),
+            libc::AT_ENTRY => entry = Some(value),
+            _ => {}
+        }
+    }
+    // Dynamically linked executables have an interpreter mapped at AT_BASE
+    let entry = match (base, entry) {
+        (Some(0), Some(entry)) | (None, Some(entry)) => entry,
+        _ => return Ok(None),
+    };
+
+    let path = fs::read_link(format!("/proc/{}/exe", pid)).context("reading exe link")?;
+    let map = MappedSlice::new(File::open(&path).context("Failed to open executable")?)?;
+    let objfile = object::File::parse(&*map)
+        .map_err(ObjectError)
+        .context("executable parse failed")?;
+
+    Ok(Some(ObjectInfo {
+        pid: pid.as_raw() as u32,
+        path,
+        // Non-zero for a static PIE
+        addr: entry.wrapping_sub(objfile.entry()),
+        phdrs: vec![],
+    }))
+}
+
+/// Set breakpoints in a process which has just exec'd a statically linked executable, if
+/// it's followed. The inject library can't be loaded into it to tell us.
+fn exec_static(
+    state: &mut State,
+    pid: Pid,
+    args: &Args,
+    config: &BreakpointConfig,
+    follow_exec: &FollowExec,
+) -> Result<(), Error> {
+    let exe = match static_exe(pid)? {
+        Some(exe) => exe,
+        None => return Ok(()),
     };
+    if !state.follows(&exe, follow_exec) {
+        if args.debug {
+            println!("Not following {}", exe.path.display());
+        }
+        return Ok(());
+    }
 
-    symtab::Context::new_from_mapping(mapping, objfile).map_err(Error::from)
+    set_static_breakpoints(state, &exe, args, config)
+        .with_context(|| format!("static executable {}", exe.path.display()))
 }
 
-fn get_breakpoints(
+/// Compute a statically linked executable's breakpoints and set them with ptrace, while the
+/// process is stopped.
+fn set_static_breakpoints(
+    state: &mut State,
     obj: &ObjectInfo,
-    filter: &Filter,
-    debug: bool,
-) -> Result<Vec<(u64, Location)>, Error> {
-    if debug {
-        println!("Object {:x?}", obj);
+    args: &Args,
+    config: &BreakpointConfig,
+) -> Result<(), Error> {
+    if !state.add_object(obj) {
+        return Ok(());
+    }
+    let bps = get_breakpoints(obj, config)?;
+    let addrs = state.add_breakpoints(obj.pid, &bps, args.lazy);
+    if args.debug {
+        println!(
+            "{}: setting {} breakpoints for static {}",
+            obj.pid,
+            addrs.len(),
+            obj.path.display()
+        );
     }
 
-    let ctxt = load_debug(&obj.path, debug)?;
+    let pid = Pid::from_raw(obj.pid as i32);
+    let set = addrs
+        .into_iter()
+        .map(|addr| Ok((addr, ptrace::replace_inst(pid, addr, BREAKPOINT)?)))
+        .collect::<Result<Vec<_>, nix::Error>>()
+        .context("setting breakpoints")?;
+    state.set_replaced(obj.pid, set);
 
-    let mut locations = Vec::new();
+    Ok(())
+}
 
-    //println!("units for {}: {:#?}", obj.path.display(), ctxt.units());
-    for unit in ctxt.units() {
-        let comp_dir = unit
-            .comp_dir
-            .as_ref()
-            .map(|dir| dir.to_string_lossy().map(|dir| dir.into_owned()))
-            .transpose()?;
-        let comp_dir = Path::new(comp_dir.as_ref().map(String::as_str).unwrap_or("."));
+/// Handle a connection from the inject library: compute breakpoints for the objects it sends
+/// and have it set them.
+fn handle_connection(
+    conn: UnixStream,
+    state: &Mutex<State>,
+    args: &Args,
+    config: &BreakpointConfig,
+    follow_exec: &FollowExec,
+) -> Result<(), Error> {
+    let mut reader = BufReader::new(conn.try_clone().context("clone failed")?);
+    let mut writer = BufWriter::new(conn);
+
+    let objinfo: Vec<ObjectInfo> =
+        bincode::deserialize_from(&mut reader).context("ObjectInfo decode failed")?;
+
+    // The executable is always first. The inject library should have already
+    // made this decision, but it may be running with a stale environment. Later
+    // connections from the same image (from dlopen, or forked children) reuse the decision.
+    let follow = objinfo
+        .first()
+        .map_or(false, |exe| state.lock().unwrap().follows(exe, follow_exec));
+    if !follow && args.debug {
+        println!(
+            "Not following {}",
+            objinfo
+                .first()
+                .map(|exe| exe.path.display().to_string())
+                .unwrap_or_default()
+        );
+    }
 
-        if debug {
-            println!(
-                "==== NEW UNIT ==== {} {}",
-                comp_dir.display(),
-                unit.name
-                    .as_ref()
-                    .map(|name| name.to_string_lossy().unwrap().into_owned())
-                    .unwrap_or("???".to_string()),
-            );
+    let objs: Vec<&ObjectInfo> = objinfo
+        .iter()
+        .filter(|_| follow)
+        // Skip the inject library itself, and the vdso which isn't a file
+        .filter(|obj| obj.path.is_absolute() && !is_inject(&obj.path, &args.inject))
+        .filter(|obj| state.lock().unwrap().add_object(obj))
+        .collect();
+
+    // Get the objects' breakpoints in parallel, and set each object's as soon as they're ready
+    let (tx, rx) = mpsc::channel();
+    crossbeam_utils::thread::scope(|scope| -> Result<(), Error> {
+        let _ = scope.spawn(move |_| {
+            objs.par_iter().for_each_with(tx, |tx, &obj| {
+                let _ = tx.send((obj, get_breakpoints(obj, config)));
+            })
+        });
+
+        for (obj, bps) in rx {
+            let bps = match bps {
+                Ok(bps) => bps,
+                Err(err) => {
+                    println!("Failed to get bps for {}: {}", obj.path.display(), err);
+                    continue;
+                }
+            };
+
+            let addrs = state
+                .lock()
+                .unwrap()
+                .add_breakpoints(obj.pid, &bps, args.lazy);
+            if args.debug {
+                println!(
+                    "{}: setting {} breakpoints for obj {}",
+                    obj.pid,
+                    addrs.len(),
+                    obj.path.display()
+                );
+            }
+
+            for batch in addrs.chunks(BREAKPOINT_BATCH) {
+                // Record the original instructions before the breakpoints are set, so that
+                // any hit before the inject library responds can be restored. Those which
+                // can't be read are never set.
+                let insts = read_insts(obj.pid, batch).context("reading instructions")?;
+                let req = SetBreakpointsReq {
+                    breakpoints: insts.iter().map(|&(addr, _)| addr).collect(),
+                };
+                state.lock().unwrap().set_replaced(obj.pid, insts);
+
+                bincode::serialize_into(&mut writer, &req).context("sending breakpoints")?;
+                writer.flush().context("flush send")?;
+
+                // The original instructions it reports are the ones already read
+                let _: SetBreakpointsResp =
+                    bincode::deserialize_from(&mut reader).context("breakpoint response")?;
+            }
         }
 
-        if let Some(ilnp) = &unit.line_program {
-            let header = ilnp.header();
-            // Directory-level filter, indexed by the per-unit directory index number.
-            let allowed_dirs: Vec<(String, bool)> = (0..)
-                .map(|idx| header.directory(idx))
-                .take_while(Option::is_some)
-                .map(Option::unwrap)
-                .map(|dir| -> Result<(String, bool), Error> {
-                    let dir = ctxt
-                        .sections
-                        .attr_string(unit, dir)?
-                        .to_string_lossy()?
-                        .into_owned();
-                    let strdir = comp_dir.join(dir).display().to_string();
-
-                    let allow = filter.dir_include.is_match(&strdir)
-                        || !filter.dir_exclude.is_match(&strdir);
-
-                    Ok((strdir, allow))
-                })
-                .collect::<Result<_, _>>()?;
+        Ok(())
+    })
+    .map_err(|_| anyhow!("breakpoint thread panicked"))??;
 
-            if debug {
-                println!("allowed_dirs {:?}", allowed_dirs);
+    bincode::serialize_into(&mut writer, &SetBreakpointsReq::default())
+        .context("serialize nil breakpoints")?;
+    writer.flush().context("flush send")?;
+
+    Ok(())
+}
+
+/// Restart a tracee after a breakpoint trap, restoring the original instruction if it was one
+/// of ours.
+fn handle_trap(state: &Mutex<State>, pid: Pid) -> Result<(), nix::Error> {
+    let (ip, flags) = ptrace::getipflags(pid)?;
+    let addr = ptrace::breakpoint_addr(ip);
+
+    let trap = state.lock().unwrap().trap(pid, addr, flags);
+    match trap {
+        Trap::Breakpoint(inst) => {
+            ptrace::write_inst(pid, addr, inst)?;
+            ptrace::setip(pid, addr)?;
+            // Set before any other traps are handled, so none can be hit before their
+            // original instructions are recorded
+            if let Err(err) = state.lock().unwrap().set_deferred(pid, addr) {
+                println!("{}: failed to set deferred breakpoints: {}", pid, err);
             }
+        }
+        Trap::Jump(target) => ptrace::setip(pid, target)?,
+        Trap::Removed => ptrace::setip(pid, addr)?,
+        Trap::Unknown => {}
+    }
 
-            let mut rows = ilnp.clone().rows();
-            while let Some((header, row)) = rows.next_row()? {
-                if !row.is_stmt() {
-                    continue;
+    ptrace::cont(pid, None)
+}
+
+/// Trace processes until they've all exited.
+fn trace(
+    state: &Mutex<State>,
+    primary: Pid,
+    args: &Args,
+    config: &BreakpointConfig,
+    follow_exec: &FollowExec,
+) {
+    use wait::WaitStatus::*;
+
+    let debug = args.debug;
+
+    while let Ok(status) = wait::waitpid(None, Some(wait::WaitPidFlag::__WALL)) {
+        if debug {
+            println!("wait status {:?}", status);
+        }
+
+        let res = match status {
+            Exited(pid, code) => {
+                let mut state = state.lock().unwrap();
+                state.exit(pid);
+                if pid == primary {
+                    state.status = Some(code);
                 }
-                let file = row.file(header).unwrap();
-                if !allowed_dirs[file.directory_index() as usize].1 {
-                    continue;
+                Ok(())
+            }
+            Signaled(pid, sig, _) => {
+                let mut state = state.lock().unwrap();
+                state.exit(pid);
+                if pid == primary {
+                    state.status = Some(128 + sig as i32);
                 }
-                let dirname = &allowed_dirs[file.directory_index() as usize].0;
-                let filename = ctxt.sections.attr_string(unit, file.path_name())?;
-                let line = row.line().unwrap_or(0);
-
-                let addr = row.address() + obj.addr as u64;
-                let loc = Location::new(
-                    SrcPath::new(dirname, &*filename.to_string_lossy()?),
-                    line as u32,
-                );
-
-                if debug {
-                    println!(
-                        "Location: {}:{} {:x}",
-                        loc.srcpath().display(),
-                        loc.line(),
-                        addr
-                    );
+                Ok(())
+            }
+            Stopped(pid, signal::SIGTRAP) => handle_trap(state, pid),
+            Stopped(pid, signal::SIGSTOP) => {
+                let mut state = state.lock().unwrap();
+                match state.tracees.get_mut(&pid) {
+                    // Initial stop of a new tracee
+                    Some(process) if process.state() == ProcessState::New => {
+                        process.set_state(ProcessState::Running);
+                        ptrace::cont(pid, None)
+                    }
+                    Some(_) => ptrace::cont(pid, signal::SIGSTOP),
+                    // Leave it stopped until we hear about it from its parent
+                    None => {
+                        let _ = state.pending.insert(pid);
+                        Ok(())
+                    }
                 }
+            }
+            Stopped(pid, signal) => ptrace::cont(pid, signal),
+            PtraceEvent(pid, _, event) => {
+                use ptrace::Event::*;
+
+                let mut state = state.lock().unwrap();
+                let res = if event == PTRACE_EVENT_FORK as i32
+                    || event == PTRACE_EVENT_VFORK as i32
+                    || event == PTRACE_EVENT_CLONE as i32
+                {
+                    ptrace::getevent(pid).and_then(|child| {
+                        let child = Pid::from_raw(child as i32);
+                        if state.add_child(pid, child, event != PTRACE_EVENT_FORK as i32) {
+                            ptrace::cont(child, None)
+                        } else {
+                            Ok(())
+                        }
+                    })
+                } else {
+                    if event == PTRACE_EVENT_EXEC as i32 {
+                        state.exec(pid);
+                        if let Err(err) = exec_static(&mut state, pid, args, config, follow_exec) {
+                            println!("{}: {:#}", pid, err);
+                        }
+                    }
+                    Ok(())
+                };
+                res.and_then(|()| ptrace::cont(pid, None))
+            }
+            PtraceSyscall(pid) => ptrace::cont(pid, None),
+            Continued(_) | StillAlive => Ok(()),
+        };
 
-                locations.push((addr, loc));
+        if let Err(err) = res {
+            // Most likely the tracee was killed while stopped
+            if debug {
+                println!("ptrace failed after {:?}: {}", status, err);
             }
         }
     }
+}
+
+fn write_reports(coverage: &Coverage, args: &Args) -> Result<(), Error> {
+    if let Some(path) = &args.lcov {
+        let mut file = BufWriter::new(File::create(path).context("Creating LCOV file")?);
+        coverage.write_lcov(&mut file)?;
+        file.flush()?;
+    }
+    if let Some(path) = &args.cobertura {
+        let mut file = BufWriter::new(File::create(path).context("Creating Cobertura file")?);
+        coverage.write_cobertura(&mut file)?;
+        file.flush()?;
+    }
+
+    let Totals {
+        lines,
+        branches,
+        functions,
+    } = coverage.summary();
+    println!("Lines: {}/{} covered", lines.hit, lines.found);
+    if args.branches {
+        println!("Branches: {}/{} covered", branches.hit, branches.found);
+    }
+    println!("Functions: {}/{} entered", functions.hit, functions.found);
+    if args.functions {
+        coverage.write_uncalled(io::stdout().lock())?;
+    }
 
-    Ok(locations)
+    Ok(())
 }
 
-fn try_main() -> Result<(), Error> {
+fn try_main() -> Result<i32, Error> {
     let args = Args::from_args();
 
     if args.debug {
@@ -296,114 +698,132 @@ fn try_main() -> Result<(), Error> {
         .tempdir()
         .context("Making tempdir")?;
 
-    let filter = Filter {
-        dir_include: RegexSet::new(&args.include_dir)?,
-        dir_exclude: RegexSet::new(&args.exclude_dir)?,
+    let filter = Filter::new(&args.filter)?;
+    let exclusions = Exclusions::new(&args.exclusion_marker);
+    let debuginfod = Debuginfod::from_env(
+        args.debuginfod_timeout.map(Duration::from_secs),
+        args.debuginfod_offline,
+    );
+    let search = DebugSearch::new(&args.debug_dir, debuginfod);
+    let cache = if args.no_cache {
+        None
+    } else {
+        // Everything which affects the breakpoints found in an object, including everywhere
+        // its debug info may be found
+        let settings = format!(
+            "{} {} {:?} {:?} {:?} {}",
+            env!("CARGO_PKG_VERSION"),
+            filter.cache_key(),
+            args.placement,
+            args.inline,
+            args.branches,
+            search.cache_key()
+        );
+        BreakpointCache::from_env(&settings)
+    };
+    let config = Arc::new(BreakpointConfig {
+        filter,
+        exclusions,
+        search,
+        cache,
+        placement: args.placement,
+        inline: args.inline,
+        branches: args.branches,
+        debug: args.debug,
+    });
+
+    let follow_exec = if args.no_follow_exec {
+        FollowExec::None
+    } else if let Some(re) = &args.follow_exec_filter {
+        // Check it now, since the inject library can only ignore a bad one
+        FollowExec::filter(re).context("Bad --follow-exec-filter")?
+    } else {
+        FollowExec::All
     };
 
     let sock_path = tempdir.path().join("rustkcov.sock");
 
     let listener = UnixListener::bind(&sock_path).context("Socket bind")?;
 
-    let mut command = Command::new(args.binary);
+    let mut command = Command::new(&args.binary);
     command
-        .args(args.args)
+        .args(&args.args)
         .env(INJECT_LIBRARY_VAR, std::env::join_paths(&args.inject)?)
-        .env(SOCKET_ENV, &sock_path);
+        .env(SOCKET_ENV, &sock_path)
+        .env(FOLLOW_EXEC_ENV, follow_exec.to_env())
+        .env(PRIMARY_ENV, std::process::id().to_string());
+    // Stop the child at exec so it can't run before we're tracing it
+    unsafe {
+        command
+            .pre_exec(|| ptrace::traceme().map_err(|err| io::Error::new(io::ErrorKind::Other, err)))
+    };
 
     let child = command.spawn().context("process spawn")?;
     let child_id = Pid::from_raw(child.id() as i32);
 
-    let mut state = Arc::new(Mutex::new(State::new(child)));
+    match wait::waitpid(child_id, None).context("waiting for child exec")? {
+        wait::WaitStatus::Stopped(_, signal::SIGTRAP) => {}
+        status => return Err(anyhow!("Unexpected child status {:?}", status)),
+    }
+    ptrace::setoptions(
+        child_id,
+        ptrace::Options::PTRACE_O_TRACECLONE
+            | ptrace::Options::PTRACE_O_TRACEFORK
+            | ptrace::Options::PTRACE_O_TRACEVFORK
+            | ptrace::Options::PTRACE_O_TRACEEXEC,
+    )
+    .context("setting ptrace options")?;
+
+    let mut state = State::new(child);
+    if let Some(process) = state.tracees.get_mut(&child_id) {
+        process.set_state(ProcessState::Running);
+    }
+    if let Some(exe) = static_exe(child_id)? {
+        set_static_breakpoints(&mut state, &exe, &args, &config)
+            .with_context(|| format!("static executable {}", exe.path.display()))?;
+    }
+    let state = Arc::new(Mutex::new(state));
 
     thread::spawn({
         let state = state.clone();
+        let args = args.clone();
+        let config = config.clone();
+        let follow_exec = follow_exec.clone();
         move || {
-            ptrace::seize(
-                child_id,
-                ptrace::Options::PTRACE_O_TRACECLONE
-                    | ptrace::Options::PTRACE_O_TRACEFORK
-                    | ptrace::Options::PTRACE_O_TRACEVFORK, //| ptrace::Options::PTRACE_O_TRACESYSGOOD
-            )
-            .context("attaching to child")
-            .expect("seize failed");
-            while let Ok(status) = wait::wait() {
-                use wait::WaitStatus::*;
-                println!("wait status {:?}", status);
-                match status {
-                    Exited(pid, status) => {
-                        println!("pid {} exited status {}", pid, status);
-                    }
-                    Signaled(pid, sig, coredumped) => unimplemented!("{:?}", status),
-                    Stopped(pid, signal) => {
-                        let ip = match ptrace::getregs(pid).expect("getregs") {
-                            ptrace::UserRegs::I386(regs) => u64::from(regs.eip),
-                            ptrace::UserRegs::X86_64(regs) => u64::from(regs.rip),
-                        };
-
-                        println!("stopped pid {} signal {} rip {:x}", pid, signal, ip);
-                        if signal == signal::SIGTRAP {
-                            ptrace::cont(pid, None).expect("cont failed");
-                        } else {
-                            ptrace::cont(pid, Some(signal)).expect("cont signal failed");
+            for conn in listener.incoming() {
+                match conn {
+                    Ok(conn) => {
+                        if let Err(err) =
+                            handle_connection(conn, &state, &args, &config, &follow_exec)
+                        {
+                            println!("Connection failed: {:#}", err);
                         }
                     }
-                    PtraceEvent(pid, sig, event) => {
-                        let ev: ptrace::Event = unsafe { std::mem::transmute(event) };
-                        unimplemented!("{:?} ev {:?}", status, ev)
-                    }
-                    PtraceSyscall(pid) => unimplemented!("{:?}", status),
-                    Continued(pid) => unimplemented!("{:?}", status),
-                    StillAlive => {}
+                    Err(err) => println!("Failed to get connection: {}", err),
                 }
             }
         }
     });
 
-    let mut objseen = HashSet::new();
-
-    eprintln!("listening child pid {}", child_id);
-    for conn in listener.incoming() {
-        match conn {
-            Ok(conn) => {
-                eprintln!("connection");
-                let mut reader = BufReader::new(conn.try_clone().expect("clone failed"));
-                let mut writer = BufWriter::new(conn);
-
-                let objinfo: Vec<ObjectInfo> =
-                    bincode::deserialize_from(&mut reader).expect("ObjectInfo decode failed");
-                for obj in &objinfo {
-                    if objseen.insert((obj.pid, obj.path.clone())) {
-                        match get_breakpoints(obj, &filter, args.debug) {
-                            Ok(bp) => println!(
-                                "{}: would set {} breakpoints for obj {}",
-                                obj.pid,
-                                bp.len(),
-                                obj.path.display()
-                            ),
-                            Err(err) => {
-                                println!("Failed to get bps for {}: {}", obj.path.display(), err)
-                            }
-                        }
-                    }
-                }
+    eprintln!("tracing child pid {}", child_id);
+    ptrace::cont(child_id, None).context("starting child")?;
+    trace(&state, child_id, &args, &config, &follow_exec);
 
-                bincode::serialize_into(&mut writer, &SetBreakpointsReq::default())
-                    .expect("serialize nil breakpoints");
-                writer.flush().expect("flush send");
-            }
-            Err(err) => println!("Failed to get connection: {}", err),
-        }
-    }
+    let state = state.lock().unwrap();
+    write_reports(&state.coverage, &args)?;
 
-    Ok(())
+    Ok(state.status.unwrap_or(1))
 }
 
 fn main() {
-    if let Err(err) = try_main() {
-        eprintln!("Failed : {}", err);
-        for err in err.chain().skip(1) {
-            eprintln!("Because: {}", err);
+    match try_main() {
+        Ok(status) => std::process::exit(status),
+        Err(err) => {
+            eprintln!("Failed : {}", err);
+            for err in err.chain().skip(1) {
+                eprintln!("Because: {}", err);
+            }
+            std::process::exit(1);
         }
     }
 }
@@ -5,14 +5,32 @@ use std::{
     sync::Arc,
 };
 
-/// Slice of data from a mapped file
+/// Slice of data from a mapped file, or from an owned buffer for data which isn't in the
+/// file as-is, like decompressed sections.
 #[derive(Clone, Debug)]
 pub struct MappedSlice {
-    mapping: Arc<memmap::Mmap>,
+    mapping: Arc<Backing>,
     start: usize,
     end: usize,
 }
 
+#[derive(Debug)]
+enum Backing {
+    Mapped(memmap::Mmap),
+    Owned(Vec<u8>),
+}
+
+impl Deref for Backing {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        match self {
+            Backing::Mapped(map) => &*map,
+            Backing::Owned(data) => data.as_slice(),
+        }
+    }
+}
+
 impl MappedSlice {
     pub fn new(file: File) -> Result<Self, io::Error> {
         let map = unsafe { memmap::Mmap::map(&file)? };
@@ -20,7 +38,7 @@ impl MappedSlice {
         Ok(MappedSlice {
             start: 0,
             end: map.len(),
-            mapping: Arc::new(map),
+            mapping: Arc::new(Backing::Mapped(map)),
         })
     }
 
@@ -86,5 +104,16 @@ impl Deref for MappedSlice {
         &self.mapping[self.start..self.end]
     }
 }
+
+impl From<Vec<u8>> for MappedSlice {
+    fn from(data: Vec<u8>) -> Self {
+        MappedSlice {
+            start: 0,
+            end: data.len(),
+            mapping: Arc::new(Backing::Owned(data)),
+        }
+    }
+}
+
 unsafe impl gimli::StableDeref for MappedSlice {}
 unsafe impl gimli::CloneStableDeref for MappedSlice {}
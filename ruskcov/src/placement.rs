@@ -0,0 +1,97 @@
+//! Breakpoint placement strategies
+//!
+//! The line table can have many rows for a single source line - loop bodies, inlined
+//! code, instruction scheduling - and a breakpoint at each one is mostly redundant for
+//! line coverage.
+
+use std::{collections::HashSet, hash::Hash, str::FromStr};
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Placement {
+    /// Breakpoint on every `is_stmt` row in the line table
+    All,
+    /// Breakpoint on the lowest address of each line within each line sequence
+    Line,
+    /// Breakpoint on the first instruction of each basic block, found by decoding each
+    /// function's code
+    Block,
+}
+
+impl Placement {
+    pub const VARIANTS: &'static [&'static str] = &["all", "line", "block"];
+
+    /// Reduce a set of candidate breakpoints to those needed by this strategy. Each
+    /// candidate is `(address, sequence, location)`, where sequence identifies the line
+    /// sequence (ie, contiguous range of code) it came from, and location is whatever the
+    /// breakpoint credits. Returns breakpoints in address order.
+    pub fn select<L>(self, mut candidates: Vec<(u64, usize, L)>) -> Vec<(u64, L)>
+    where
+        L: Clone + Eq + Hash,
+    {
+        match self {
+            Placement::All | Placement::Block => {}
+            Placement::Line => {
+                candidates.sort_by_key(|&(addr, _, _)| addr);
+
+                let mut seen = HashSet::new();
+                candidates.retain(|(_, seq, loc)| seen.insert((*seq, loc.clone())));
+            }
+        }
+
+        let mut bps: Vec<_> = candidates
+            .into_iter()
+            .map(|(addr, _, loc)| (addr, loc))
+            .collect();
+        bps.sort_by_key(|&(addr, _)| addr);
+        bps.dedup_by_key(|&mut (addr, _)| addr);
+        bps
+    }
+}
+
+impl FromStr for Placement {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "all" => Ok(Placement::All),
+            "line" => Ok(Placement::Line),
+            "block" => Ok(Placement::Block),
+            _ => Err(format!("Unknown placement {}", s)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::srcloc::{Location, SrcPath};
+
+    fn loc(line: u32) -> Location {
+        Location::new(SrcPath::new("/src", "lib.rs"), line)
+    }
+
+    #[test]
+    fn all_keeps_everything() {
+        let bps = Placement::All.select(vec![(30, 0, loc(2)), (10, 0, loc(1)), (20, 0, loc(1))]);
+
+        assert_eq!(bps, vec![(10, loc(1)), (20, loc(1)), (30, loc(2))]);
+    }
+
+    #[test]
+    fn line_lowest_per_sequence() {
+        let bps = Placement::Line.select(vec![
+            (20, 0, loc(1)),
+            (10, 0, loc(1)),
+            (30, 0, loc(2)),
+            (40, 0, loc(1)),
+            (100, 1, loc(1)),
+            (110, 1, loc(2)),
+            (120, 1, loc(2)),
+        ]);
+
+        assert_eq!(
+            bps,
+            vec![(10, loc(1)), (30, loc(2)), (100, loc(1)), (110, loc(2))]
+        );
+    }
+}
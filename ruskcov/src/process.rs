@@ -1,25 +1,230 @@
 //! Process model
 
+use inject_types::{BreakpointInst, ObjectInfo};
 use nix::unistd::Pid;
+use smallvec::SmallVec;
 use std::{
-    collections::{BTreeMap, HashMap},
+    collections::{BTreeMap, HashMap, HashSet},
     mem,
-    sync::Arc,
+    path::PathBuf,
+    sync::{Arc, Mutex, MutexGuard},
 };
 
-use crate::Location;
+use crate::{
+    bptable::{BreakpointTable, CallTable, Status},
+    disasm::Cond,
+    srcloc::{BranchArm, Function, Location},
+};
 
 #[derive(Debug, Clone)]
 pub struct Segment {
     len: u64,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct AddressSpace {
-    /// Breakpoints by address
-    breakpoints: HashMap<u64, Location>,
+    /// Breakpoints and their state, including those which have been hit and removed, since
+    /// other threads may still trap on them
+    breakpoints: BreakpointTable,
+    /// Branch arms recorded by each breakpoint
+    branches: HashMap<u64, SmallVec<[BranchArm; 2]>>,
+    /// Call sites credited by each breakpoint in inlined code
+    calls: CallTable,
+    /// Functions whose entry is at each breakpoint
+    functions: HashMap<u64, Function>,
+    /// Conditional branches evaluated at their breakpoint
+    conds: HashMap<u64, CondBp>,
+    /// Breakpoints to set once the function entry breakpoint they're keyed by is hit
+    deferred: HashMap<u64, Vec<u64>>,
     /// Segment by address
     segments: BTreeMap<u64, Segment>,
+    /// Objects mapped into the address space
+    objects: HashSet<PathBuf>,
+}
+
+/// A conditional branch whose arms can't all be recorded by breakpoints at their
+/// successors, because they're join points. Instead the breakpoint is on the branch
+/// itself, and stays in place until each such arm has been seen; meanwhile the branch is
+/// emulated.
+#[derive(Debug, Clone, Copy)]
+pub struct CondBp {
+    pub cond: Cond,
+    /// Successor for the taken and not-taken arms
+    pub targets: [u64; 2],
+    pub arms: [BranchArm; 2],
+    /// Arms which have yet to be seen here
+    pub pending: [bool; 2],
+}
+
+/// A breakpoint which was hit
+#[derive(Debug, Clone)]
+pub struct Hit {
+    pub loc: Location,
+    /// Original instruction
+    pub inst: BreakpointInst,
+    pub calls: SmallVec<[Location; 2]>,
+    pub branches: SmallVec<[BranchArm; 2]>,
+    pub function: Option<Function>,
+    /// If set, the breakpoint is still in place, and execution should continue here
+    /// rather than at the original instruction.
+    pub jump: Option<u64>,
+}
+
+impl AddressSpace {
+    /// Add an object and its segments. Returns false if it was already present.
+    pub fn add_object(&mut self, obj: &ObjectInfo) -> bool {
+        if !self.objects.insert(obj.path.clone()) {
+            return false;
+        }
+
+        self.segments.extend(
+            obj.phdrs
+                .iter()
+                .map(|phdr| (obj.addr + phdr.vaddr, Segment { len: phdr.memsize })),
+        );
+        true
+    }
+
+    /// Add breakpoints which are about to be set. Their original instructions must be
+    /// recorded with `set_replaced` before they are. Where there are several at an address the
+    /// first is used, and existing breakpoints are left alone, since they may already be
+    /// set (eg inherited over fork).
+    pub fn add_breakpoints(&mut self, bps: impl IntoIterator<Item = (u64, Location)>) {
+        self.breakpoints.insert(bps)
+    }
+
+    /// Add a branch arm to be recorded by a breakpoint
+    pub fn add_branch(&mut self, addr: u64, arm: BranchArm) {
+        self.branches.entry(addr).or_default().push(arm);
+    }
+
+    /// Add call sites to be credited by line breakpoints
+    pub fn add_calls(&mut self, calls: impl IntoIterator<Item = (u64, Location)>) {
+        self.calls.insert(calls)
+    }
+
+    /// Add a function whose entry is at a breakpoint
+    pub fn add_function(&mut self, addr: u64, func: Function) {
+        let _ = self.functions.insert(addr, func);
+    }
+
+    /// Add a conditional branch to be evaluated at its breakpoint
+    pub fn add_cond(&mut self, addr: u64, cond: CondBp) {
+        let _ = self.conds.insert(addr, cond);
+    }
+
+    /// Record the original instructions of breakpoints which are being set
+    pub fn set_replaced(&mut self, set: impl IntoIterator<Item = (u64, BreakpointInst)>) {
+        for (addr, inst) in set {
+            let _ = self.breakpoints.set_status(addr, Status::Set, Some(inst));
+        }
+    }
+
+    /// Addresses of all breakpoints yet to be set, in order, except deferred ones
+    pub fn unset_breakpoints(&self) -> Vec<u64> {
+        self.breakpoints.with_status(Status::Unset)
+    }
+
+    /// Defer setting breakpoints until the one at `entry` is hit
+    pub fn defer(&mut self, entry: u64, addrs: impl IntoIterator<Item = u64>) {
+        let deferred = self.deferred.entry(entry).or_default();
+        for addr in addrs {
+            if self.breakpoints.get(addr).map(|entry| entry.status) == Some(Status::Unset) {
+                let _ = self.breakpoints.set_status(addr, Status::Deferred, None);
+                deferred.push(addr);
+            }
+        }
+    }
+
+    /// Take the breakpoints deferred until the one at `entry` was hit which still need
+    /// setting. They're no longer deferred, so their original instructions should be
+    /// recorded with `set_replaced` as they're set.
+    pub fn take_deferred(&mut self, entry: u64) -> Vec<u64> {
+        let mut addrs = self.deferred.remove(&entry).unwrap_or_default();
+        addrs.retain(|&addr| self.status(addr) == Some(Status::Deferred));
+        for &addr in &addrs {
+            let _ = self.breakpoints.set_status(addr, Status::Unset, None);
+        }
+        addrs
+    }
+
+    /// Set the breakpoints deferred until the one at `entry` was hit, with `set` replacing
+    /// the instruction at each address with a breakpoint and returning the original. Any
+    /// which fail are left unset, so they're tried again along with the next breakpoints
+    /// added, and the first error is returned.
+    pub fn set_deferred<E>(
+        &mut self,
+        entry: u64,
+        mut set: impl FnMut(u64) -> Result<BreakpointInst, E>,
+    ) -> Result<(), E> {
+        let mut result = Ok(());
+        for addr in self.take_deferred(entry) {
+            match set(addr) {
+                Ok(inst) => {
+                    let _ = self.breakpoints.set_status(addr, Status::Set, Some(inst));
+                }
+                Err(err) => result = result.and(Err(err)),
+            }
+        }
+        result
+    }
+
+    fn status(&self, addr: u64) -> Option<Status> {
+        self.breakpoints.get(addr).map(|entry| entry.status)
+    }
+
+    /// Remove a breakpoint which has been hit, given the flags register at the time. A
+    /// conditional branch breakpoint is left in place while any of its arms are pending.
+    pub fn hit(&mut self, addr: u64, flags: u64) -> Option<Hit> {
+        let entry = self.breakpoints.get(addr)?;
+        let loc = entry.loc;
+        let inst = match (entry.status, entry.inst) {
+            (Status::Set, Some(inst)) => inst,
+            // Not set, so it's not ours
+            _ => return None,
+        };
+
+        let mut branches = self.branches.remove(&addr).unwrap_or_default();
+        let function = self.functions.remove(&addr);
+        let calls = self.calls.take(addr).iter().copied().collect();
+
+        if let Some(cond) = self.conds.get_mut(&addr) {
+            let arm = if cond.cond.eval(flags) { 0 } else { 1 };
+            // Other arms are recorded by breakpoints at their successors
+            if cond.pending[arm] {
+                branches.push(cond.arms[arm]);
+                cond.pending[arm] = false;
+            }
+
+            if cond.pending.iter().any(|&pending| pending) {
+                return Some(Hit {
+                    loc,
+                    inst,
+                    calls,
+                    branches,
+                    function,
+                    jump: Some(cond.targets[arm]),
+                });
+            }
+            let _ = self.conds.remove(&addr);
+        }
+
+        let _ = self.breakpoints.set_status(addr, Status::Removed, None);
+
+        Some(Hit {
+            loc,
+            inst,
+            calls,
+            branches,
+            function,
+            jump: None,
+        })
+    }
+
+    /// Return true if there was a breakpoint at `addr` which has since been removed
+    pub fn was_removed(&self, addr: u64) -> bool {
+        self.status(addr) == Some(Status::Removed)
+    }
 }
 
 /// Model both processes and threads. The only distinction is that threads share an address space
@@ -27,10 +232,10 @@ pub struct AddressSpace {
 pub struct Process {
     pid: Pid,
     state: ProcessState,
-    addrspace: Arc<AddressSpace>,
+    addrspace: Arc<Mutex<AddressSpace>>,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub enum ProcessState {
     /// Newly created; sole owner of address space (ie, main thread)
     New,
@@ -43,41 +248,111 @@ pub enum ProcessState {
 }
 
 impl Process {
-    pub fn new(
-        pid: Pid,
-        segments: impl IntoIterator<Item = (u64, u64)>,
-        breakpoints: impl IntoIterator<Item = (u64, Location)>,
-    ) -> Self {
-        let addrspace = AddressSpace {
-            breakpoints: breakpoints.into_iter().collect(),
-            segments: segments
-                .into_iter()
-                .map(|(addr, len)| (addr, Segment { len }))
-                .collect(),
-        };
+    pub fn new(pid: Pid) -> Self {
         Process {
             pid,
             state: ProcessState::New,
-            addrspace: Arc::new(addrspace),
+            addrspace: Default::default(),
         }
     }
 
+    /// New thread sharing our address space. Also used for vfork, since the child runs in
+    /// the parent's memory until it execs.
     pub fn new_thread(&self, pid: Pid) -> Self {
         Process {
             pid,
-            state: ProcessState::Running,
+            state: ProcessState::New,
             addrspace: Arc::clone(&self.addrspace),
         }
     }
 
-    pub fn exec(&mut self, segments: impl IntoIterator<Item = (u64, u64)>) {
-        let addrspace = AddressSpace {
-            breakpoints: Default::default(),
-            segments: segments
-                .into_iter()
-                .map(|(addr, len)| (addr, Segment { len }))
-                .collect(),
-        };
-        let _ = mem::replace(&mut self.addrspace, Arc::new(addrspace));
+    /// New process with a copy of our address space, including any breakpoints.
+    pub fn fork(&self, pid: Pid) -> Self {
+        Process {
+            pid,
+            state: ProcessState::New,
+            addrspace: Arc::new(Mutex::new(self.addrspace().clone())),
+        }
+    }
+
+    pub fn exec(&mut self) {
+        let _ = mem::replace(&mut self.addrspace, Default::default());
+    }
+
+    pub fn state(&self) -> ProcessState {
+        self.state
+    }
+
+    pub fn set_state(&mut self, state: ProcessState) {
+        self.state = state
+    }
+
+    pub fn addrspace(&self) -> MutexGuard<AddressSpace> {
+        self.addrspace.lock().expect("addrspace lock poisoned")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::srcloc::SrcPath;
+    use inject_types::BREAKPOINT;
+    use std::path::Path;
+
+    #[test]
+    fn deferred() {
+        let loc = |line| Location::new(SrcPath::from_path(Path::new("/src/lib.rs")), line);
+        let mut addrspace = AddressSpace::default();
+        addrspace.add_breakpoints(
+            [0x10, 0x14, 0x18, 0x20]
+                .iter()
+                .map(|&addr| (addr, loc(addr as u32))),
+        );
+        addrspace.defer(0x10, vec![0x14, 0x18]);
+
+        // Only the entry and the breakpoint outside the function are set at first
+        assert_eq!(addrspace.unset_breakpoints(), vec![0x10, 0x20]);
+        addrspace.set_replaced(vec![(0x10, BREAKPOINT), (0x20, BREAKPOINT)]);
+        assert_eq!(addrspace.unset_breakpoints(), vec![]);
+        assert!(addrspace.hit(0x14, 0).is_none());
+
+        // Entering the function releases its body
+        assert!(addrspace.hit(0x10, 0).is_some());
+        assert_eq!(addrspace.take_deferred(0x10), vec![0x14, 0x18]);
+        assert_eq!(addrspace.take_deferred(0x10), vec![]);
+        addrspace.set_replaced(vec![(0x14, BREAKPOINT), (0x18, BREAKPOINT)]);
+
+        let hit = addrspace.hit(0x14, 0).unwrap();
+        assert_eq!(hit.loc.line(), 0x14);
+        assert_eq!(hit.inst, BREAKPOINT);
+    }
+
+    #[test]
+    fn set_deferred() {
+        let loc = |line| Location::new(SrcPath::from_path(Path::new("/src/lib.rs")), line);
+        let mut addrspace = AddressSpace::default();
+        addrspace.add_breakpoints(
+            [0x10, 0x14, 0x18, 0x1c]
+                .iter()
+                .map(|&addr| (addr, loc(addr as u32))),
+        );
+        addrspace.defer(0x10, vec![0x14, 0x18, 0x1c]);
+        addrspace.set_replaced(vec![(0x10, BREAKPOINT)]);
+
+        // The body is set once the entry is hit, except where setting fails
+        assert!(addrspace.hit(0x10, 0).is_some());
+        let inst = BreakpointInst([0x55]);
+        let result = addrspace.set_deferred(0x10, |addr| match addr {
+            0x18 => Err("unmapped"),
+            _ => Ok(inst),
+        });
+        assert_eq!(result, Err("unmapped"));
+        assert_eq!(addrspace.hit(0x14, 0).unwrap().inst, inst);
+        assert_eq!(addrspace.hit(0x1c, 0).unwrap().inst, inst);
+
+        // and that's left to be set with the next breakpoints
+        assert!(addrspace.hit(0x18, 0).is_none());
+        assert_eq!(addrspace.unset_breakpoints(), vec![0x18]);
+        assert_eq!(addrspace.set_deferred(0x10, |_| Err("again")), Ok(()));
     }
 }
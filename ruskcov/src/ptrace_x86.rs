@@ -1,6 +1,7 @@
+use inject_types::BreakpointInst;
 use libc::c_void;
 pub use nix::{
-    sys::ptrace::{cont, seize, Event, Options},
+    sys::ptrace::{cont, getevent, setoptions, traceme, Event, Options},
     unistd::Pid,
     Result,
 };
@@ -120,3 +121,89 @@ pub fn getregs(pid: Pid) -> Result<UserRegs> {
         }
     }
 }
+
+/// Set process registers. The register set must be the same size as the one returned by
+/// `getregs`.
+pub fn setregs(pid: Pid, regs: UserRegs) -> Result<()> {
+    unsafe {
+        let (mut regs, len) = match regs {
+            UserRegs::I386(regs) => (RegUnion { i386: regs }, mem::size_of::<RegsI386>()),
+            UserRegs::X86_64(regs) => (RegUnion { x86_64: regs }, mem::size_of::<RegsX86_64>()),
+        };
+        let mut iov = libc::iovec {
+            iov_base: &mut regs as *mut RegUnion as *mut c_void,
+            iov_len: len,
+        };
+        let res = libc::ptrace(
+            libc::PTRACE_SETREGSET,
+            pid.as_raw(),
+            NT_PRSTATUS as usize,
+            &mut iov,
+        );
+        if res < 0 {
+            return Err(nix::Error::last());
+        }
+        Ok(())
+    }
+}
+
+/// Get the instruction pointer
+pub fn getip(pid: Pid) -> Result<u64> {
+    Ok(match getregs(pid)? {
+        UserRegs::I386(regs) => u64::from(regs.eip),
+        UserRegs::X86_64(regs) => u64::from(regs.rip),
+    })
+}
+
+/// Get the instruction pointer and flags register
+pub fn getipflags(pid: Pid) -> Result<(u64, u64)> {
+    Ok(match getregs(pid)? {
+        UserRegs::I386(regs) => (u64::from(regs.eip), u64::from(regs.eflags)),
+        UserRegs::X86_64(regs) => (u64::from(regs.rip), u64::from(regs.eflags)),
+    })
+}
+
+/// Set the instruction pointer
+pub fn setip(pid: Pid, ip: u64) -> Result<()> {
+    let regs = match getregs(pid)? {
+        UserRegs::I386(mut regs) => {
+            regs.eip = ip as u32;
+            UserRegs::I386(regs)
+        }
+        #[cfg(target_arch = "x86_64")]
+        UserRegs::X86_64(mut regs) => {
+            regs.rip = ip;
+            UserRegs::X86_64(regs)
+        }
+        #[cfg(not(target_arch = "x86_64"))]
+        UserRegs::X86_64(_) => unreachable!(),
+    };
+    setregs(pid, regs)
+}
+
+/// Address of the breakpoint instruction which trapped at `ip`
+pub fn breakpoint_addr(ip: u64) -> u64 {
+    ip - mem::size_of::<BreakpointInst>() as u64
+}
+
+/// Write an instruction at `addr`, ie to restore the original instruction under a breakpoint.
+pub fn write_inst(pid: Pid, addr: u64, inst: BreakpointInst) -> Result<()> {
+    replace_inst(pid, addr, inst).map(|_| ())
+}
+
+/// Replace the instruction at `addr`, returning the original, ie to set a breakpoint. This
+/// uses the aligned word containing it, so it can't run past the end of the mapping.
+pub fn replace_inst(pid: Pid, addr: u64, inst: BreakpointInst) -> Result<BreakpointInst> {
+    use nix::sys::ptrace;
+
+    let offset = addr % mem::size_of::<libc::c_long>() as u64;
+    let word_addr = (addr - offset) as ptrace::AddressType;
+    // Little-endian, so the instruction is `offset` bytes up from the low byte
+    let shift = offset * 8;
+    let word = ptrace::read(pid, word_addr)?;
+    let old = BreakpointInst([(word >> shift) as u8]);
+    let word = (word & !(0xff << shift)) | (libc::c_long::from(inst.0[0]) << shift);
+
+    ptrace::write(pid, word_addr, word as *mut c_void)?;
+    Ok(old)
+}
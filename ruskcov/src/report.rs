@@ -0,0 +1,545 @@
+//! Coverage results and report output
+
+use std::{
+    collections::BTreeMap,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::srcloc::{BranchArm, Function, Location, SrcPath};
+
+/// Coverage accumulated over all traced processes
+#[derive(Debug, Default)]
+pub struct Coverage {
+    files: BTreeMap<SrcPath, FileCoverage>,
+}
+
+/// Coverage for a single source file
+#[derive(Debug, Default)]
+struct FileCoverage {
+    /// Hits by line
+    lines: BTreeMap<u32, u64>,
+    /// Hits by (line, branch, arm), in the merged files for output
+    branches: BTreeMap<(u32, u32, u32), u64>,
+    /// Hits by arm as they're recorded. Branches are only numbered within each object, so
+    /// they're numbered afresh into `branches` for output.
+    arms: BTreeMap<BranchArm, u64>,
+    /// Entries by (declaration line, name)
+    functions: BTreeMap<(u32, String), FunctionCoverage>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct FunctionCoverage {
+    krate: String,
+    hits: u64,
+}
+
+impl FileCoverage {
+    /// Number of arms of each branch which were taken, by (line, branch)
+    fn branches_taken(&self) -> BTreeMap<(u32, u32), (usize, usize)> {
+        let mut taken = BTreeMap::new();
+        for (&(line, branch, _), &hits) in &self.branches {
+            let (arms, hit) = taken.entry((line, branch)).or_insert((0, 0));
+            *arms += 1;
+            if hits > 0 {
+                *hit += 1;
+            }
+        }
+        taken
+    }
+}
+
+/// Line, branch and function summaries
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct Totals {
+    pub lines: Summary,
+    pub branches: Summary,
+    pub functions: Summary,
+}
+
+/// Line, branch or function hit summary
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct Summary {
+    pub found: usize,
+    pub hit: usize,
+}
+
+impl Summary {
+    fn add(&mut self, hit: bool) {
+        self.found += 1;
+        if hit {
+            self.hit += 1;
+        }
+    }
+
+    fn rate(&self) -> f64 {
+        if self.found == 0 {
+            1.0
+        } else {
+            self.hit as f64 / self.found as f64
+        }
+    }
+}
+
+impl Coverage {
+    /// Record an instrumented line
+    pub fn add_line(&mut self, loc: &Location) {
+        let _ = self.file(loc.file()).lines.entry(loc.line()).or_insert(0);
+    }
+
+    pub fn hit_line(&mut self, loc: &Location) {
+        *self.file(loc.file()).lines.entry(loc.line()).or_insert(0) += 1;
+    }
+
+    /// Record an instrumented branch arm
+    pub fn add_branch(&mut self, arm: &BranchArm) {
+        let _ = self.file(arm.file()).arms.entry(*arm).or_insert(0);
+    }
+
+    pub fn hit_branch(&mut self, arm: &BranchArm) {
+        *self.file(arm.file()).arms.entry(*arm).or_insert(0) += 1;
+    }
+
+    /// Record an instrumented function
+    pub fn add_function(&mut self, func: &Function) {
+        let _ = self.function(func);
+    }
+
+    pub fn hit_function(&mut self, func: &Function) {
+        self.function(func).hits += 1;
+    }
+
+    fn function(&mut self, func: &Function) -> &mut FunctionCoverage {
+        self.file(func.file())
+            .functions
+            .entry((func.line(), func.name().to_string()))
+            .or_insert_with(|| FunctionCoverage {
+                krate: func.krate().to_string(),
+                hits: 0,
+            })
+    }
+
+    fn file(&mut self, srcpath: SrcPath) -> &mut FileCoverage {
+        self.files.entry(srcpath).or_default()
+    }
+
+    /// Files in path order, merging any which have the same path. Each line's branches are
+    /// numbered in order of the objects they're in, then their number within the object.
+    fn sorted_files(&self) -> BTreeMap<PathBuf, FileCoverage> {
+        let mut files: BTreeMap<PathBuf, FileCoverage> = BTreeMap::new();
+        let mut branches: BTreeMap<PathBuf, BTreeMap<(u32, &Path, u32, u32), u64>> =
+            BTreeMap::new();
+
+        for (srcpath, cov) in &self.files {
+            let file = files.entry(srcpath.to_pathbuf()).or_default();
+            for (&line, &hits) in &cov.lines {
+                *file.lines.entry(line).or_insert(0) += hits;
+            }
+            let file_branches = branches.entry(srcpath.to_pathbuf()).or_default();
+            for (arm, &hits) in &cov.arms {
+                *file_branches
+                    .entry((arm.line(), arm.object(), arm.branch(), arm.arm()))
+                    .or_insert(0) += hits;
+            }
+            for (key, func) in &cov.functions {
+                file.functions
+                    .entry(key.clone())
+                    .or_insert_with(|| FunctionCoverage {
+                        krate: func.krate.clone(),
+                        hits: 0,
+                    })
+                    .hits += func.hits;
+            }
+        }
+
+        for (path, file_branches) in branches {
+            let file = files.get_mut(&path).unwrap();
+            let mut prev = None;
+            let mut number = 0;
+            for ((line, object, branch, arm), hits) in file_branches {
+                match prev {
+                    Some(prev) if prev == (line, object, branch) => {}
+                    Some((prev_line, _, _)) if prev_line == line => number += 1,
+                    _ => number = 0,
+                }
+                prev = Some((line, object, branch));
+                let _ = file.branches.insert((line, number, arm), hits);
+            }
+        }
+
+        files
+    }
+
+    /// Summary of lines, branches and functions
+    pub fn summary(&self) -> Totals {
+        let mut totals = Totals::default();
+
+        for file in self.sorted_files().values() {
+            file.lines
+                .values()
+                .for_each(|&hits| totals.lines.add(hits > 0));
+            file.branches
+                .values()
+                .for_each(|&hits| totals.branches.add(hits > 0));
+            file.functions
+                .values()
+                .for_each(|func| totals.functions.add(func.hits > 0));
+        }
+
+        totals
+    }
+
+    /// Write the functions which were never called, grouped by crate
+    pub fn write_uncalled<W: Write>(&self, mut w: W) -> io::Result<()> {
+        let mut crates: BTreeMap<&str, (Summary, Vec<(&str, &Path, u32)>)> = BTreeMap::new();
+        let files = self.sorted_files();
+
+        for (path, file) in &files {
+            for ((line, name), func) in &file.functions {
+                let (summary, uncalled) = crates.entry(func.krate.as_str()).or_default();
+                summary.add(func.hits > 0);
+                if func.hits == 0 {
+                    uncalled.push((name, path, *line));
+                }
+            }
+        }
+
+        for (krate, (summary, mut uncalled)) in crates {
+            writeln!(
+                w,
+                "{}: {}/{} functions never called",
+                krate,
+                uncalled.len(),
+                summary.found
+            )?;
+            uncalled.sort();
+            for (name, path, line) in uncalled {
+                writeln!(w, "    {} ({}:{})", name, path.display(), line)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Write an LCOV tracefile
+    pub fn write_lcov<W: Write>(&self, mut w: W) -> io::Result<()> {
+        writeln!(w, "TN:")?;
+
+        for (path, file) in &self.sorted_files() {
+            writeln!(w, "SF:{}", path.display())?;
+
+            let mut functions = Summary::default();
+            for ((line, name), _) in &file.functions {
+                writeln!(w, "FN:{},{}", line, name)?;
+            }
+            for ((_, name), func) in &file.functions {
+                writeln!(w, "FNDA:{},{}", func.hits, name)?;
+                functions.add(func.hits > 0);
+            }
+            if functions.found > 0 {
+                writeln!(w, "FNF:{}", functions.found)?;
+                writeln!(w, "FNH:{}", functions.hit)?;
+            }
+
+            let taken = file.branches_taken();
+            let mut branches = Summary::default();
+            for (&(line, branch, arm), &hits) in &file.branches {
+                // A branch which was never reached has no arms taken
+                if taken[&(line, branch)].1 == 0 {
+                    writeln!(w, "BRDA:{},{},{},-", line, branch, arm)?;
+                } else {
+                    writeln!(w, "BRDA:{},{},{},{}", line, branch, arm, hits)?;
+                }
+                branches.add(hits > 0);
+            }
+            if branches.found > 0 {
+                writeln!(w, "BRF:{}", branches.found)?;
+                writeln!(w, "BRH:{}", branches.hit)?;
+            }
+
+            let mut lines = Summary::default();
+            for (&line, &hits) in &file.lines {
+                writeln!(w, "DA:{},{}", line, hits)?;
+                lines.add(hits > 0);
+            }
+            writeln!(w, "LF:{}", lines.found)?;
+            writeln!(w, "LH:{}", lines.hit)?;
+            writeln!(w, "end_of_record")?;
+        }
+
+        Ok(())
+    }
+
+    /// Write a Cobertura XML report. Each source directory is a package, and each file a
+    /// class.
+    pub fn write_cobertura<W: Write>(&self, mut w: W) -> io::Result<()> {
+        let Totals {
+            lines, branches, ..
+        } = self.summary();
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        writeln!(w, r#"<?xml version="1.0" ?>"#)?;
+        writeln!(
+            w,
+            r#"<!DOCTYPE coverage SYSTEM "http://cobertura.sourceforge.net/xml/coverage-04.dtd">"#
+        )?;
+        writeln!(
+            w,
+            r#"<coverage line-rate="{:.4}" branch-rate="{:.4}" lines-covered="{}" lines-valid="{}" branches-covered="{}" branches-valid="{}" complexity="0" version="ruskcov {}" timestamp="{}">"#,
+            lines.rate(),
+            branches.rate(),
+            lines.hit,
+            lines.found,
+            branches.hit,
+            branches.found,
+            env!("CARGO_PKG_VERSION"),
+            timestamp,
+        )?;
+        writeln!(w, "  <sources>\n    <source>/</source>\n  </sources>")?;
+        writeln!(w, "  <packages>")?;
+
+        let mut packages: BTreeMap<&Path, Vec<(&Path, &FileCoverage)>> = BTreeMap::new();
+        let files = self.sorted_files();
+        for (path, file) in &files {
+            packages
+                .entry(path.parent().unwrap_or(Path::new("")))
+                .or_default()
+                .push((path, file));
+        }
+
+        for (dir, files) in packages {
+            let mut pkglines = Summary::default();
+            let mut pkgbranches = Summary::default();
+            for (_, file) in &files {
+                file.lines.values().for_each(|&hits| pkglines.add(hits > 0));
+                file.branches
+                    .values()
+                    .for_each(|&hits| pkgbranches.add(hits > 0));
+            }
+
+            writeln!(
+                w,
+                r#"    <package name="{}" line-rate="{:.4}" branch-rate="{:.4}" complexity="0">"#,
+                xml_escape(&dir.display().to_string()),
+                pkglines.rate(),
+                pkgbranches.rate(),
+            )?;
+            writeln!(w, "      <classes>")?;
+
+            for (path, file) in files {
+                let taken = file.branches_taken();
+                let mut filelines = Summary::default();
+                let mut filebranches = Summary::default();
+                file.lines
+                    .values()
+                    .for_each(|&hits| filelines.add(hits > 0));
+                file.branches
+                    .values()
+                    .for_each(|&hits| filebranches.add(hits > 0));
+
+                writeln!(
+                    w,
+                    r#"        <class name="{}" filename="{}" line-rate="{:.4}" branch-rate="{:.4}" complexity="0">"#,
+                    xml_escape(
+                        &path
+                            .file_name()
+                            .map(|name| name.to_string_lossy())
+                            .unwrap_or_default()
+                    ),
+                    xml_escape(&path.display().to_string()),
+                    filelines.rate(),
+                    filebranches.rate(),
+                )?;
+                if file.functions.is_empty() {
+                    writeln!(w, "          <methods/>")?;
+                } else {
+                    writeln!(w, "          <methods>")?;
+                    for ((line, name), func) in &file.functions {
+                        let rate = if func.hits > 0 { 1.0 } else { 0.0 };
+                        writeln!(
+                            w,
+                            r#"            <method name="{}" signature="" line-rate="{:.4}" branch-rate="{:.4}" complexity="0">"#,
+                            xml_escape(name),
+                            rate,
+                            rate,
+                        )?;
+                        writeln!(
+                            w,
+                            r#"              <lines><line number="{}" hits="{}" branch="false"/></lines>"#,
+                            line, func.hits
+                        )?;
+                        writeln!(w, "            </method>")?;
+                    }
+                    writeln!(w, "          </methods>")?;
+                }
+                writeln!(w, "          <lines>")?;
+
+                for (&line, &hits) in &file.lines {
+                    // Sum all the branches on this line
+                    let (arms, hit) = taken
+                        .range((line, 0)..=(line, u32::max_value()))
+                        .fold((0, 0), |(arms, hit), (_, &(a, h))| (arms + a, hit + h));
+
+                    if arms > 0 {
+                        writeln!(
+                            w,
+                            r#"            <line number="{}" hits="{}" branch="true" condition-coverage="{}% ({}/{})"/>"#,
+                            line,
+                            hits,
+                            hit * 100 / arms,
+                            hit,
+                            arms
+                        )?;
+                    } else {
+                        writeln!(
+                            w,
+                            r#"            <line number="{}" hits="{}" branch="false"/>"#,
+                            line, hits
+                        )?;
+                    }
+                }
+
+                writeln!(w, "          </lines>")?;
+                writeln!(w, "        </class>")?;
+            }
+
+            writeln!(w, "      </classes>")?;
+            writeln!(w, "    </package>")?;
+        }
+
+        writeln!(w, "  </packages>")?;
+        writeln!(w, "</coverage>")?;
+
+        Ok(())
+    }
+}
+
+fn xml_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn coverage() -> Coverage {
+        let srcpath = SrcPath::new("/src", "lib.rs");
+        let mut cov = Coverage::default();
+
+        cov.add_line(&Location::new(srcpath, 1));
+        cov.add_line(&Location::new(srcpath, 2));
+        cov.hit_line(&Location::new(srcpath, 1));
+
+        let arm = |line, arm| BranchArm::new(Path::new("/lib.so"), srcpath, line, 0, arm);
+        cov.add_branch(&arm(1, 0));
+        cov.add_branch(&arm(1, 1));
+        cov.hit_branch(&arm(1, 1));
+        cov.add_branch(&arm(2, 0));
+        cov.add_branch(&arm(2, 1));
+
+        let used = Function::new(srcpath, 1, "lib::used", "lib");
+        cov.add_function(&used);
+        cov.add_function(&Function::new(srcpath, 2, "lib::unused", "lib"));
+        cov.hit_function(&used);
+
+        cov
+    }
+
+    #[test]
+    fn lcov() {
+        let mut out = Vec::new();
+        coverage().write_lcov(&mut out).unwrap();
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "TN:\n\
+             SF:/src/lib.rs\n\
+             FN:1,lib::used\n\
+             FN:2,lib::unused\n\
+             FNDA:1,lib::used\n\
+             FNDA:0,lib::unused\n\
+             FNF:2\n\
+             FNH:1\n\
+             BRDA:1,0,0,0\n\
+             BRDA:1,0,1,1\n\
+             BRDA:2,0,0,-\n\
+             BRDA:2,0,1,-\n\
+             BRF:4\n\
+             BRH:1\n\
+             DA:1,1\n\
+             DA:2,0\n\
+             LF:2\n\
+             LH:1\n\
+             end_of_record\n"
+        );
+    }
+
+    #[test]
+    fn cobertura() {
+        let mut out = Vec::new();
+        coverage().write_cobertura(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        assert!(out.contains(r#"<package name="/src" line-rate="0.5000" branch-rate="0.2500""#));
+        assert!(out.contains(
+            r#"<line number="1" hits="1" branch="true" condition-coverage="50% (1/2)"/>"#
+        ));
+        assert!(out.contains(
+            r#"<line number="2" hits="0" branch="true" condition-coverage="0% (0/2)"/>"#
+        ));
+        assert!(out.contains(r#"<method name="lib::unused" signature="" line-rate="0.0000""#));
+    }
+
+    #[test]
+    fn branches_per_object() {
+        let srcpath = SrcPath::new("/src", "lib.h");
+        let mut cov = Coverage::default();
+
+        // The first branch on the line in each of two objects, which are different branches.
+        // The second object's is hit in two processes.
+        let arm = |object, arm| BranchArm::new(Path::new(object), srcpath, 1, 0, arm);
+        cov.add_line(&Location::new(srcpath, 1));
+        for &object in &["/b.so", "/a.so", "/b.so"] {
+            cov.add_branch(&arm(object, 0));
+            cov.add_branch(&arm(object, 1));
+        }
+        cov.hit_branch(&arm("/a.so", 1));
+        cov.hit_branch(&arm("/b.so", 0));
+        cov.hit_branch(&arm("/b.so", 0));
+
+        let mut out = Vec::new();
+        cov.write_lcov(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(
+            out.contains("BRDA:1,0,0,0\nBRDA:1,0,1,1\nBRDA:1,1,0,2\nBRDA:1,1,1,0\nBRF:4\n"),
+            "{}",
+            out
+        );
+    }
+
+    #[test]
+    fn uncalled() {
+        let mut out = Vec::new();
+        coverage().write_uncalled(&mut out).unwrap();
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "lib: 1/2 functions never called\n    lib::unused (/src/lib.rs:2)\n"
+        );
+    }
+}
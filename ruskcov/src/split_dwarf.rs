@@ -0,0 +1,362 @@
+//! Loading split DWARF units from `.dwo` files and `.dwp` packages.
+//!
+//! With `-gsplit-dwarf` the object only has skeleton units with the line tables and
+//! addresses, and the DIEs describing functions are in a `.dwo` file for each unit, or a
+//! `.dwp` package of them all next to the executable. Only GNU split DWARF (DWARF 4 with the
+//! `DW_AT_GNU_dwo_*` extensions) is supported, since gimli doesn't yet parse DWARF 5
+//! skeleton units. The context skips those, and the object's coverage for them is lost with a
+//! warning.
+
+use anyhow::{anyhow, Context, Error};
+use object::read::Object;
+use std::{
+    collections::HashMap,
+    fs::File,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    error::ObjectError,
+    mapped_slice::MappedSlice,
+    symtab::{self, section_data, Skeleton},
+};
+
+type Reader = gimli::EndianReader<gimli::RunTimeEndian, MappedSlice>;
+
+/// `DW_SECT_*` column ids in a package index, which are the same in the GNU and DWARF 5
+/// formats for the sections we use.
+const DW_SECT_INFO: u32 = 1;
+const DW_SECT_ABBREV: u32 = 3;
+const DW_SECT_LINE: u32 = 4;
+const DW_SECT_STR_OFFSETS: u32 = 6;
+
+/// Add the split units for all the skeleton units in `ctxt`, for the object at `path`.
+/// They're taken from the object's `.dwp` package if it has one, or else each unit's `.dwo`
+/// file.
+pub fn load_split_units(ctxt: &mut symtab::Context<Reader>, path: &Path, debug: bool) {
+    let skeletons: Vec<(usize, Skeleton)> = ctxt
+        .skeletons()
+        .into_iter()
+        .map(|(unit_id, skeleton)| (unit_id, skeleton.clone()))
+        .collect();
+    if skeletons.is_empty() {
+        return;
+    }
+
+    let mut dwp_name = path.file_name().unwrap_or_default().to_os_string();
+    dwp_name.push(".dwp");
+    let dwp_path = path.with_file_name(dwp_name);
+    let dwp = if dwp_path.exists() {
+        match Package::open(&dwp_path) {
+            Ok(dwp) => Some(dwp),
+            Err(err) => {
+                println!("Failed to load {}: {:#}", dwp_path.display(), err);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    for (unit_id, skeleton) in skeletons {
+        let sections = match &dwp {
+            Some(dwp) => dwp.unit(skeleton.dwo_id),
+            None => load_dwo(path, &skeleton, debug),
+        };
+        let sections = match sections {
+            Some(sections) => sections,
+            None => {
+                if debug {
+                    println!(
+                        "No split unit {:016x} {}",
+                        skeleton.dwo_id,
+                        skeleton.dwo_name.as_deref().unwrap_or("")
+                    );
+                }
+                continue;
+            }
+        };
+
+        match ctxt.add_split_unit(unit_id, sections) {
+            Ok(true) => {}
+            Ok(false) => {
+                if debug {
+                    println!("Split unit {:016x} id mismatch", skeleton.dwo_id);
+                }
+            }
+            Err(err) => println!("Failed to add split unit {:016x}: {}", skeleton.dwo_id, err),
+        }
+    }
+}
+
+/// Load the sections of a skeleton's `.dwo` file, which is at `dwo_name` relative to
+/// `comp_dir`, or next to the object.
+fn load_dwo(path: &Path, skeleton: &Skeleton, debug: bool) -> Option<gimli::Dwarf<Reader>> {
+    let dwo_name = Path::new(skeleton.dwo_name.as_ref()?);
+    let comp_dir = Path::new(skeleton.comp_dir.as_deref().unwrap_or("."));
+    let objdir = path.parent().unwrap_or(Path::new("."));
+    let candidates: Vec<PathBuf> =
+        vec![comp_dir.join(dwo_name), objdir.join(dwo_name.file_name()?)];
+
+    for candidate in candidates {
+        let sections = with_object(&candidate, |map, endian, file| {
+            let section = |name| section_data(map, file, name);
+            dwo_sections(
+                endian,
+                section(".debug_info.dwo"),
+                section(".debug_abbrev.dwo"),
+                section(".debug_line.dwo"),
+                section(".debug_str.dwo"),
+                section(".debug_str_offsets.dwo"),
+            )
+        });
+        match sections {
+            Ok(sections) => {
+                if debug {
+                    println!("Using dwo {}", candidate.display());
+                }
+                return Some(sections);
+            }
+            Err(err) => {
+                if debug {
+                    println!("dwo {}: {:#}", candidate.display(), err);
+                }
+            }
+        }
+    }
+
+    None
+}
+
+/// Map and parse an object file, and call `f` with it
+fn with_object<T, F>(path: &Path, f: F) -> Result<T, Error>
+where
+    F: FnOnce(&MappedSlice, gimli::RunTimeEndian, &object::File) -> T,
+{
+    let map = MappedSlice::new(File::open(path).context("open failed")?)?;
+    let file = object::File::parse(&*map)
+        .map_err(ObjectError)
+        .context("parse error")?;
+    let endian = if file.is_little_endian() {
+        gimli::RunTimeEndian::Little
+    } else {
+        gimli::RunTimeEndian::Big
+    };
+
+    Ok(f(&map, endian, &file))
+}
+
+/// Make the sections of a split unit. The addresses and ranges are in the skeleton's object.
+fn dwo_sections(
+    endian: gimli::RunTimeEndian,
+    info: MappedSlice,
+    abbrev: MappedSlice,
+    line: MappedSlice,
+    str: MappedSlice,
+    str_offsets: MappedSlice,
+) -> gimli::Dwarf<Reader> {
+    let empty = gimli::EndianReader::new(info.subslice(0..0), endian);
+    let section = |data| gimli::EndianReader::new(data, endian);
+
+    gimli::Dwarf {
+        debug_abbrev: section(abbrev).into(),
+        debug_addr: empty.clone().into(),
+        debug_info: section(info).into(),
+        debug_line: section(line).into(),
+        debug_line_str: empty.clone().into(),
+        debug_str: section(str).into(),
+        debug_str_offsets: section(str_offsets).into(),
+        debug_str_sup: empty.clone().into(),
+        debug_types: empty.clone().into(),
+        locations: gimli::LocationLists::new(empty.clone().into(), empty.clone().into()),
+        ranges: gimli::RangeLists::new(empty.clone().into(), empty.into()),
+    }
+}
+
+/// A `.dwp` package of split units, indexed by unit id
+struct Package {
+    endian: gimli::RunTimeEndian,
+    info: MappedSlice,
+    abbrev: MappedSlice,
+    line: MappedSlice,
+    str: MappedSlice,
+    str_offsets: MappedSlice,
+    /// Each unit's contributions to the sections, by `DW_SECT_*` id
+    index: HashMap<u64, Vec<Contribution>>,
+}
+
+/// A unit's part of a package section
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Contribution {
+    section: u32,
+    offset: usize,
+    size: usize,
+}
+
+impl Package {
+    fn open(path: &Path) -> Result<Self, Error> {
+        with_object(path, |map, endian, file| {
+            let section = |name| section_data(map, file, name);
+            let index = parse_cu_index(
+                &section(".debug_cu_index"),
+                endian == gimli::RunTimeEndian::Little,
+            )
+            .ok_or_else(|| anyhow!("bad .debug_cu_index"))?;
+
+            Ok(Package {
+                endian,
+                info: section(".debug_info.dwo"),
+                abbrev: section(".debug_abbrev.dwo"),
+                line: section(".debug_line.dwo"),
+                str: section(".debug_str.dwo"),
+                str_offsets: section(".debug_str_offsets.dwo"),
+                index,
+            })
+        })?
+    }
+
+    /// Get the sections of a split unit, limited to its contributions
+    fn unit(&self, dwo_id: u64) -> Option<gimli::Dwarf<Reader>> {
+        let contributions = self.index.get(&dwo_id)?;
+        let part = |data: &MappedSlice, section| -> Option<MappedSlice> {
+            match contributions.iter().find(|c| c.section == section) {
+                Some(c) if c.offset + c.size <= data.len() => {
+                    Some(data.subslice(c.offset..c.offset + c.size))
+                }
+                Some(_) => None,
+                None => Some(data.subslice(0..0)),
+            }
+        };
+
+        Some(dwo_sections(
+            self.endian,
+            part(&self.info, DW_SECT_INFO)?,
+            part(&self.abbrev, DW_SECT_ABBREV)?,
+            part(&self.line, DW_SECT_LINE)?,
+            self.str.clone(),
+            part(&self.str_offsets, DW_SECT_STR_OFFSETS)?,
+        ))
+    }
+}
+
+/// Parse a package's unit index, returning each unit's section contributions by unit id.
+///
+/// The index is a header of version, number of columns, units and hash slots, then a hash
+/// table of unit ids and a parallel table of 1-based rows, then a row of the `DW_SECT_*` id
+/// of each column, and tables of offsets and sizes with a row for each unit.
+fn parse_cu_index(data: &[u8], little_endian: bool) -> Option<HashMap<u64, Vec<Contribution>>> {
+    let u32_at = |offset: usize| -> Option<u32> {
+        let bytes = data.get(offset..offset + 4)?;
+        let bytes = [bytes[0], bytes[1], bytes[2], bytes[3]];
+        Some(if little_endian {
+            u32::from_le_bytes(bytes)
+        } else {
+            u32::from_be_bytes(bytes)
+        })
+    };
+    let u64_at = |offset: usize| -> Option<u64> {
+        let (a, b) = (u64::from(u32_at(offset)?), u64::from(u32_at(offset + 4)?));
+        Some(if little_endian {
+            b << 32 | a
+        } else {
+            a << 32 | b
+        })
+    };
+
+    if data.is_empty() {
+        return Some(HashMap::new());
+    }
+    // DWARF 5 has a 2 byte version and 2 bytes padding, GNU a 4 byte version
+    let version = if little_endian {
+        u32_at(0)? & 0xffff
+    } else {
+        u32_at(0)? >> 16
+    };
+    let version = if version == 0 { u32_at(0)? } else { version };
+    if version != 2 && version != 5 {
+        return None;
+    }
+    let columns = u32_at(4)? as usize;
+    let units = u32_at(8)? as usize;
+    let slots = u32_at(12)? as usize;
+
+    let hashes = 16;
+    let rows = hashes + slots * 8;
+    let ids = rows + slots * 4;
+    let offsets = ids + columns * 4;
+    let sizes = offsets + units * columns * 4;
+
+    let mut index = HashMap::new();
+    for slot in 0..slots {
+        let row = u32_at(rows + slot * 4)? as usize;
+        if row == 0 {
+            continue;
+        }
+        if row > units {
+            return None;
+        }
+
+        let contributions = (0..columns)
+            .map(|col| {
+                let cell = ((row - 1) * columns + col) * 4;
+                Some(Contribution {
+                    section: u32_at(ids + col * 4)?,
+                    offset: u32_at(offsets + cell)? as usize,
+                    size: u32_at(sizes + cell)? as usize,
+                })
+            })
+            .collect::<Option<_>>()?;
+        let _ = index.insert(u64_at(hashes + slot * 8)?, contributions);
+    }
+
+    Some(index)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn cu_index() {
+        let words = |words: &[u32]| -> Vec<u8> {
+            words
+                .iter()
+                .flat_map(|w| w.to_le_bytes().to_vec())
+                .collect()
+        };
+
+        // Two units with INFO and ABBREV columns, in a table of 4 slots
+        let mut data = words(&[2, 2, 2, 4]);
+        for id in &[0u64, 0x1111_2222_3333_4444, 0, 0x5555_6666_7777_8888] {
+            data.extend_from_slice(&id.to_le_bytes());
+        }
+        data.extend(words(&[0, 2, 0, 1]));
+        data.extend(words(&[DW_SECT_INFO, DW_SECT_ABBREV]));
+        data.extend(words(&[0, 0, 100, 50]));
+        data.extend(words(&[100, 50, 80, 30]));
+
+        let index = parse_cu_index(&data, true).unwrap();
+        assert_eq!(index.len(), 2);
+        assert_eq!(
+            index[&0x5555_6666_7777_8888],
+            vec![
+                Contribution {
+                    section: DW_SECT_INFO,
+                    offset: 0,
+                    size: 100
+                },
+                Contribution {
+                    section: DW_SECT_ABBREV,
+                    offset: 0,
+                    size: 50
+                },
+            ]
+        );
+        assert_eq!(index[&0x1111_2222_3333_4444][0].offset, 100);
+        assert_eq!(index[&0x1111_2222_3333_4444][1].size, 30);
+
+        // Truncated
+        assert_eq!(parse_cu_index(&data[..data.len() - 4], true), None);
+        assert_eq!(parse_cu_index(&[], true), Some(HashMap::new()));
+    }
+}
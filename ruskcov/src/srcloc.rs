@@ -1,12 +1,19 @@
 //! Locations in source code
 
-use inject_types::{BreakpointInst, ObjectInfo, SetBreakpointsReq, SetBreakpointsResp, SOCKET_ENV};
 use internment::Intern;
+use once_cell::sync::Lazy;
 use std::{
+    collections::HashMap,
     ops::Deref,
-    path::{Path, PathBuf},
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+    sync::RwLock,
 };
 
+/// Each path canonicalized so far, since there's a lookup for every line table row and
+/// inlined call site
+static CANONICAL: Lazy<RwLock<HashMap<SrcPath, SrcPath>>> = Lazy::new(Default::default);
+
 /// Information about a breakpoint address. Does not contain the address itself,
 /// on the assumption that its the key of some mapping structure.
 #[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
@@ -15,41 +22,209 @@ pub struct Location {
     srcpath: SrcPath,
     // Line number
     line: u32,
-    // Replaced instruction when breakpoint set
-    replaced: Option<BreakpointInst>,
 }
 
 impl Location {
     pub fn new(srcpath: SrcPath, line: u32) -> Self {
-        Location {
+        Location { srcpath, line }
+    }
+
+    pub fn srcpath(&self) -> PathBuf {
+        self.srcpath.to_pathbuf()
+    }
+
+    pub fn file(&self) -> SrcPath {
+        self.srcpath
+    }
+
+    pub fn line(&self) -> u32 {
+        self.line
+    }
+}
+
+/// One arm of a conditional branch. Branches are numbered in address order within each
+/// line of the object they're in, and arm 0 is the branch taken, arm 1 not taken. The same
+/// number on the same line in another object is a different branch.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct BranchArm {
+    object: Intern<PathBuf>,
+    srcpath: SrcPath,
+    line: u32,
+    branch: u32,
+    arm: u32,
+}
+
+impl BranchArm {
+    pub fn new(object: &Path, srcpath: SrcPath, line: u32, branch: u32, arm: u32) -> Self {
+        BranchArm {
+            object: Intern::new(object.to_path_buf()),
             srcpath,
             line,
-            replaced: None,
+            branch,
+            arm,
         }
     }
 
-    pub fn srcpath(&self) -> PathBuf {
-        self.srcpath.to_pathbuf()
+    /// Path of the object the branch is in
+    pub fn object(&self) -> &Path {
+        &self.object
+    }
+
+    pub fn file(&self) -> SrcPath {
+        self.srcpath
     }
 
     pub fn line(&self) -> u32 {
         self.line
     }
+
+    pub fn branch(&self) -> u32 {
+        self.branch
+    }
+
+    pub fn arm(&self) -> u32 {
+        self.arm
+    }
 }
 
+/// A function, identified by where it's declared and its name. Functions are grouped by
+/// crate, which is the outermost namespace for Rust and C++, or the object they're in
+/// otherwise.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct Function {
+    srcpath: SrcPath,
+    line: u32,
+    name: Intern<String>,
+    krate: Intern<String>,
+}
+
+impl Function {
+    pub fn new(srcpath: SrcPath, line: u32, name: &str, krate: &str) -> Self {
+        Function {
+            srcpath,
+            line,
+            name: Intern::new(name.to_string()),
+            krate: Intern::new(krate.to_string()),
+        }
+    }
+
+    pub fn file(&self) -> SrcPath {
+        self.srcpath
+    }
+
+    pub fn line(&self) -> u32 {
+        self.line
+    }
+
+    pub fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    pub fn krate(&self) -> &str {
+        self.krate.as_str()
+    }
+}
+
+/// Path of a source file, split into its directory and file name. Paths are normalized so
+/// the same file has the same `SrcPath` however the debug info names it.
 #[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub struct SrcPath(SrcDir, SrcFile);
 
 impl SrcPath {
-    pub fn new<D: Into<PathBuf>, F: Into<PathBuf>>(dir: D, file: F) -> Self {
+    /// Make a path from a directory and a file name relative to it, which may itself have
+    /// directories.
+    pub fn new<D: AsRef<Path>, F: AsRef<Path>>(dir: D, file: F) -> Self {
+        Self::from_path(&dir.as_ref().join(file))
+    }
+
+    /// Make a path from a full path, which is normalized lexically.
+    pub fn from_path(path: &Path) -> Self {
+        let path = normalize(path);
+        let dir = path.parent().unwrap_or(Path::new(""));
+        let file = path.file_name().map_or(Path::new(""), Path::new);
+
         SrcPath(From::from(dir), From::from(file))
     }
 
-    fn to_pathbuf(&self) -> PathBuf {
+    /// Resolve symlinks in the path, if it exists. The result is remembered, so each path
+    /// is only looked up once.
+    pub fn canonical(self) -> Self {
+        if let Some(&canonical) = CANONICAL.read().unwrap().get(&self) {
+            return canonical;
+        }
+
+        let canonical = match self.to_pathbuf().canonicalize() {
+            Ok(path) => Self::from_path(&path),
+            Err(_) => self,
+        };
+        let _ = CANONICAL.write().unwrap().insert(self, canonical);
+        canonical
+    }
+
+    pub fn to_pathbuf(&self) -> PathBuf {
         self.0.join(&*self.1)
     }
 }
 
+/// Rewrite of a source path prefix, given as `FROM=TO` like rustc's `--remap-path-prefix`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct PathMap {
+    from: PathBuf,
+    to: PathBuf,
+}
+
+impl PathMap {
+    /// Return the rewritten path, if it starts with `from`
+    pub fn apply(&self, path: &Path) -> Option<PathBuf> {
+        let rest = path.strip_prefix(&self.from).ok()?;
+        if rest.as_os_str().is_empty() {
+            Some(self.to.clone())
+        } else {
+            Some(self.to.join(rest))
+        }
+    }
+}
+
+impl FromStr for PathMap {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // Split on the last `=`, as rustc does
+        match s.rfind('=') {
+            Some(idx) if idx > 0 => Ok(PathMap {
+                from: s[..idx].into(),
+                to: s[idx + 1..].into(),
+            }),
+            _ => Err(format!("Path map {} is not FROM=TO", s)),
+        }
+    }
+}
+
+/// Remove `.` components, and `..` components along with the one before them, without
+/// looking at the filesystem. `..` at the start of a relative path is kept, and at the root
+/// is dropped.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normal = PathBuf::new();
+
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => match normal.components().next_back() {
+                Some(Component::Normal(_)) => {
+                    let _ = normal.pop();
+                }
+                Some(Component::RootDir) | Some(Component::Prefix(_)) => {}
+                Some(Component::ParentDir) | Some(Component::CurDir) | None => {
+                    normal.push(component)
+                }
+            },
+            _ => normal.push(component),
+        }
+    }
+
+    normal
+}
+
 #[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
 struct SrcDir(Intern<PathBuf>);
 
@@ -87,3 +262,71 @@ impl Deref for SrcFile {
         self.0.as_path()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn path_map() {
+        let map: PathMap = "/build/xyz=/home/me/proj".parse().unwrap();
+
+        assert_eq!(
+            map.apply(Path::new("/build/xyz/src/lib.rs")),
+            Some(PathBuf::from("/home/me/proj/src/lib.rs"))
+        );
+        assert_eq!(
+            map.apply(Path::new("/build/xyz")),
+            Some(PathBuf::from("/home/me/proj"))
+        );
+        // Prefixes match whole components
+        assert_eq!(map.apply(Path::new("/build/xyzzy/lib.rs")), None);
+
+        let map: PathMap = "/a=b=/c".parse().unwrap();
+        assert_eq!(map.apply(Path::new("/a=b/x")), Some(PathBuf::from("/c/x")));
+
+        assert!("/no/equals".parse::<PathMap>().is_err());
+        assert!("=/to".parse::<PathMap>().is_err());
+    }
+
+    #[test]
+    fn normalize_paths() {
+        let norm = |path: &str| normalize(Path::new(path));
+
+        assert_eq!(norm("/src/../src/./lib.rs"), PathBuf::from("/src/lib.rs"));
+        assert_eq!(norm("/../lib.rs"), PathBuf::from("/lib.rs"));
+        assert_eq!(norm("./a/../../lib.rs"), PathBuf::from("../lib.rs"));
+        assert_eq!(norm("../../lib.rs"), PathBuf::from("../../lib.rs"));
+    }
+
+    #[test]
+    fn same_file() {
+        let lib = SrcPath::new("/src/foo", "lib.rs");
+
+        assert_eq!(SrcPath::new("/src", "foo/lib.rs"), lib);
+        assert_eq!(SrcPath::new("/src/bar/..", "./foo/lib.rs"), lib);
+        assert_eq!(SrcPath::from_path(Path::new("/src/foo/lib.rs")), lib);
+        assert_eq!(lib.to_pathbuf(), PathBuf::from("/src/foo/lib.rs"));
+    }
+
+    #[test]
+    fn canonical() {
+        let dir = tempfile::tempdir().unwrap();
+        let real = dir.path().canonicalize().unwrap().join("real");
+        let link = dir.path().join("link");
+        std::fs::create_dir(&real).unwrap();
+        std::fs::write(real.join("lib.rs"), "").unwrap();
+        std::os::unix::fs::symlink(&real, &link).unwrap();
+
+        let resolved = SrcPath::new(&real, "lib.rs");
+        assert_eq!(SrcPath::new(&link, "lib.rs").canonical(), resolved);
+        assert_eq!(
+            SrcPath::new(&link, "missing.rs").canonical(),
+            SrcPath::new(&link, "missing.rs")
+        );
+
+        // Remembered, so the link isn't looked at again
+        std::fs::remove_file(&link).unwrap();
+        assert_eq!(SrcPath::new(&link, "lib.rs").canonical(), resolved);
+    }
+}
@@ -11,12 +11,14 @@ use alloc::sync::Arc;
 use alloc::vec::Vec;
 
 use std::cmp::Ordering;
+use std::collections::HashMap;
 use std::mem;
+use std::ops::Range;
 use std::u64;
 
 use fallible_iterator::FallibleIterator;
 use intervaltree::{Element, IntervalTree};
-use lazycell::LazyCell;
+use once_cell::sync::OnceCell;
 use smallvec::SmallVec;
 
 use crate::mapped_slice::MappedSlice;
@@ -29,6 +31,7 @@ where
 {
     pub unit_ranges: Vec<(gimli::Range, usize)>,
     units: Vec<ResUnit<R>>,
+    skipped_units: usize,
     pub sections: gimli::Dwarf<R>,
 }
 
@@ -131,13 +134,12 @@ impl Context<gimli::EndianArcSlice<gimli::RunTimeEndian>> {
 }
 
 impl Context<gimli::EndianReader<gimli::RunTimeEndian, MappedSlice>> {
-    /// Construct a context from a mapping. This is zero-copy - all the sections are used out of the mapping.
+    /// Construct a context from a mapping. This is zero-copy - all the sections are used out of the mapping,
+    /// except for compressed sections (`SHF_COMPRESSED` or `.zdebug_*`) which are decompressed into owned buffers.
     pub fn new_from_mapping<'data, 'file, O: object::Object<'data, 'file>>(
         mapping: &'data MappedSlice,
         file: &'file O,
     ) -> Result<Self, Error> {
-        use object::read::ObjectSection;
-
         let endian = if file.is_little_endian() {
             gimli::RunTimeEndian::Little
         } else {
@@ -154,16 +156,8 @@ impl Context<gimli::EndianReader<gimli::RunTimeEndian, MappedSlice>> {
             S: gimli::Section<gimli::EndianReader<Endian, MappedSlice>>,
             Endian: gimli::Endianity,
         {
-            let mapping = if let Some((offset, size)) =
-                file.section_by_name(S::section_name()).and_then(|s| s.file_range())
-            {
-                let offset = offset as usize;
-                let size = size as usize;
-                mapping.subslice(offset..offset + size)
-            } else {
-                mapping.subslice(0..0)
-            };
-            S::from(gimli::EndianReader::new(mapping, endian))
+            let data = section_data(mapping, file, S::section_name());
+            S::from(gimli::EndianReader::new(data, endian))
         }
 
         let debug_abbrev: gimli::DebugAbbrev<_> = map_section(mapping, file, endian);
@@ -192,6 +186,35 @@ impl Context<gimli::EndianReader<gimli::RunTimeEndian, MappedSlice>> {
     }
 }
 
+/// Get the data of a section from the object file's mapping, or an empty slice if it isn't
+/// there. Compressed sections are decompressed into an owned buffer.
+pub fn section_data<'data, 'file, O>(
+    mapping: &MappedSlice,
+    file: &'file O,
+    name: &str,
+) -> MappedSlice
+where
+    O: object::Object<'data, 'file>,
+{
+    use object::read::ObjectSection;
+
+    // This also finds the `.zdebug_*` section if there's no `.debug_*` one
+    let section = file.section_by_name(name);
+    match section
+        .as_ref()
+        .map(|s| (s.uncompressed_data(), s.file_range()))
+    {
+        // Decompressed
+        Some((Cow::Owned(data), _)) => MappedSlice::from(data),
+        Some((Cow::Borrowed(_), Some((offset, size)))) => {
+            let offset = offset as usize;
+            let size = size as usize;
+            mapping.subslice(offset..offset + size)
+        }
+        _ => mapping.subslice(0..0),
+    }
+}
+
 impl<R: gimli::Reader> Context<R> {
     /// Construct a new `Context` from DWARF sections.
     pub fn from_sections(
@@ -228,8 +251,24 @@ impl<R: gimli::Reader> Context<R> {
     pub fn from_dwarf(sections: gimli::Dwarf<R>) -> Result<Self, Error> {
         let mut unit_ranges = Vec::new();
         let mut res_units = Vec::new();
-        let mut units = sections.units();
-        while let Some(header) = units.next()? {
+        let mut skipped_units = 0;
+        // Walk the unit headers by hand rather than with `units()`, which gives up on the
+        // rest of the section at the first unit type gimli can't parse, like the DWARF 5
+        // skeleton and split units from `-gsplit-dwarf`.
+        let debug_info = gimli::Section::reader(&sections.debug_info);
+        let mut input = debug_info.clone();
+        while !input.is_empty() {
+            let offset = gimli::DebugInfoOffset(input.offset_from(debug_info));
+            let (length, _) = input.read_initial_length()?;
+            input.skip(length)?;
+            let header = match sections.debug_info.header_from_offset(offset) {
+                Ok(header) => header,
+                Err(gimli::Error::UnsupportedUnitType) => {
+                    skipped_units += 1;
+                    continue;
+                }
+                Err(err) => return Err(err),
+            };
             let unit_id = res_units.len();
             let dw_unit = match sections.unit(header) {
                 Ok(dw_unit) => dw_unit,
@@ -237,6 +276,7 @@ impl<R: gimli::Reader> Context<R> {
             };
 
             let lang;
+            let skeleton;
             {
                 let mut cursor = dw_unit.entries();
 
@@ -249,6 +289,7 @@ impl<R: gimli::Reader> Context<R> {
                     Some(gimli::AttributeValue::Language(lang)) => Some(lang),
                     _ => None,
                 };
+                skeleton = Skeleton::from_unit(unit, &dw_unit, &sections)?;
                 let mut ranges = sections.unit_ranges(&dw_unit)?;
                 while let Some(range) = ranges.next()? {
                     if range.begin == range.end {
@@ -262,8 +303,10 @@ impl<R: gimli::Reader> Context<R> {
             res_units.push(ResUnit {
                 dw_unit,
                 lang,
-                lines: LazyCell::new(),
-                funcs: LazyCell::new(),
+                skeleton,
+                dwo: None,
+                lines: OnceCell::new(),
+                funcs: OnceCell::new(),
             });
         }
 
@@ -287,6 +330,7 @@ impl<R: gimli::Reader> Context<R> {
 
         Ok(Context {
             units: res_units,
+            skipped_units,
             unit_ranges,
             sections,
         })
@@ -296,6 +340,75 @@ impl<R: gimli::Reader> Context<R> {
         self.units.iter().map(|r| &r.dw_unit).collect()
     }
 
+    /// The number of units that were skipped because gimli doesn't support their unit type.
+    pub fn skipped_units(&self) -> usize {
+        self.skipped_units
+    }
+
+    /// Return the GNU split DWARF skeleton units whose split units haven't been added yet,
+    /// by unit index.
+    pub fn skeletons(&self) -> Vec<(usize, &Skeleton)> {
+        self.units
+            .iter()
+            .enumerate()
+            .filter(|(_, unit)| unit.dwo.is_none())
+            .filter_map(|(idx, unit)| unit.skeleton.as_ref().map(|skel| (idx, skel)))
+            .collect()
+    }
+
+    /// Add the split unit for a skeleton unit from the sections of its `.dwo` file, or its
+    /// contribution to a `.dwp` package. These have the DIEs, but the line table, addresses
+    /// and ranges are in the skeleton's object. Returns false if the split unit's id doesn't
+    /// match the skeleton.
+    pub fn add_split_unit(&mut self, unit_id: usize, dwo: gimli::Dwarf<R>) -> Result<bool, Error> {
+        let unit = &mut self.units[unit_id];
+        let (dwo_id, ranges_base) = match &unit.skeleton {
+            Some(skeleton) => (skeleton.dwo_id, skeleton.ranges_base),
+            None => return Ok(false),
+        };
+
+        let mut sections = dwo;
+        sections.debug_addr = self.sections.debug_addr.clone();
+        sections.ranges = self.sections.ranges.clone();
+
+        let header = match sections.units().next()? {
+            Some(header) => header,
+            None => return Ok(false),
+        };
+        let mut dw_unit = gimli::Unit::new(&sections, header)?;
+
+        let (split_id, lang) = {
+            let mut cursor = dw_unit.entries();
+            let root = match cursor.next_dfs()? {
+                Some((_, root)) if root.tag() == gimli::DW_TAG_compile_unit => root,
+                _ => return Ok(false),
+            };
+            let split_id = root
+                .attr(gimli::DW_AT_GNU_dwo_id)?
+                .and_then(|attr| attr.udata_value());
+            let lang = match root.attr_value(gimli::DW_AT_language)? {
+                Some(gimli::AttributeValue::Language(lang)) => Some(lang),
+                _ => None,
+            };
+            (split_id, lang)
+        };
+        if split_id != Some(dwo_id) {
+            return Ok(false);
+        }
+
+        dw_unit.addr_base = unit.dw_unit.addr_base;
+        dw_unit.low_pc = unit.dw_unit.low_pc;
+        dw_unit.line_program = unit.dw_unit.line_program.clone();
+
+        unit.lang = unit.lang.or(lang);
+        unit.dwo = Some(DwoUnit {
+            ranges_base,
+            sections,
+            dw_unit,
+        });
+        Ok(true)
+    }
+
     fn find_unit(&self, probe: u64) -> Option<usize> {
         let idx = self.unit_ranges.binary_search_by(|r| {
             if probe < r.0.begin {
@@ -339,7 +452,7 @@ impl<R: gimli::Reader> Context<R> {
                 let loc = unit.find_location(probe, &self.sections)?;
                 let funcs = unit.parse_functions(&self.sections)?;
                 let mut res: SmallVec<[_; 16]> =
-                    funcs.query_point(probe).map(|x| &x.value).collect();
+                    funcs.tree.query_point(probe).map(|x| &x.value).collect();
                 res.sort_by_key(|x| -x.depth);
                 (unit_id, loc, res)
             }
@@ -355,6 +468,92 @@ impl<R: gimli::Reader> Context<R> {
         })
     }
 
+    /// Return the code ranges of all out-of-line functions, sorted by address.
+    pub fn function_ranges(&self) -> Result<Vec<Range<u64>>, Error> {
+        let mut ranges = Vec::new();
+        for unit_id in 0..self.units.len() {
+            ranges.extend(self.unit_function_ranges(unit_id)?);
+        }
+        ranges.sort_by_key(|range| range.start);
+        Ok(ranges)
+    }
+
+    /// Return the code ranges of the out-of-line functions in the unit with index
+    /// `unit_id`, sorted by address.
+    pub fn unit_function_ranges(&self, unit_id: usize) -> Result<Vec<Range<u64>>, Error> {
+        let funcs = self.units[unit_id].parse_functions(&self.sections)?;
+        let mut ranges: Vec<_> = funcs
+            .subprograms
+            .iter()
+            .map(|(range, _)| range.clone())
+            .collect();
+        ranges.sort_by_key(|range| range.start);
+        Ok(ranges)
+    }
+
+    /// Return all out-of-line functions with code, in address order.
+    pub fn functions(&self) -> Result<Vec<Function<'_, R>>, Error> {
+        let mut functions = Vec::new();
+        for unit_id in 0..self.units.len() {
+            functions.extend(self.unit_functions(unit_id)?);
+        }
+        functions.sort_by_key(|func| func.entry);
+        Ok(functions)
+    }
+
+    /// Return the out-of-line functions with code in the unit with index `unit_id`, in
+    /// address order.
+    pub fn unit_functions(&self, unit_id: usize) -> Result<Vec<Function<'_, R>>, Error> {
+        let mut functions = Vec::new();
+
+        let unit = &self.units[unit_id];
+        let funcs = unit.parse_functions(&self.sections)?;
+        let lines = unit.parse_lines(&self.sections)?;
+
+        for sub in &funcs.entries {
+            let mut cursor = unit
+                .dies(&self.sections)
+                .1
+                .entries_at_offset(sub.entry_off)?;
+            let (_, entry) = cursor
+                .next_dfs()?
+                .expect("DIE we read a while ago is no longer readable??");
+
+            // Set an arbitrary recursion limit of 16
+            let name = name_attr(entry, unit, &self.sections, &self.units, 16)?;
+            let decl = decl_attrs(entry, unit, &self.sections, 16)?;
+
+            let file = match (decl.file, lines) {
+                (Some(fi), Some(lines)) => lines.files.get(fi as usize).and_then(Option::as_deref),
+                _ => None,
+            };
+
+            functions.push(Function {
+                name: name.map(|name| FunctionName {
+                    name,
+                    language: unit.lang,
+                }),
+                qualified_name: decl
+                    .name
+                    .map(|name| -> Result<_, Error> {
+                        Ok(qualify(&sub.scope, &name.to_string_lossy()?))
+                    })
+                    .transpose()?,
+                scope: &sub.scope,
+                decl: Location {
+                    file,
+                    line: decl.line,
+                    column: None,
+                },
+                entry: sub.entry,
+                ranges: &sub.ranges,
+            });
+        }
+
+        functions.sort_by_key(|func| func.entry);
+        Ok(functions)
+    }
+
     /// Initialize all line data structures. This is used for benchmarks.
     #[doc(hidden)]
     pub fn parse_lines(&self) -> Result<(), Error> {
@@ -375,7 +574,7 @@ impl<R: gimli::Reader> Context<R> {
 }
 
 struct Lines {
-    files: Vec<String>,
+    files: Vec<Option<String>>,
     sequences: Vec<LineSequence>,
 }
 
@@ -397,27 +596,61 @@ struct Func<T> {
     depth: isize,
 }
 
+struct Functions<T> {
+    /// Subprograms and inlined subroutines by address range
+    tree: IntervalTree<u64, Func<T>>,
+    /// Code ranges of out-of-line subprograms
+    subprograms: Vec<(Range<u64>, gimli::UnitOffset<T>)>,
+    /// Out-of-line subprograms with code, one per DIE
+    entries: Vec<SubprogramEntry<T>>,
+}
+
+struct SubprogramEntry<T> {
+    entry_off: gimli::UnitOffset<T>,
+    /// Lowest address of the subprogram's code
+    entry: u64,
+    /// Code ranges of the subprogram
+    ranges: Vec<Range<u64>>,
+    /// Enclosing namespaces and types, joined with `::`
+    scope: String,
+}
+
 struct ResUnit<R>
 where
     R: gimli::Reader,
 {
     dw_unit: gimli::Unit<R>,
     lang: Option<gimli::DwLang>,
-    lines: LazyCell<Result<Lines, Error>>,
-    funcs: LazyCell<Result<IntervalTree<u64, Func<R::Offset>>, Error>>,
+    /// Split DWARF skeleton attributes, if this is a skeleton unit
+    skeleton: Option<Skeleton>,
+    /// Split unit with the DIEs for a skeleton unit, once it's been loaded
+    dwo: Option<DwoUnit<R>>,
+    lines: OnceCell<Result<Lines, Error>>,
+    funcs: OnceCell<Result<Functions<R::Offset>, Error>>,
 }
 
 impl<R> ResUnit<R>
 where
     R: gimli::Reader,
 {
+    /// Sections and unit of this unit's DIEs, which are in the split unit for a skeleton
+    fn dies<'a>(
+        &'a self,
+        sections: &'a gimli::Dwarf<R>,
+    ) -> (&'a gimli::Dwarf<R>, &'a gimli::Unit<R>) {
+        match &self.dwo {
+            Some(dwo) => (&dwo.sections, &dwo.dw_unit),
+            None => (sections, &self.dw_unit),
+        }
+    }
+
     fn parse_lines(&self, sections: &gimli::Dwarf<R>) -> Result<Option<&Lines>, Error> {
         let ilnp = match self.dw_unit.line_program {
             Some(ref ilnp) => ilnp,
             None => return Ok(None),
         };
         self.lines
-            .borrow_with(|| {
+            .get_or_init(|| {
                 let mut sequences = Vec::new();
                 let mut sequence_rows = Vec::<LineRow>::new();
                 let mut rows = ilnp.clone().rows();
@@ -460,13 +693,7 @@ where
                 }
                 sequences.sort_by_key(|x| x.start);
 
-                let mut files = Vec::new();
-                let mut index = 0;
-                let header = ilnp.header();
-                while let Some(file) = header.file(index) {
-                    files.push(self.render_file(file, header, sections)?);
-                    index += 1;
-                }
+                let files = line_files(sections, &self.dw_unit)?;
 
                 Ok(Lines { files, sequences })
             })
@@ -475,27 +702,66 @@ where
             .map_err(Error::clone)
     }
 
-    fn parse_functions(
-        &self,
-        sections: &gimli::Dwarf<R>,
-    ) -> Result<&IntervalTree<u64, Func<R::Offset>>, Error> {
+    fn parse_functions(&self, sections: &gimli::Dwarf<R>) -> Result<&Functions<R::Offset>, Error> {
         self.funcs
-            .borrow_with(|| {
+            .get_or_init(|| {
                 let mut results = Vec::new();
+                let mut subprograms = Vec::new();
+                let mut entries = Vec::new();
+                // Enclosing scopes as (depth, qualified name)
+                let mut scopes: Vec<(isize, String)> = Vec::new();
+                // Scope of each subprogram DIE, and the DIE it refers to for its declaration
+                let mut subprogram_scopes = HashMap::new();
+                let mut origins = HashMap::new();
                 let mut depth = 0;
-                let mut cursor = self.dw_unit.entries();
+                let (sections, dw_unit) = self.dies(sections);
+                let ranges_base = self.dwo.as_ref().map(|dwo| dwo.ranges_base);
+                let mut cursor = dw_unit.entries();
                 while let Some((d, entry)) = cursor.next_dfs()? {
                     depth += d;
+                    while scopes.last().map_or(false, |&(d, _)| d >= depth) {
+                        let _ = scopes.pop();
+                    }
+                    let scope = scopes.last().map_or("", |(_, scope)| scope.as_str());
+
                     match entry.tag() {
+                        gimli::DW_TAG_namespace
+                        | gimli::DW_TAG_structure_type
+                        | gimli::DW_TAG_class_type
+                        | gimli::DW_TAG_union_type
+                        | gimli::DW_TAG_enumeration_type => {
+                            if let Some(attr) = entry.attr_value(gimli::DW_AT_name)? {
+                                let name = sections.attr_string(dw_unit, attr)?;
+                                let name = qualify(scope, &name.to_string_lossy()?);
+                                scopes.push((depth, name));
+                            }
+                        }
                         gimli::DW_TAG_subprogram | gimli::DW_TAG_inlined_subroutine => {
-                            let mut ranges = sections.die_ranges(&self.dw_unit, entry)?;
-                            while let Some(range) = ranges.next()? {
+                            if entry.tag() == gimli::DW_TAG_subprogram {
+                                if !scope.is_empty() {
+                                    let _ =
+                                        subprogram_scopes.insert(entry.offset(), scope.to_string());
+                                }
+                                let origin = entry
+                                    .attr_value(gimli::DW_AT_specification)?
+                                    .or(entry.attr_value(gimli::DW_AT_abstract_origin)?);
+                                if let Some(gimli::AttributeValue::UnitRef(origin)) = origin {
+                                    let _ = origins.insert(entry.offset(), origin);
+                                }
+                            }
+
+                            let mut own_ranges = Vec::new();
+                            for range in die_ranges(sections, dw_unit, entry, ranges_base)? {
                                 // Ignore invalid DWARF so that a query of 0 does not give
                                 // a long list of matches.
                                 // TODO: don't ignore if there is a section at this address
                                 if range.begin == 0 {
                                     continue;
                                 }
+                                if entry.tag() == gimli::DW_TAG_subprogram {
+                                    subprograms.push((range.begin..range.end, entry.offset()));
+                                    own_ranges.push(range.begin..range.end);
+                                }
                                 results.push(Element {
                                     range: range.begin..range.end,
                                     value: Func {
@@ -504,13 +770,47 @@ where
                                     },
                                 });
                             }
+                            let entry_pc = if own_ranges.is_empty() {
+                                None
+                            } else {
+                                die_entry_pc(sections, dw_unit, entry, &own_ranges)?
+                            };
+                            if let Some(entry_addr) = entry_pc {
+                                entries.push(SubprogramEntry {
+                                    entry_off: entry.offset(),
+                                    entry: entry_addr,
+                                    ranges: own_ranges,
+                                    scope: scope.to_string(),
+                                });
+                            }
                         }
                         _ => (),
                     }
                 }
 
+                // Definitions are often outside the scope they're declared in (ie C++
+                // methods and namespaced functions), so take the scope of the declaration.
+                for sub in entries.iter_mut().filter(|sub| sub.scope.is_empty()) {
+                    let mut off = sub.entry_off;
+                    // Arbitrary recursion limit of 16
+                    for _ in 0..16 {
+                        off = match origins.get(&off) {
+                            Some(&origin) => origin,
+                            None => break,
+                        };
+                        if let Some(scope) = subprogram_scopes.get(&off) {
+                            sub.scope.clone_from(scope);
+                            break;
+                        }
+                    }
+                }
+
                 let tree: IntervalTree<_, _> = results.into_iter().collect();
-                Ok(tree)
+                Ok(Functions {
+                    tree,
+                    subprograms,
+                    entries,
+                })
             })
             .as_ref()
             .map_err(Error::clone)
@@ -551,50 +851,236 @@ where
         };
         let row = &sequence.rows[idx];
 
-        let file = lines.files.get(row.file_index as usize).map(String::as_str);
+        let file = lines
+            .files
+            .get(row.file_index as usize)
+            .and_then(Option::as_deref);
         Ok(Some(Location {
             file,
             line: row.line,
             column: row.column,
         }))
     }
+}
 
-    fn render_file(
-        &self,
-        file: &gimli::FileEntry<R, R::Offset>,
-        header: &gimli::LineProgramHeader<R, R::Offset>,
+/// Attributes of a GNU split DWARF skeleton unit, whose DIEs are in a split unit in a
+/// separate `.dwo` file or `.dwp` package.
+#[derive(Debug, Clone)]
+pub struct Skeleton {
+    /// Id of the split unit
+    pub dwo_id: u64,
+    /// Path of the `.dwo` file, relative to `comp_dir`
+    pub dwo_name: Option<String>,
+    pub comp_dir: Option<String>,
+    /// Base of the split unit's range list offsets
+    ranges_base: usize,
+}
+
+impl Skeleton {
+    fn from_unit<R: gimli::Reader>(
+        root: &gimli::DebuggingInformationEntry<R, R::Offset>,
+        dw_unit: &gimli::Unit<R>,
         sections: &gimli::Dwarf<R>,
-    ) -> Result<String, gimli::Error> {
-        let mut path = if let Some(ref comp_dir) = self.dw_unit.comp_dir {
-            comp_dir.to_string_lossy()?.into_owned()
-        } else {
-            String::new()
+    ) -> Result<Option<Self>, Error> {
+        let dwo_id = match root.attr(gimli::DW_AT_GNU_dwo_id)? {
+            Some(attr) => match attr.udata_value() {
+                Some(dwo_id) => dwo_id,
+                None => return Ok(None),
+            },
+            None => return Ok(None),
         };
+        let string = |value| -> Result<String, Error> {
+            Ok(sections
+                .attr_string(dw_unit, value)?
+                .to_string_lossy()?
+                .into_owned())
+        };
+        let dwo_name = root
+            .attr_value(gimli::DW_AT_GNU_dwo_name)?
+            .or(root.attr_value(gimli::DW_AT_dwo_name)?)
+            .map(string)
+            .transpose()?;
+        let comp_dir = dw_unit
+            .comp_dir
+            .as_ref()
+            .map(|dir| dir.to_string_lossy().map(|dir| dir.into_owned()))
+            .transpose()?;
+        let ranges_base = root
+            .attr(gimli::DW_AT_GNU_ranges_base)?
+            .and_then(|attr| attr.value().offset_value())
+            .map_or(0, |base| gimli::ReaderOffset::into_u64(base) as usize);
+
+        Ok(Some(Skeleton {
+            dwo_id,
+            dwo_name,
+            comp_dir,
+            ranges_base,
+        }))
+    }
+}
+
+/// Split unit for a skeleton unit
+struct DwoUnit<R: gimli::Reader> {
+    /// The split unit's sections, with the skeleton's object's addresses and ranges
+    sections: gimli::Dwarf<R>,
+    dw_unit: gimli::Unit<R>,
+    ranges_base: usize,
+}
 
-        if let Some(directory) = file.directory(header) {
-            path_push(
-                &mut path,
-                sections
-                    .attr_string(&self.dw_unit, directory)?
-                    .to_string_lossy()?
-                    .as_ref(),
-            );
+/// Get the address ranges of a DIE. Unlike `gimli::Dwarf::die_ranges`, this resolves indexed
+/// addresses, which split units use, and adds a skeleton's `ranges_base` to range list
+/// offsets.
+fn die_ranges<R: gimli::Reader>(
+    sections: &gimli::Dwarf<R>,
+    dw_unit: &gimli::Unit<R>,
+    entry: &gimli::DebuggingInformationEntry<R, R::Offset>,
+    ranges_base: Option<usize>,
+) -> Result<Vec<gimli::Range>, Error> {
+    let address = |value| die_address(sections, dw_unit, value);
+
+    let mut ranges = Vec::new();
+    let mut low_pc = None;
+    let mut high_pc = None;
+    let mut size = None;
+    let mut attrs = entry.attrs();
+    while let Some(attr) = attrs.next()? {
+        match attr.name() {
+            gimli::DW_AT_low_pc => low_pc = address(attr.value())?,
+            gimli::DW_AT_high_pc => match attr.value() {
+                gimli::AttributeValue::Udata(val) => size = Some(val),
+                value => high_pc = address(value)?,
+            },
+            gimli::DW_AT_ranges => {
+                let value = match (attr.value(), ranges_base) {
+                    (gimli::AttributeValue::RangeListsRef(offset), Some(base)) => {
+                        gimli::AttributeValue::RangeListsRef(gimli::RangeListsOffset(
+                            offset.0 + gimli::ReaderOffset::from_u64(base as u64)?,
+                        ))
+                    }
+                    (value, _) => value,
+                };
+                if let Some(mut iter) = sections.attr_ranges(dw_unit, value)? {
+                    while let Some(range) = iter.next()? {
+                        ranges.push(range);
+                    }
+                }
+            }
+            _ => {}
         }
+    }
 
-        path_push(
-            &mut path,
-            sections
-                .attr_string(&self.dw_unit, file.path_name())?
-                .to_string_lossy()?
-                .as_ref(),
-        );
+    let high_pc = high_pc.or_else(|| Some(low_pc? + size?));
+    if let (Some(begin), Some(end)) = (low_pc, high_pc) {
+        ranges.push(gimli::Range { begin, end });
+    }
+    Ok(ranges)
+}
+
+/// Get the entry address of a subprogram DIE with the given ranges: its `DW_AT_entry_pc`,
+/// else its `DW_AT_low_pc`, else the start of the first of its `DW_AT_ranges`. The lowest
+/// address isn't necessarily the entry, since the cold part of a function split into hot
+/// and cold parts is often placed before it.
+fn die_entry_pc<R: gimli::Reader>(
+    sections: &gimli::Dwarf<R>,
+    dw_unit: &gimli::Unit<R>,
+    entry: &gimli::DebuggingInformationEntry<R, R::Offset>,
+    ranges: &[Range<u64>],
+) -> Result<Option<u64>, Error> {
+    let low_pc = match entry.attr_value(gimli::DW_AT_low_pc)? {
+        Some(value) => die_address(sections, dw_unit, value)?,
+        None => None,
+    };
+    let base = low_pc.or_else(|| ranges.first().map(|range| range.start));
+    let entry_pc = match entry.attr_value(gimli::DW_AT_entry_pc)? {
+        // DWARF 5 allows an offset from the base address
+        Some(value) if value.udata_value().is_some() => {
+            base.and_then(|base| Some(base + value.udata_value()?))
+        }
+        Some(value) => die_address(sections, dw_unit, value)?,
+        None => None,
+    };
+    Ok(entry_pc.or(base))
+}
+
+/// Get an address attribute's value, or `None` if it isn't an address
+fn die_address<R: gimli::Reader>(
+    sections: &gimli::Dwarf<R>,
+    dw_unit: &gimli::Unit<R>,
+    value: gimli::AttributeValue<R>,
+) -> Result<Option<u64>, gimli::Error> {
+    match value {
+        gimli::AttributeValue::Addr(addr) => Ok(Some(addr)),
+        gimli::AttributeValue::DebugAddrIndex(index) => sections.address(dw_unit, index).map(Some),
+        _ => Ok(None),
+    }
+}
+
+/// Get the paths of the files in a unit's line program, indexed by file number, with `None`
+/// for numbers which don't name a file.
+///
+/// Before DWARF 5 file numbers start at 1 and directory 0 is the unit's `comp_dir`. From
+/// DWARF 5 both start at 0, with entry 0 describing the unit itself, and names are usually
+/// `DW_FORM_line_strp` references into `.debug_line_str`.
+pub fn line_files<R: gimli::Reader>(
+    sections: &gimli::Dwarf<R>,
+    dw_unit: &gimli::Unit<R>,
+) -> Result<Vec<Option<String>>, Error> {
+    let header = match &dw_unit.line_program {
+        Some(ilnp) => ilnp.header(),
+        None => return Ok(Vec::new()),
+    };
+    let string = |attr| -> Result<String, Error> {
+        Ok(sections
+            .attr_string(dw_unit, attr)?
+            .to_string_lossy()?
+            .into_owned())
+    };
+    let comp_dir = match &dw_unit.comp_dir {
+        Some(dir) => dir.to_string_lossy()?.into_owned(),
+        None => String::new(),
+    };
+    let dwarf5 = header.version() >= 5;
+
+    let mut dirs = Vec::new();
+    if !dwarf5 {
+        dirs.push(comp_dir.clone());
+    }
+    for dir in header.include_directories() {
+        let mut path = comp_dir.clone();
+        path_push(&mut path, &string(dir.clone())?);
+        dirs.push(path);
+    }
+
+    let mut files = Vec::new();
+    if !dwarf5 {
+        files.push(None);
+    }
+    for file in header.file_names() {
+        let file = match dirs.get(file.directory_index() as usize) {
+            Some(dir) => {
+                let mut path = dir.clone();
+                path_push(&mut path, &string(file.path_name())?);
+                Some(path)
+            }
+            None => None,
+        };
+        files.push(file);
+    }
+
+    Ok(files)
+}
 
-        Ok(path)
+/// Join a name onto its enclosing scope
+fn qualify(scope: &str, name: &str) -> String {
+    if scope.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}::{}", scope, name)
     }
 }
 
 fn path_push(path: &mut String, p: &str) {
-    if p.starts_with('/') {
+    if p.starts_with('/') || path.is_empty() {
         *path = p.to_string();
     } else {
         if !path.ends_with('/') {
@@ -617,19 +1103,20 @@ where
     if recursion_limit == 0 {
         return Ok(None);
     }
+    let (sections, dw_unit) = unit.dies(sections);
 
     if let Some(attr) = entry.attr_value(gimli::DW_AT_linkage_name)? {
-        if let Ok(val) = sections.attr_string(&unit.dw_unit, attr) {
+        if let Ok(val) = sections.attr_string(dw_unit, attr) {
             return Ok(Some(val));
         }
     }
     if let Some(attr) = entry.attr_value(gimli::DW_AT_MIPS_linkage_name)? {
-        if let Ok(val) = sections.attr_string(&unit.dw_unit, attr) {
+        if let Ok(val) = sections.attr_string(dw_unit, attr) {
             return Ok(Some(val));
         }
     }
     if let Some(attr) = entry.attr_value(gimli::DW_AT_name)? {
-        if let Ok(val) = sections.attr_string(&unit.dw_unit, attr) {
+        if let Ok(val) = sections.attr_string(dw_unit, attr) {
             return Ok(Some(val));
         }
     }
@@ -639,7 +1126,7 @@ where
         .or(entry.attr_value(gimli::DW_AT_specification)?);
     match next {
         Some(gimli::AttributeValue::UnitRef(offset)) => {
-            let mut entries = unit.dw_unit.entries_at_offset(offset)?;
+            let mut entries = dw_unit.entries_at_offset(offset)?;
             if let Some((_, entry)) = entries.next_dfs()? {
                 return name_attr(entry, unit, sections, units, recursion_limit - 1);
             } else {
@@ -670,6 +1157,61 @@ where
     Ok(None)
 }
 
+/// Declaration attributes of a subprogram
+struct Decl<R> {
+    name: Option<R>,
+    file: Option<u64>,
+    line: Option<u64>,
+}
+
+/// Get the declared name, file and line of a subprogram, following its abstract origin or
+/// specification within the unit for any which are missing.
+fn decl_attrs<'abbrev, 'unit, R>(
+    entry: &gimli::DebuggingInformationEntry<'abbrev, 'unit, R, R::Offset>,
+    unit: &ResUnit<R>,
+    sections: &gimli::Dwarf<R>,
+    recursion_limit: usize,
+) -> Result<Decl<R>, Error>
+where
+    R: gimli::Reader,
+{
+    let (sections, dw_unit) = unit.dies(sections);
+    let mut decl = Decl {
+        name: None,
+        file: None,
+        line: None,
+    };
+
+    if let Some(attr) = entry.attr_value(gimli::DW_AT_name)? {
+        decl.name = sections.attr_string(dw_unit, attr).ok();
+    }
+    if let Some(gimli::AttributeValue::FileIndex(fi)) = entry.attr_value(gimli::DW_AT_decl_file)? {
+        decl.file = Some(fi);
+    }
+    decl.line = entry
+        .attr(gimli::DW_AT_decl_line)?
+        .and_then(|x| x.udata_value());
+
+    if recursion_limit == 0 || (decl.name.is_some() && decl.file.is_some() && decl.line.is_some()) {
+        return Ok(decl);
+    }
+
+    let next = entry
+        .attr_value(gimli::DW_AT_abstract_origin)?
+        .or(entry.attr_value(gimli::DW_AT_specification)?);
+    if let Some(gimli::AttributeValue::UnitRef(offset)) = next {
+        let mut entries = dw_unit.entries_at_offset(offset)?;
+        if let Some((_, entry)) = entries.next_dfs()? {
+            let origin = decl_attrs(entry, unit, sections, recursion_limit - 1)?;
+            decl.name = decl.name.or(origin.name);
+            decl.file = decl.file.or(origin.file);
+            decl.line = decl.line.or(origin.line);
+        }
+    }
+
+    Ok(decl)
+}
+
 /// An iterator over function frames.
 pub struct FrameIter<'ctx, R>
 where
@@ -701,7 +1243,10 @@ where
 
         let unit = &self.units[self.unit_id];
 
-        let mut cursor = unit.dw_unit.entries_at_offset(func.entry_off)?;
+        let mut cursor = unit
+            .dies(self.sections)
+            .1
+            .entries_at_offset(func.entry_off)?;
         let (_, entry) = cursor
             .next_dfs()?
             .expect("DIE we read a while ago is no longer readable??");
@@ -713,7 +1258,7 @@ where
             let file = match entry.attr_value(gimli::DW_AT_call_file)? {
                 Some(gimli::AttributeValue::FileIndex(fi)) => {
                     match unit.parse_lines(self.sections)? {
-                        Some(lines) => lines.files.get(fi as usize).map(String::as_str),
+                        Some(lines) => lines.files.get(fi as usize).and_then(Option::as_deref),
                         None => None,
                     }
                 }
@@ -762,6 +1307,22 @@ pub struct Frame<'ctx, R: gimli::Reader> {
     pub location: Option<Location<'ctx>>,
 }
 
+/// An out-of-line function.
+pub struct Function<'ctx, R: gimli::Reader> {
+    /// The linkage name of the function, if any, otherwise its plain name.
+    pub name: Option<FunctionName<R>>,
+    /// The function's name qualified by its enclosing namespaces and types.
+    pub qualified_name: Option<String>,
+    /// The enclosing namespaces and types, joined with `::`. Empty at the top level.
+    pub scope: &'ctx str,
+    /// The declaration location, from `DW_AT_decl_file` and `DW_AT_decl_line`.
+    pub decl: Location<'ctx>,
+    /// The lowest address of the function's code.
+    pub entry: u64,
+    /// The address ranges of the function's code.
+    pub ranges: &'ctx [Range<u64>],
+}
+
 /// A function name.
 pub struct FunctionName<R: gimli::Reader> {
     /// The name of the function.
@@ -775,6 +1336,46 @@ impl<R: gimli::Reader> FunctionName<R> {
     pub fn raw_name(&self) -> Result<Cow<str>, Error> {
         self.name.to_string_lossy()
     }
+
+    /// The name of this function after demangling (if applicable).
+    pub fn demangle(&self) -> Result<Cow<str>, Error> {
+        self.raw_name()
+            .map(|name| match demangle(&name, self.language) {
+                Some(demangled) => Cow::Owned(demangled),
+                None => name,
+            })
+    }
+}
+
+/// Demangle a symbol name. Rust (legacy or v0) or Itanium C++ demangling is chosen by the
+/// language of the compilation unit, if known, otherwise by the name's prefix. The other
+/// scheme is tried if the first fails, since code from one language can be inlined into
+/// another.
+///
+/// Returns `None` if the name isn't mangled, or demangling failed.
+pub fn demangle(name: &str, language: Option<gimli::DwLang>) -> Option<String> {
+    let rust = || {
+        rustc_demangle::try_demangle(name)
+            .ok()
+            .map(|demangled| format!("{:#}", demangled))
+    };
+    let cpp = || {
+        cpp_demangle::Symbol::new(name)
+            .ok()
+            .and_then(|sym| sym.demangle(&Default::default()).ok())
+    };
+
+    match language {
+        Some(gimli::DW_LANG_Rust) => rust().or_else(cpp),
+        Some(gimli::DW_LANG_C_plus_plus)
+        | Some(gimli::DW_LANG_C_plus_plus_03)
+        | Some(gimli::DW_LANG_C_plus_plus_11)
+        | Some(gimli::DW_LANG_C_plus_plus_14) => cpp().or_else(rust),
+        _ if name.starts_with("_R") => rust(),
+        // Rust legacy mangling is a subset of Itanium
+        _ if name.starts_with("_Z") => rust().or_else(cpp),
+        _ => None,
+    }
 }
 
 /// A source location.
@@ -786,3 +1387,162 @@ pub struct Location<'a> {
     /// The column number.
     pub column: Option<u64>,
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use object::read::{Object, ObjectSection};
+
+    #[test]
+    fn demangle_by_language() {
+        assert_eq!(
+            demangle("_ZN3foo3bar17h05af221e174051e9E", Some(gimli::DW_LANG_Rust)).as_deref(),
+            Some("foo::bar")
+        );
+        assert_eq!(
+            demangle("_ZN3foo3barEi", Some(gimli::DW_LANG_C_plus_plus)).as_deref(),
+            Some("foo::bar(int)")
+        );
+        assert_eq!(demangle("main", Some(gimli::DW_LANG_C99)), None);
+    }
+
+    #[test]
+    fn demangle_by_prefix() {
+        assert_eq!(
+            demangle("_RNvCs1234_7mycrate3foo", None).as_deref(),
+            Some("mycrate::foo")
+        );
+        assert_eq!(
+            demangle("_ZN3foo3bar17h05af221e174051e9E", None).as_deref(),
+            Some("foo::bar")
+        );
+        assert_eq!(demangle("_Z3bazv", None).as_deref(), Some("baz()"));
+        assert_eq!(demangle("baz", None), None);
+    }
+
+    /// Map a fixture built by `testdata/build.sh`
+    fn map_fixture(name: &str) -> MappedSlice {
+        let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("testdata")
+            .join(name);
+        MappedSlice::new(std::fs::File::open(path).unwrap()).unwrap()
+    }
+
+    fn load_fixture(name: &str) -> Context<gimli::EndianReader<gimli::RunTimeEndian, MappedSlice>> {
+        let map = map_fixture(name);
+        let file = object::File::parse(&*map).unwrap();
+        Context::new_from_mapping(&map, &file).unwrap()
+    }
+
+    /// Check the line table and functions of a fixture
+    fn check_fixture(name: &str, files: &[Option<&str>]) {
+        let ctxt = load_fixture(name);
+
+        let units = ctxt.units();
+        assert_eq!(units.len(), 1);
+        let files: Vec<_> = files.iter().map(|file| file.map(String::from)).collect();
+        assert_eq!(line_files(&ctxt.sections, units[0]).unwrap(), files);
+
+        let mut functions: Vec<_> = ctxt
+            .functions()
+            .unwrap()
+            .into_iter()
+            .map(|func| {
+                let name = func.name.unwrap().raw_name().unwrap().into_owned();
+                let loc = ctxt.find_location(func.entry).unwrap().unwrap();
+                assert_eq!(loc.file, func.decl.file);
+                (name, func.decl.file.map(String::from), func.decl.line)
+            })
+            .collect();
+        functions.sort();
+        assert_eq!(
+            functions,
+            vec![
+                ("_start".into(), Some("/fixture/lines.c".into()), Some(3)),
+                ("twice".into(), Some("/fixture/inc/twice.h".into()), Some(1)),
+            ]
+        );
+    }
+
+    #[test]
+    fn dwarf4_lines() {
+        // File numbers are 1-based, and directory 0 is comp_dir
+        check_fixture(
+            "lines-dwarf4",
+            &[None, Some("/fixture/lines.c"), Some("/fixture/inc/twice.h")],
+        );
+    }
+
+    /// Check a copy of `lines-dwarf4` with its debug info compressed into `section`
+    fn check_compressed(name: &str, section: &str) {
+        // objcopy only compresses the sections it can shrink, which here includes .debug_info
+        let map = map_fixture(name);
+        let file = object::File::parse(&*map).unwrap();
+        let debug_info = file.section_by_name(".debug_info").unwrap();
+        assert_eq!(debug_info.name(), Some(section));
+        match debug_info.uncompressed_data() {
+            Cow::Owned(_) => {}
+            Cow::Borrowed(_) => panic!("{} isn't compressed", section),
+        }
+
+        check_fixture(
+            name,
+            &[None, Some("/fixture/lines.c"), Some("/fixture/inc/twice.h")],
+        );
+
+        // Every address has the same location as in the uncompressed original
+        let plain = load_fixture("lines-dwarf4");
+        let compressed = load_fixture(name);
+        let ranges = plain.function_ranges().unwrap();
+        assert_eq!(compressed.function_ranges().unwrap(), ranges);
+        for addr in ranges.into_iter().flatten() {
+            let loc = |ctxt: &Context<_>| {
+                ctxt.find_location(addr)
+                    .unwrap()
+                    .map(|loc| (loc.file.map(String::from), loc.line, loc.column))
+            };
+            assert_eq!(loc(&compressed), loc(&plain), "{:#x}", addr);
+        }
+    }
+
+    #[test]
+    fn compressed_lines() {
+        check_compressed("lines-dwarf4-zlib", ".debug_info");
+    }
+
+    #[test]
+    fn gnu_compressed_lines() {
+        check_compressed("lines-dwarf4-zlib-gnu", ".zdebug_info");
+    }
+
+    #[test]
+    fn dwarf5_skeleton() {
+        // gimli can't parse the DWARF 5 skeleton unit for lines.c, but that mustn't lose the
+        // DWARF 4 unit for other.c after it
+        let ctxt = load_fixture("lines-split-dwarf5");
+        assert_eq!(ctxt.skipped_units(), 1);
+        assert_eq!(ctxt.units().len(), 1);
+        assert!(ctxt.skeletons().is_empty());
+
+        let names: Vec<_> = ctxt
+            .functions()
+            .unwrap()
+            .into_iter()
+            .map(|func| func.name.unwrap().raw_name().unwrap().into_owned())
+            .collect();
+        assert_eq!(names, vec!["other".to_string()]);
+    }
+
+    #[test]
+    fn dwarf5_lines() {
+        // File numbers are 0-based, names are in .debug_line_str, and entries have MD5s
+        check_fixture(
+            "lines-dwarf5",
+            &[
+                Some("/fixture/lines.c"),
+                Some("/fixture/inc/twice.h"),
+                Some("/fixture/lines.c"),
+            ],
+        );
+    }
+}
@@ -0,0 +1,30 @@
+#!/bin/sh
+# Rebuild the line table fixtures from lines.c, as DWARF 4 and DWARF 5. The DWARF 5 one has
+# MD5 checksums added to its file entries, since gcc doesn't emit them itself. Also build a
+# stripped object with a known build-id, for serving from debuginfod, copies of the DWARF 4
+# fixture with compressed debug sections in the SHF_COMPRESSED and the older GNU `.zdebug_*`
+# formats, and lines.c as a DWARF 5 skeleton unit with
+# -gsplit-dwarf linked with other.c as a plain DWARF 4 unit.
+set -e
+cd "$(dirname "$0")"
+
+FLAGS="-O0 -nostdlib -static -Wl,--build-id=none -Wl,-N -Wl,--no-warn-rwx-segments -fdebug-prefix-map=$PWD=/fixture"
+
+gcc -g -gdwarf-4 $FLAGS -o lines-dwarf4 lines.c
+
+md5() { echo "md5 0x$(md5sum "$1" | cut -d' ' -f1)"; }
+gcc -S -g -gdwarf-5 $FLAGS -o - lines.c |
+    sed -e "s|^\t\.file \([02]\) .*|& $(md5 lines.c)|" \
+        -e "s|^\t\.file 1 .*|& $(md5 inc/twice.h)|" > lines-dwarf5.s
+gcc $FLAGS -o lines-dwarf5 lines-dwarf5.s
+rm lines-dwarf5.s
+
+gcc $FLAGS -s -Wl,--build-id=0x0123456789abcdef -o build-id lines.c
+
+objcopy --compress-debug-sections=zlib lines-dwarf4 lines-dwarf4-zlib
+objcopy --compress-debug-sections=zlib-gnu lines-dwarf4 lines-dwarf4-zlib-gnu
+
+gcc -c -g -gdwarf-5 -gsplit-dwarf $FLAGS -o lines-split-dwarf5.o lines.c
+gcc -c -g -gdwarf-4 $FLAGS -o other.o other.c
+gcc $FLAGS -o lines-split-dwarf5 lines-split-dwarf5.o other.o
+rm lines-split-dwarf5.o other.o
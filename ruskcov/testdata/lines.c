@@ -0,0 +1,7 @@
+#include "inc/twice.h"
+
+void _start(void)
+{
+    for (;;)
+        twice(1);
+}
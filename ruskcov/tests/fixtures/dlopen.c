@@ -0,0 +1,13 @@
+#include <dlfcn.h>
+#include <stdio.h>
+
+int main(int argc, char **argv) { // hit
+	void *lib = dlopen(argv[1], RTLD_NOW); // hit
+
+	if (lib == NULL) { // hit
+		printf("%s\n", dlerror()); // miss
+		return 1; // miss
+	}
+	int (*used)(int) = (int (*)(int))dlsym(lib, "plugin_used"); // hit
+	return used(2) == 4 ? 0 : 1; // hit
+} // hit
@@ -0,0 +1,8 @@
+#include <stdio.h>
+#include <unistd.h>
+
+int main(int argc, char **argv) { // hit
+	execv(argv[1], argv + 1); // hit
+	perror("execv"); // miss
+	return 1; // miss
+} // miss
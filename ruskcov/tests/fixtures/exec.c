@@ -0,0 +1,17 @@
+#include <stdio.h>
+#include <string.h>
+#include <unistd.h>
+
+static int after_exec(void) { // hit
+	printf("exec'd\n"); // hit
+	return 0; // hit
+} // hit
+
+int main(int argc, char **argv) { // hit
+	if (argc > 1 && strcmp(argv[1], "again") == 0) // hit
+		return after_exec(); // hit
+
+	execl(argv[0], argv[0], "again", (char *)NULL); // hit
+	perror("execl"); // miss
+	return 1; // miss
+} // hit
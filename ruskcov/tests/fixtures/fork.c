@@ -0,0 +1,31 @@
+#include <stdio.h>
+#include <sys/wait.h>
+#include <unistd.h>
+
+static int child(void) { // hit
+	printf("child\n"); // hit
+	return 3; // hit
+} // hit
+
+static int parent(pid_t pid) { // hit
+	int status;
+
+	waitpid(pid, &status, 0); // hit
+	return WIFEXITED(status) && WEXITSTATUS(status) == 3 ? 0 : 1; // hit
+} // hit
+
+static void never(void) { // miss
+	printf("never\n"); // miss
+} // miss
+
+int main(int argc, char **argv) { // hit
+	pid_t pid = fork(); // hit
+
+	if (pid < 0) { // hit
+		never(); // miss
+		return 2; // miss
+	}
+	if (pid == 0) // hit
+		return child(); // hit
+	return parent(pid); // hit
+} // hit
@@ -0,0 +1,7 @@
+int plugin_used(int x) { // hit
+	return x * 2; // hit
+} // hit
+
+int plugin_unused(int x) { // miss
+	return x + 1; // miss
+} // miss
@@ -0,0 +1,25 @@
+#include <signal.h>
+#include <string.h>
+
+static volatile sig_atomic_t caught;
+
+static void handler(int sig) { // hit
+	caught = sig; // hit
+} // hit
+
+static void unused_handler(int sig) { // miss
+	caught = -sig; // miss
+} // miss
+
+int main(void) { // hit
+	struct sigaction sa;
+
+	memset(&sa, 0, sizeof(sa)); // hit
+	sa.sa_handler = handler; // hit
+	sigaction(SIGUSR1, &sa, NULL); // hit
+	sa.sa_handler = unused_handler; // hit
+	sigaction(SIGUSR2, &sa, NULL); // hit
+
+	raise(SIGUSR1); // hit
+	return caught == SIGUSR1 ? 0 : 1; // hit
+} // hit
@@ -0,0 +1,31 @@
+use std::{env, process::Command, thread};
+
+fn work(id: usize) -> usize { // hit
+    let mut total = 0; // hit
+    for i in 0..1000 { // hit
+        total += i % (id + 1); // hit
+    }
+    total // hit
+} // hit
+
+fn never(total: usize) { // miss
+    println!("never {}", total); // miss
+} // miss
+
+fn main() { // hit
+    if env::args().nth(1).as_deref() == Some("child") { // hit
+        println!("child {}", work(7)); // hit
+        return;
+    }
+
+    let threads: Vec<_> = (0..4).map(|id| thread::spawn(move || work(id))).collect(); // hit
+    let total: usize = threads.into_iter().map(|t| t.join().unwrap()).sum(); // hit
+    if total == 0 { // hit
+        never(total); // miss
+    }
+
+    // Runs this again, so a fork and exec
+    let exe = env::current_exe().unwrap(); // hit
+    let status = Command::new(exe).arg("child").status().unwrap(); // hit
+    assert!(status.success()); // hit
+} // hit
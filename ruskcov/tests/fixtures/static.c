@@ -0,0 +1,17 @@
+#include <stdio.h>
+
+static int square(int x) { // hit
+	return x * x; // hit
+} // hit
+
+static int never(int x) { // miss
+	return x + 1; // miss
+} // miss
+
+int main(int argc, char **argv) { // hit
+	int total = square(argc); // hit
+	if (total > 100) // hit
+		total = never(total); // miss
+	printf("%d\n", total); // hit
+	return 0; // hit
+} // hit
@@ -0,0 +1,36 @@
+#include <pthread.h>
+#include <stdio.h>
+
+#define THREADS 4
+
+static int counts[THREADS];
+
+static void *worker(void *arg) { // hit
+	int id = *(int *)arg; // hit
+
+	for (int i = 0; i < 1000; i++) // hit
+		counts[id]++; // hit
+	if (id >= THREADS) // hit
+		printf("bad id %d\n", id); // miss
+	return NULL; // hit
+} // hit
+
+int main(void) { // hit
+	pthread_t threads[THREADS];
+	int ids[THREADS];
+	int total = 0; // hit
+
+	for (int i = 0; i < THREADS; i++) { // hit
+		ids[i] = i; // hit
+		pthread_create(&threads[i], NULL, worker, &ids[i]); // hit
+	}
+	for (int i = 0; i < THREADS; i++) { // hit
+		pthread_join(threads[i], NULL); // hit
+		total += counts[i]; // hit
+	}
+	if (total != THREADS * 1000) { // hit
+		printf("total %d\n", total); // miss
+		return 1; // miss
+	}
+	return 0; // hit
+} // hit
@@ -0,0 +1,240 @@
+//! Trace small fixture programs and check exactly which of their lines are covered.
+//!
+//! Each fixture in `tests/fixtures` marks the lines which should be covered with `// hit`,
+//! and those which have breakpoints but shouldn't be reached with `// miss`. No other lines
+//! of it may appear in the report. They're built with `cc` and `rustc` without optimization.
+#![cfg(all(target_os = "linux", any(target_arch = "x86", target_arch = "x86_64")))]
+
+use once_cell::sync::Lazy;
+use std::{
+    collections::BTreeMap,
+    env,
+    ffi::OsStr,
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+use tempfile::TempDir;
+
+/// Line counts by line number, for each source file in a report
+type Report = BTreeMap<PathBuf, BTreeMap<u32, u64>>;
+
+/// The inject library. `cargo test` doesn't build cdylibs, so build it next to the tracer.
+static INJECT: Lazy<PathBuf> = Lazy::new(|| {
+    let tracer = Path::new(env!("CARGO_BIN_EXE_ruskcov"));
+    let mut cargo = Command::new(env::var_os("CARGO").unwrap_or_else(|| "cargo".into()));
+    let _ = cargo
+        .args(&["build", "-p", "ruskcov-inject"])
+        .current_dir(env!("CARGO_MANIFEST_DIR"));
+    if tracer
+        .parent()
+        .map_or(false, |dir| dir.ends_with("release"))
+    {
+        let _ = cargo.arg("--release");
+    }
+    run(&mut cargo);
+
+    tracer.with_file_name("libruskcov_inject.so")
+});
+
+fn fixture(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures")
+        .join(name)
+}
+
+/// Run a command, panicking with its output if it fails
+fn run(cmd: &mut Command) {
+    let output = cmd
+        .output()
+        .unwrap_or_else(|err| panic!("Failed to run {:?}: {}", cmd, err));
+    assert!(
+        output.status.success(),
+        "{:?} failed: {}\n{}{}",
+        cmd,
+        output.status,
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+/// Compile C fixtures into `out` in `dir`
+fn cc(dir: &TempDir, out: &str, srcs: &[&str], flags: &[&str]) -> PathBuf {
+    let path = dir.path().join(out);
+    run(Command::new("cc")
+        .args(&["-g", "-O0"])
+        .args(flags)
+        .arg("-o")
+        .arg(&path)
+        .args(srcs.iter().map(|src| fixture(src))));
+    path
+}
+
+/// Compile a Rust fixture into `dir`
+fn rustc(dir: &TempDir, src: &str) -> PathBuf {
+    let path = dir.path().join(Path::new(src).file_stem().unwrap());
+    run(Command::new("rustc")
+        .args(&["-g", "-C", "opt-level=0", "-o"])
+        .arg(&path)
+        .arg(fixture(src)));
+    path
+}
+
+/// Run `exe` under the tracer with extra tracer options `opts`, and return its coverage
+fn trace(exe: &Path, opts: &[&str], args: &[&OsStr]) -> Report {
+    let lcov = exe.with_extension("lcov");
+    run(Command::new(env!("CARGO_BIN_EXE_ruskcov"))
+        .arg("--inject")
+        .arg(&*INJECT)
+        .arg("--no-cache")
+        .arg("--lcov")
+        .arg(&lcov)
+        .args(opts)
+        .arg(exe)
+        .args(args));
+
+    let mut report = Report::new();
+    let mut file = None;
+    for line in fs::read_to_string(&lcov).unwrap().lines() {
+        if let Some(path) = line.strip_prefix("SF:") {
+            file = Some(report.entry(PathBuf::from(path)).or_default());
+        } else if let Some(da) = line.strip_prefix("DA:") {
+            let mut fields = da.split(',');
+            let line = fields.next().unwrap().parse().unwrap();
+            let count = fields.next().unwrap().parse().unwrap();
+            let _ = file.as_mut().expect("DA outside file").insert(line, count);
+        }
+    }
+    report
+}
+
+/// Check a fixture's lines in a report against its markers
+fn check(report: &Report, src: &str) {
+    let path = fixture(src);
+    let text = fs::read_to_string(&path).unwrap();
+    let expected: BTreeMap<u32, bool> = (1..)
+        .zip(text.lines())
+        .filter_map(|(num, line)| {
+            if line.ends_with("// hit") {
+                Some((num, true))
+            } else if line.ends_with("// miss") {
+                Some((num, false))
+            } else {
+                None
+            }
+        })
+        .collect();
+    let actual: BTreeMap<u32, bool> = report
+        .get(&path)
+        .unwrap_or_else(|| panic!("{} not in report", src))
+        .iter()
+        .map(|(&line, &count)| (line, count > 0))
+        .collect();
+
+    assert_eq!(
+        actual, expected,
+        "{}: (line, hit) in the report (left) don't match the markers (right)",
+        src
+    );
+}
+
+#[test]
+fn threads() {
+    let dir = TempDir::new().unwrap();
+    let exe = cc(&dir, "threads", &["threads.c"], &["-pthread"]);
+    check(&trace(&exe, &[], &[]), "threads.c");
+}
+
+#[test]
+fn fork() {
+    let dir = TempDir::new().unwrap();
+    let exe = cc(&dir, "fork", &["fork.c"], &[]);
+    check(&trace(&exe, &[], &[]), "fork.c");
+}
+
+#[test]
+fn exec() {
+    let dir = TempDir::new().unwrap();
+    let exe = cc(&dir, "exec", &["exec.c"], &[]);
+    check(&trace(&exe, &[], &[]), "exec.c");
+}
+
+#[test]
+fn dlopen() {
+    let dir = TempDir::new().unwrap();
+    let plugin = cc(&dir, "libplugin.so", &["plugin.c"], &["-shared", "-fPIC"]);
+    let exe = cc(&dir, "dlopen", &["dlopen.c"], &["-ldl"]);
+    let report = trace(&exe, &[], &[plugin.as_os_str()]);
+    check(&report, "dlopen.c");
+    check(&report, "plugin.c");
+}
+
+/// Objects dlopen'd by the primary are followed even if exec'd processes aren't
+#[test]
+fn dlopen_no_follow_exec() {
+    let dir = TempDir::new().unwrap();
+    let plugin = cc(&dir, "libplugin.so", &["plugin.c"], &["-shared", "-fPIC"]);
+    let exe = cc(&dir, "dlopen", &["dlopen.c"], &["-ldl"]);
+    let report = trace(&exe, &["--no-follow-exec"], &[plugin.as_os_str()]);
+    check(&report, "dlopen.c");
+    check(&report, "plugin.c");
+}
+
+#[test]
+fn signals() {
+    let dir = TempDir::new().unwrap();
+    let exe = cc(&dir, "signals", &["signals.c"], &[]);
+    check(&trace(&exe, &[], &[]), "signals.c");
+}
+
+#[test]
+fn static_exe() {
+    let dir = TempDir::new().unwrap();
+    let exe = cc(&dir, "static", &["static.c"], &["-static"]);
+    check(&trace(&exe, &[], &[]), "static.c");
+}
+
+/// A statically linked executable exec'd by a followed process is followed too, though the
+/// inject library isn't loaded into it
+#[test]
+fn exec_static() {
+    let dir = TempDir::new().unwrap();
+    let exe = cc(&dir, "exec-static", &["exec-static.c"], &[]);
+    let target = cc(&dir, "static", &["static.c"], &["-static"]);
+    let report = trace(&exe, &[], &[target.as_os_str()]);
+    check(&report, "exec-static.c");
+    check(&report, "static.c");
+
+    let report = trace(&exe, &["--no-follow-exec"], &[target.as_os_str()]);
+    check(&report, "exec-static.c");
+    assert!(!report.contains_key(&fixture("static.c")));
+}
+
+/// A process exec'd by one the filter rejects is still followed if it matches
+#[test]
+fn exec_filter_through_unfollowed() {
+    let dir = TempDir::new().unwrap();
+    let exe = cc(&dir, "exec-static", &["exec-static.c"], &[]);
+    let relay = cc(&dir, "relay", &["exec-static.c"], &[]);
+    let target = cc(&dir, "target", &["exec.c"], &[]);
+    let report = trace(
+        &exe,
+        &["--follow-exec-filter", "/target$"],
+        &[relay.as_os_str(), target.as_os_str()],
+    );
+    check(&report, "exec.c");
+}
+
+#[test]
+fn static_pie() {
+    let dir = TempDir::new().unwrap();
+    let exe = cc(&dir, "static-pie", &["static.c"], &["-static-pie"]);
+    check(&trace(&exe, &[], &[]), "static.c");
+}
+
+#[test]
+fn rust_spawn() {
+    let dir = TempDir::new().unwrap();
+    let exe = rustc(&dir, "spawn.rs");
+    check(&trace(&exe, &[], &[]), "spawn.rs");
+}